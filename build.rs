@@ -3,5 +3,9 @@ fn main() {
     cfg_aliases::cfg_aliases! {
         dx12: { all(target_os = "windows", feature = "dx12") },
         vulkan: { all(not(target_arch = "wasm32"), feature = "vulkan") },
+        // the GL backend needs the EGL context, which wgpu only uses on these platforms
+        gles: { all(any(target_os = "linux", target_os = "android"), feature = "gles") },
+        // passing file descriptors needs `SCM_RIGHTS` and memfds
+        server: { all(any(target_os = "linux", target_os = "android"), feature = "server") },
     }
 }
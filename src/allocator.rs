@@ -0,0 +1,206 @@
+use crate::{SharedBufferCreateError, SharingMode};
+use std::any::Any;
+use std::fmt::Debug;
+
+/// The memory an [`ExternalMemoryAllocator`] has to allocate for a shared buffer.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ExternalMemoryRequest {
+    pub size: u64,
+    pub alignment: u64,
+    /// The Vulkan memory types the memory may come from, one bit per index. All bits are set
+    /// on DX12.
+    pub memory_type_bits: u32,
+    /// How the memory will be exported to OIDN.
+    pub sharing_mode: SharingMode,
+}
+
+/// Memory that can be exported to OIDN.
+pub enum ExternalMemory {
+    /// Memory allocated with `VkExportMemoryAllocateInfo` for the requested sharing mode.
+    #[cfg(vulkan)]
+    Vulkan {
+        memory: ash::vk::DeviceMemory,
+        memory_type_index: u32,
+    },
+    /// A heap created with `D3D12_HEAP_FLAG_SHARED`, the buffer is placed at its start.
+    #[cfg(dx12)]
+    Dx12Heap(windows::Win32::Graphics::Direct3D12::ID3D12Heap),
+    /// A committed buffer created with `D3D12_HEAP_FLAG_SHARED` that allows unordered access.
+    #[cfg(dx12)]
+    Dx12Resource(windows::Win32::Graphics::Direct3D12::ID3D12Resource),
+}
+
+// # SAFETY: Vulkan memory handles are plain handles and D3D12 heaps and resources are
+// free-threaded.
+unsafe impl Send for ExternalMemory {}
+unsafe impl Sync for ExternalMemory {}
+
+/// Memory allocated by an [`ExternalMemoryAllocator`], owned until it is freed by it.
+pub struct ExternalMemoryBlock {
+    pub memory: ExternalMemory,
+    /// The size of the allocation, at least the requested size.
+    pub size: u64,
+    /// Anything the allocator needs to free the block.
+    pub user_data: Box<dyn Any + Send + Sync>,
+}
+
+/// Allocates the memory shared buffers export to OIDN, so it can be accounted for with the
+/// rest of an application's allocations.
+///
+/// Blocks are exported whole, so they can't be sub-allocated from larger blocks. The memory of
+/// [`SharingMode::HostMemory`] buffers is not allocated through this.
+pub trait ExternalMemoryAllocator: Debug + Send + Sync + 'static {
+    fn allocate(
+        &self,
+        device: &wgpu::Device,
+        request: &ExternalMemoryRequest,
+    ) -> Result<ExternalMemoryBlock, SharedBufferCreateError>;
+
+    /// # Safety
+    ///
+    /// `block` must have been allocated by this allocator on `device` and no longer be used.
+    unsafe fn free(&self, device: &wgpu::Device, block: ExternalMemoryBlock);
+
+    /// Whether DX12 memory is allocated as committed resources
+    /// ([`ExternalMemory::Dx12Resource`]) instead of heaps, which OIDN imports as
+    /// `D3D12_RESOURCE` rather than `OPAQUE_WIN32` memory.
+    fn dx12_committed_resources(&self) -> bool {
+        false
+    }
+}
+
+/// Allocates a dedicated Vulkan allocation or DX12 heap for each shared buffer.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct DefaultAllocator;
+
+impl ExternalMemoryAllocator for DefaultAllocator {
+    fn allocate(
+        &self,
+        device: &wgpu::Device,
+        request: &ExternalMemoryRequest,
+    ) -> Result<ExternalMemoryBlock, SharedBufferCreateError> {
+        let _ = (device, request);
+        #[cfg(vulkan)]
+        if let Some(block) = unsafe { crate::vulkan::allocate_exportable(device, request) } {
+            return block;
+        }
+        #[cfg(dx12)]
+        if let Some(block) = unsafe { crate::dx12::create_shared_heap(device, request) } {
+            return block;
+        }
+        Err(SharedBufferCreateError::Allocator(
+            "the device's backend can't export memory".to_string(),
+        ))
+    }
+
+    unsafe fn free(&self, device: &wgpu::Device, block: ExternalMemoryBlock) {
+        let _ = device;
+        match block.memory {
+            #[cfg(vulkan)]
+            ExternalMemory::Vulkan { memory, .. } => unsafe {
+                crate::vulkan::free_memory(device, memory)
+            },
+            // released when dropped
+            #[cfg(dx12)]
+            ExternalMemory::Dx12Heap(_) | ExternalMemory::Dx12Resource(_) => {}
+        }
+    }
+}
+
+/// Allocates shared buffers as committed resources through a `gpu-allocator` DX12 allocator, so
+/// they appear in its reports.
+///
+/// gpu-allocator can't allocate exportable Vulkan memory, so this fails on Vulkan devices.
+/// OIDN imports the resources as `D3D12_RESOURCE` memory, which its device has to support.
+#[cfg(all(dx12, feature = "gpu-allocator"))]
+#[derive(Debug, Clone)]
+pub struct GpuAllocator(std::sync::Arc<std::sync::Mutex<gpu_allocator::d3d12::Allocator>>);
+
+#[cfg(all(dx12, feature = "gpu-allocator"))]
+mod gpu {
+    use super::*;
+    use gpu_allocator::MemoryLocation;
+    use gpu_allocator::d3d12::{
+        Allocator, ResourceCategory, ResourceCreateDesc, ResourceStateOrBarrierLayout, ResourceType,
+    };
+    use std::sync::{Arc, Mutex};
+    use windows::Win32::Graphics::Direct3D12::{
+        D3D12_CPU_PAGE_PROPERTY_UNKNOWN, D3D12_HEAP_FLAG_SHARED, D3D12_HEAP_PROPERTIES,
+        D3D12_HEAP_TYPE_DEFAULT, D3D12_MEMORY_POOL_UNKNOWN, D3D12_RESOURCE_STATE_COMMON,
+    };
+
+    /// Keeps gpu-allocator's resource until it is freed.
+    struct GpuResource(gpu_allocator::d3d12::Resource);
+
+    // # SAFETY: D3D12 resources are free-threaded.
+    unsafe impl Send for GpuResource {}
+    unsafe impl Sync for GpuResource {}
+
+    impl GpuAllocator {
+        pub fn new(allocator: Arc<Mutex<Allocator>>) -> Self {
+            Self(allocator)
+        }
+    }
+
+    impl ExternalMemoryAllocator for GpuAllocator {
+        fn allocate(
+            &self,
+            device: &wgpu::Device,
+            request: &ExternalMemoryRequest,
+        ) -> Result<ExternalMemoryBlock, SharedBufferCreateError> {
+            // # SAFETY: the raw handle is not used.
+            let is_dx12 =
+                unsafe { device.as_hal::<wgpu::hal::api::Dx12, _, _>(|device| device.is_some()) };
+            if !is_dx12 {
+                return Err(SharedBufferCreateError::Allocator(
+                    "gpu-allocator can only allocate exportable memory on DX12".to_string(),
+                ));
+            }
+            let heap_properties = D3D12_HEAP_PROPERTIES {
+                Type: D3D12_HEAP_TYPE_DEFAULT,
+                CPUPageProperty: D3D12_CPU_PAGE_PROPERTY_UNKNOWN,
+                MemoryPoolPreference: D3D12_MEMORY_POOL_UNKNOWN,
+                CreationNodeMask: 0,
+                VisibleNodeMask: 0,
+            };
+            let resource = self
+                .0
+                .lock()
+                .unwrap()
+                .create_resource(&ResourceCreateDesc {
+                    name: "oidn-wgpu-interop shared buffer",
+                    memory_location: MemoryLocation::GpuOnly,
+                    resource_category: ResourceCategory::Buffer,
+                    resource_desc: &crate::dx12::buffer_desc(request.size, false),
+                    castable_formats: &[],
+                    clear_value: None,
+                    initial_state_or_layout: ResourceStateOrBarrierLayout::ResourceState(
+                        D3D12_RESOURCE_STATE_COMMON,
+                    ),
+                    resource_type: &ResourceType::Committed {
+                        heap_properties: &heap_properties,
+                        heap_flags: D3D12_HEAP_FLAG_SHARED,
+                    },
+                })
+                .map_err(|err| SharedBufferCreateError::Allocator(err.to_string()))?;
+            Ok(ExternalMemoryBlock {
+                memory: ExternalMemory::Dx12Resource(resource.resource().clone()),
+                size: resource.size,
+                user_data: Box::new(GpuResource(resource)),
+            })
+        }
+
+        unsafe fn free(&self, _device: &wgpu::Device, block: ExternalMemoryBlock) {
+            // the resource has to be released before gpu-allocator frees it
+            drop(block.memory);
+            if let Ok(resource) = block.user_data.downcast::<GpuResource>() {
+                // gpu-allocator only fails to free placed resources
+                let _ = self.0.lock().unwrap().free_resource(resource.0);
+            }
+        }
+
+        fn dx12_committed_resources(&self) -> bool {
+            true
+        }
+    }
+}
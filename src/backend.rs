@@ -0,0 +1,529 @@
+use crate::builder::DeviceOptions;
+use crate::sys::OidnDevice;
+use crate::{
+    Device, DeviceCreateError, OidnDeviceType, SharedBuffer, SharedBufferCreateError, SyncError,
+};
+use std::fmt::Debug;
+
+/// A way of sharing memory between a wgpu backend and OIDN.
+///
+/// The Vulkan and DX12 backends are used by [`Device::new`], others can be passed to
+/// [`crate::DeviceBuilder::backend`].
+///
+/// Device creation calls [`InteropBackend::probe`], [`InteropBackend::create_oidn_device`]
+/// and after committing the OIDN device [`InteropBackend::select_memory_type`]. Each shared
+/// buffer is then allocated with [`InteropBackend::allocate`], imported into OIDN and wrapped
+/// into wgpu.
+pub trait InteropBackend: Debug + Send + Sync + 'static {
+    /// Memory allocated by the backend, dropped after both buffers using it.
+    type Memory: Send + Sync + 'static;
+
+    /// Checks whether `adapter` can share memory with OIDN through this backend.
+    fn probe(&mut self, adapter: &wgpu::Adapter) -> bool;
+
+    /// Creates an uncommitted OIDN device for the probed adapter, preferring `device_type`.
+    ///
+    /// Returning a null device fails device creation with
+    /// [`DeviceCreateError::OidnUnsupported`], or [`DeviceCreateError::OidnModuleMissing`] if
+    /// OIDN has no devices of the type used for the adapter's vendor.
+    fn create_oidn_device(&self, device_type: Option<OidnDeviceType>) -> oidn::sys::OIDNDevice;
+
+    /// Chooses how memory is shared from the external memory types the committed OIDN device
+    /// can import, returning `false` if none can be used.
+    fn select_memory_type(&mut self, supported: oidn::sys::OIDNExternalMemoryTypeFlag) -> bool;
+
+    /// Allocates `size` bytes of exportable memory on `device`.
+    fn allocate(
+        &self,
+        device: &wgpu::Device,
+        size: wgpu::BufferAddress,
+    ) -> Result<Self::Memory, SharedBufferCreateError>;
+
+    /// Imports `memory` into OIDN, returning the new buffer, which the shared buffer releases.
+    ///
+    /// # Safety
+    ///
+    /// `memory` must have been allocated with `size` on the wgpu device created alongside
+    /// `device`.
+    unsafe fn import_into_oidn(
+        &self,
+        device: &oidn::Device,
+        memory: &Self::Memory,
+        size: wgpu::BufferAddress,
+    ) -> Result<oidn::sys::OIDNBuffer, SharedBufferCreateError>;
+
+    /// Wraps `memory` into a zeroed wgpu buffer with
+    /// `BufferUsages::COPY_SRC | BufferUsages::COPY_DST | BufferUsages::STORAGE`.
+    ///
+    /// # Safety
+    ///
+    /// `memory` must have been allocated with `size` on `device` and not been wrapped before.
+    unsafe fn wrap_into_wgpu(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        memory: &mut Self::Memory,
+        size: wgpu::BufferAddress,
+    ) -> wgpu::Buffer;
+
+    /// Opens the wgpu device for `adapter` if the backend needs more than
+    /// [`wgpu::Adapter::request_device`] enables, returning `None` to use that instead.
+    ///
+    /// Only called for devices the crate requests, after a memory type was selected.
+    fn request_device(
+        &self,
+        adapter: &wgpu::Adapter,
+        desc: &wgpu::DeviceDescriptor<'_>,
+    ) -> Option<Result<(wgpu::Device, wgpu::Queue), DeviceCreateError>> {
+        let _ = (adapter, desc);
+        None
+    }
+
+    /// Whether the OIDN and wgpu buffers of an allocation are the same memory.
+    ///
+    /// If not, they are kept in sync through host copies around [`Device::execute_filter`],
+    /// see [`Device::sync_to_oidn`].
+    fn shares_memory(&self) -> bool {
+        true
+    }
+}
+
+/// [`InteropBackend`] with its memory type erased, so devices can store any backend.
+pub(crate) trait DynBackend: Debug + Send + Sync {
+    fn probe(&mut self, adapter: &wgpu::Adapter) -> bool;
+    fn create_oidn_device(&self, device_type: Option<OidnDeviceType>) -> oidn::sys::OIDNDevice;
+    fn select_memory_type(&mut self, supported: oidn::sys::OIDNExternalMemoryTypeFlag) -> bool;
+    fn request_device(
+        &self,
+        adapter: &wgpu::Adapter,
+        desc: &wgpu::DeviceDescriptor<'_>,
+    ) -> Option<Result<(wgpu::Device, wgpu::Queue), DeviceCreateError>>;
+    fn shares_memory(&self) -> bool;
+    fn allocate_shared(
+        &self,
+        device: &Device,
+        size: wgpu::BufferAddress,
+    ) -> Result<SharedBuffer, SharedBufferCreateError>;
+}
+
+impl<B: InteropBackend> DynBackend for B {
+    fn probe(&mut self, adapter: &wgpu::Adapter) -> bool {
+        InteropBackend::probe(self, adapter)
+    }
+
+    fn create_oidn_device(&self, device_type: Option<OidnDeviceType>) -> oidn::sys::OIDNDevice {
+        InteropBackend::create_oidn_device(self, device_type)
+    }
+
+    fn select_memory_type(&mut self, supported: oidn::sys::OIDNExternalMemoryTypeFlag) -> bool {
+        InteropBackend::select_memory_type(self, supported)
+    }
+
+    fn request_device(
+        &self,
+        adapter: &wgpu::Adapter,
+        desc: &wgpu::DeviceDescriptor<'_>,
+    ) -> Option<Result<(wgpu::Device, wgpu::Queue), DeviceCreateError>> {
+        InteropBackend::request_device(self, adapter, desc)
+    }
+
+    fn shares_memory(&self) -> bool {
+        InteropBackend::shares_memory(self)
+    }
+
+    fn allocate_shared(
+        &self,
+        device: &Device,
+        size: wgpu::BufferAddress,
+    ) -> Result<SharedBuffer, SharedBufferCreateError> {
+        let mut memory = self.allocate(&device.inner.wgpu_device, size)?;
+        // # SAFETY: the memory was just allocated on this device's wgpu device.
+        let oidn_buffer =
+            unsafe { self.import_into_oidn(&device.inner.oidn_device, &memory, size)? };
+        let wgpu_buffer = unsafe {
+            self.wrap_into_wgpu(
+                &device.inner.wgpu_device,
+                &device.inner.queue,
+                &mut memory,
+                size,
+            )
+        };
+        Ok(SharedBuffer::new(
+            device,
+            oidn_buffer,
+            wgpu_buffer,
+            Box::new(memory),
+        ))
+    }
+}
+
+/// Chooses the built-in backend for `adapter`.
+pub(crate) fn builtin_backend(
+    adapter: &wgpu::Adapter,
+    options: &DeviceOptions,
+) -> Result<Box<dyn DynBackend>, DeviceCreateError> {
+    // `options` is unused if all backends are switched off
+    let _ = options;
+    match adapter.get_info().backend {
+        #[cfg(vulkan)]
+        wgpu::Backend::Vulkan => Ok(Box::new(crate::vulkan::VulkanBackend::new(
+            &options.sharing_modes,
+            options.allocator.clone(),
+        ))),
+        #[cfg(dx12)]
+        wgpu::Backend::Dx12 => Ok(Box::new(crate::dx12::Dx12Backend::new(
+            &options.sharing_modes,
+            options.allocator.clone(),
+        ))),
+        #[cfg(gles)]
+        wgpu::Backend::Gl => Ok(Box::new(crate::gles::GlesBackend::new())),
+        backend => Err(DeviceCreateError::UnsupportedBackend(backend)),
+    }
+}
+
+/// Turns a null buffer OIDN created into the device's error.
+pub(crate) fn check_oidn_buffer(
+    device: &oidn::Device,
+    buffer: oidn::sys::OIDNBuffer,
+) -> Result<oidn::sys::OIDNBuffer, SharedBufferCreateError> {
+    if buffer.is_null() {
+        let err = crate::sys::device_error(device).unwrap_err();
+        crate::trace::oidn_error("Failed to create oidn buffer", &err);
+        return Err(SharedBufferCreateError::Oidn(err));
+    }
+    Ok(buffer)
+}
+
+/// Creates the OIDN side of an allocation for backends that do not share memory.
+#[cfg_attr(not(any(gles, feature = "testing")), allow(dead_code))]
+pub(crate) unsafe fn new_staging_oidn_buffer(
+    device: &oidn::Device,
+    size: wgpu::BufferAddress,
+) -> Result<oidn::sys::OIDNBuffer, SharedBufferCreateError> {
+    let buffer = unsafe { crate::sys::oidnNewBuffer(device.raw(), size as usize) };
+    check_oidn_buffer(device, buffer)
+}
+
+/// Creates the wgpu side of an allocation for backends that do not share memory.
+#[cfg_attr(not(any(gles, feature = "testing")), allow(dead_code))]
+pub(crate) fn new_staging_wgpu_buffer(
+    device: &wgpu::Device,
+    size: wgpu::BufferAddress,
+) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: None,
+        size,
+        usage: wgpu::BufferUsages::COPY_SRC
+            | wgpu::BufferUsages::COPY_DST
+            | wgpu::BufferUsages::STORAGE,
+        mapped_at_creation: false,
+    })
+}
+
+/// Reads back the wgpu side of `buffer`, waiting for all submitted work to finish.
+pub(crate) fn read_buffer(device: &Device, buffer: &SharedBuffer) -> Result<Vec<u8>, SyncError> {
+    if !buffer.is_valid() {
+        return Err(SyncError::DeviceLost);
+    }
+    let size = buffer.wgpu_buffer.size();
+    let staging = device
+        .inner
+        .wgpu_device
+        .create_buffer(&wgpu::BufferDescriptor {
+            label: Some("oidn-wgpu-interop staging"),
+            size,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+    let mut encoder = device
+        .wgpu_device()
+        .create_command_encoder(&Default::default());
+    encoder.copy_buffer_to_buffer(&buffer.wgpu_buffer, 0, &staging, 0, size);
+    device.inner.queue.submit([encoder.finish()]);
+    read_mapped(&device.inner.wgpu_device, &staging)
+}
+
+/// Maps `staging` for reading and returns its contents, waiting for all submitted work to
+/// finish.
+pub(crate) fn read_mapped(
+    device: &wgpu::Device,
+    staging: &wgpu::Buffer,
+) -> Result<Vec<u8>, SyncError> {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    staging
+        .slice(..)
+        .map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+    device.poll(wgpu::PollType::Wait).map_err(SyncError::Poll)?;
+    // the callback only goes unrun if the device is gone
+    match receiver.try_recv() {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => return Err(SyncError::Map(err)),
+        Err(_) => return Err(SyncError::DeviceLost),
+    }
+    let contents = staging.slice(..).get_mapped_range().to_vec();
+    staging.unmap();
+    Ok(contents)
+}
+
+impl Device {
+    pub(crate) async fn from_backend(
+        adapter: &wgpu::Adapter,
+        mut backend: Box<dyn DynBackend>,
+        options: &DeviceOptions,
+        wgpu: WgpuDevice<'_>,
+    ) -> Result<(Self, wgpu::Queue), DeviceCreateError> {
+        let desc = match &wgpu {
+            WgpuDevice::Request(desc) => desc.map_label(|label| label.map(str::to_owned)),
+            WgpuDevice::Existing(device, _) => wgpu::wgt::DeviceDescriptor {
+                label: None,
+                required_features: device.features(),
+                required_limits: device.limits(),
+                ..Default::default()
+            },
+        };
+        // the device-lost callback of an existing device belongs to the application
+        let requested = matches!(wgpu, WgpuDevice::Request(_));
+        let (oidn_device, wgpu_device, queue) =
+            Self::open(adapter, &mut *backend, options, wgpu).await?;
+        let lost = if requested {
+            crate::recovery::watch_device_lost(&wgpu_device)
+        } else {
+            Default::default()
+        };
+        Ok((
+            Self {
+                inner: std::sync::Arc::new(crate::DeviceInner {
+                    wgpu_device,
+                    oidn_device: std::sync::Arc::new(oidn_device),
+                    queue: queue.clone(),
+                    backend,
+                    filter_cache: Default::default(),
+                    oidn_lock: Default::default(),
+                    half_images: Default::default(),
+                    adapter: adapter.clone(),
+                    options: options.clone(),
+                    desc,
+                    lost,
+                }),
+            },
+            queue,
+        ))
+    }
+
+    /// Creates the OIDN device and the wgpu device for `backend`.
+    pub(crate) async fn open(
+        adapter: &wgpu::Adapter,
+        backend: &mut dyn DynBackend,
+        options: &DeviceOptions,
+        wgpu: WgpuDevice<'_>,
+    ) -> Result<(OidnDevice, wgpu::Device, wgpu::Queue), DeviceCreateError> {
+        crate::load_oidn()?;
+        if !backend.probe(adapter) {
+            return Err(DeviceCreateError::MissingFeature);
+        }
+        let device = backend.create_oidn_device(options.device_type);
+        if device.is_null() {
+            // tell a missing device module apart from a GPU OIDN doesn't support
+            let module_missing =
+                OidnDeviceType::for_vendor(adapter.get_info().vendor).filter(|&ty| {
+                    unsafe { crate::builder::physical_devices() }.all(|(_, t)| t != Some(ty))
+                });
+            return Err(match module_missing {
+                Some(ty) => DeviceCreateError::OidnModuleMissing(ty),
+                None => DeviceCreateError::OidnUnsupported,
+            });
+        }
+        unsafe {
+            crate::trace::install_oidn_error_callback(device);
+            options.apply(device);
+            crate::sys::oidnCommitDevice(device);
+        }
+        let supported_memory_types = unsafe {
+            crate::sys::oidnGetDeviceInt(device, b"externalMemoryTypes\0" as *const _ as _)
+        } as oidn::sys::OIDNExternalMemoryTypeFlag;
+        if !backend.select_memory_type(supported_memory_types) {
+            unsafe {
+                crate::sys::oidnReleaseDevice(device);
+            }
+            return Err(DeviceCreateError::OidnImportUnsupported);
+        }
+        let oidn_device = unsafe { OidnDevice::from_raw(device) };
+        let (wgpu_device, queue) = match wgpu {
+            WgpuDevice::Request(desc) => match backend.request_device(adapter, desc) {
+                Some(device) => device?,
+                None => adapter
+                    .request_device(desc)
+                    .await
+                    .map_err(DeviceCreateError::RequestDeviceError)?,
+            },
+            WgpuDevice::Existing(wgpu_device, queue) => (wgpu_device, queue),
+        };
+        Ok((oidn_device, wgpu_device, queue))
+    }
+
+    /// Copies the wgpu side of `buffer` to its OIDN side if the backend does not share memory,
+    /// waiting for all submitted work to finish.
+    ///
+    /// [`Device::execute_filter`] does this for the images it is given, this is only needed
+    /// when running OIDN filters directly.
+    pub fn sync_to_oidn(&self, buffer: &SharedBuffer) -> Result<(), SyncError> {
+        if self.inner.backend.shares_memory() {
+            return Ok(());
+        }
+        let contents = read_buffer(self, buffer)?;
+        let _oidn = self.lock_oidn();
+        unsafe {
+            crate::sys::oidnWriteBuffer(
+                buffer.oidn_buffer,
+                0,
+                contents.len(),
+                contents.as_ptr() as *const _,
+            );
+        }
+        Ok(())
+    }
+
+    /// Copies the OIDN side of `buffer` to its wgpu side if the backend does not share memory.
+    ///
+    /// [`Device::execute_filter`] does this for its output image.
+    pub fn sync_to_wgpu(&self, buffer: &SharedBuffer) -> Result<(), SyncError> {
+        if self.inner.backend.shares_memory() {
+            return Ok(());
+        }
+        if !buffer.is_valid() {
+            return Err(SyncError::DeviceLost);
+        }
+        let mut contents = vec![0u8; buffer.wgpu_buffer.size() as usize];
+        {
+            let _oidn = self.lock_oidn();
+            unsafe {
+                crate::sys::oidnReadBuffer(
+                    buffer.oidn_buffer,
+                    0,
+                    contents.len(),
+                    contents.as_mut_ptr() as *mut _,
+                );
+            }
+        }
+        self.inner
+            .queue
+            .write_buffer(&buffer.wgpu_buffer, 0, &contents);
+        self.inner.queue.submit([]);
+        Ok(())
+    }
+}
+
+/// Where the wgpu device of a new [`Device`] comes from.
+pub(crate) enum WgpuDevice<'a> {
+    Request(&'a wgpu::DeviceDescriptor<'a>),
+    Existing(wgpu::Device, wgpu::Queue),
+}
+
+#[cfg(all(test, feature = "testing"))]
+#[async_std::test]
+async fn custom_backend() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Copies through host memory like the testing backend, counting its live allocations.
+    #[derive(Debug)]
+    struct CountingBackend {
+        accept: bool,
+        live: Arc<AtomicUsize>,
+    }
+
+    struct Allocation(Arc<AtomicUsize>);
+
+    impl Drop for Allocation {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    impl InteropBackend for CountingBackend {
+        type Memory = Allocation;
+
+        fn probe(&mut self, _adapter: &wgpu::Adapter) -> bool {
+            self.accept
+        }
+
+        fn create_oidn_device(
+            &self,
+            _device_type: Option<OidnDeviceType>,
+        ) -> oidn::sys::OIDNDevice {
+            unsafe { crate::sys::oidnNewDevice(oidn::sys::OIDNDeviceType_OIDN_DEVICE_TYPE_CPU) }
+        }
+
+        fn select_memory_type(
+            &mut self,
+            _supported: oidn::sys::OIDNExternalMemoryTypeFlag,
+        ) -> bool {
+            true
+        }
+
+        fn allocate(
+            &self,
+            _device: &wgpu::Device,
+            _size: wgpu::BufferAddress,
+        ) -> Result<Allocation, SharedBufferCreateError> {
+            self.live.fetch_add(1, Ordering::SeqCst);
+            Ok(Allocation(self.live.clone()))
+        }
+
+        unsafe fn import_into_oidn(
+            &self,
+            device: &oidn::Device,
+            _memory: &Allocation,
+            size: wgpu::BufferAddress,
+        ) -> Result<oidn::sys::OIDNBuffer, SharedBufferCreateError> {
+            unsafe { new_staging_oidn_buffer(device, size) }
+        }
+
+        unsafe fn wrap_into_wgpu(
+            &self,
+            device: &wgpu::Device,
+            _queue: &wgpu::Queue,
+            _memory: &mut Allocation,
+            size: wgpu::BufferAddress,
+        ) -> wgpu::Buffer {
+            new_staging_wgpu_buffer(device, size)
+        }
+
+        fn shares_memory(&self) -> bool {
+            false
+        }
+    }
+
+    let adapter = crate::testing::noop_adapter();
+    let live = Arc::new(AtomicUsize::new(0));
+    let rejected = crate::DeviceBuilder::new(&adapter)
+        .backend(CountingBackend {
+            accept: false,
+            live: live.clone(),
+        })
+        .build_async()
+        .await;
+    assert!(matches!(rejected, Err(DeviceCreateError::MissingFeature)));
+    let (device, queue) = crate::DeviceBuilder::new(&adapter)
+        .backend(CountingBackend {
+            accept: true,
+            live: live.clone(),
+        })
+        .build_async()
+        .await
+        .unwrap();
+    let image = device
+        .allocate_shared_image(crate::Format::Float, 2, 1)
+        .unwrap();
+    assert_eq!(live.load(Ordering::SeqCst), 1);
+    crate::testing::fill_image(&queue, &image, &[0.5]);
+    device.sync_to_oidn(image.buffer()).unwrap();
+    crate::testing::fill_image(&queue, &image, &[0.0]);
+    // the OIDN side still holds the first contents
+    device.sync_to_wgpu(image.buffer()).unwrap();
+    crate::testing::assert_image_approx_eq(&device, &image, &[0.5, 0.5], 0.0);
+    drop(image);
+    assert_eq!(live.load(Ordering::SeqCst), 0);
+}
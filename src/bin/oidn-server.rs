@@ -0,0 +1,34 @@
+//! Runs a denoise server on the default OIDN device, listening on the socket path it is given.
+//!
+//! Clients are served one at a time.
+
+#[cfg(server)]
+fn main() {
+    use oidn_wgpu_interop::server::Server;
+    use std::os::unix::net::UnixListener;
+
+    let Some(path) = std::env::args_os().nth(1) else {
+        eprintln!("usage: oidn-server <socket path>");
+        std::process::exit(2);
+    };
+    let listener = UnixListener::bind(&path).unwrap_or_else(|err| {
+        eprintln!("binding {} failed: {err}", path.display());
+        std::process::exit(1);
+    });
+    let server = Server::with_default_device().unwrap_or_else(|err| {
+        eprintln!("creating the OIDN device failed: {err:?}");
+        std::process::exit(1);
+    });
+    for stream in listener.incoming() {
+        let result = stream.and_then(|stream| server.serve(&stream));
+        if let Err(err) = result {
+            eprintln!("serving a client failed: {err}");
+        }
+    }
+}
+
+#[cfg(not(server))]
+fn main() {
+    eprintln!("the denoise server is only available on Linux and Android");
+    std::process::exit(1);
+}
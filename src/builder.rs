@@ -0,0 +1,349 @@
+use crate::backend::DynBackend;
+use crate::{DefaultAllocator, Device, DeviceCreateError, ExternalMemoryAllocator, InteropBackend};
+use std::sync::Arc;
+
+/// A way of sharing memory between wgpu and OIDN.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum SharingMode {
+    /// Opaque Win32 handles, used by Vulkan on Windows and by DX12.
+    OpaqueWin32,
+    /// Opaque POSIX file descriptors, used by Vulkan.
+    OpaqueFd,
+    /// Linux dma-bufs, used by Vulkan.
+    DmaBuf,
+    /// Host memory denoised by an OIDN CPU device and imported into Vulkan with
+    /// `VK_EXT_external_memory_host`, used when OIDN has no device for the adapter.
+    ///
+    /// Not used by default as the Vulkan device has to be created with the extension enabled,
+    /// which only [`crate::DeviceBuilder`] does.
+    HostMemory,
+}
+
+/// The kind of OIDN device to create for an adapter.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum OidnDeviceType {
+    Cpu,
+    Sycl,
+    Cuda,
+    Hip,
+    Metal,
+}
+
+impl OidnDeviceType {
+    pub(crate) fn from_raw(ty: oidn::sys::OIDNDeviceType) -> Option<Self> {
+        match ty {
+            oidn::sys::OIDNDeviceType_OIDN_DEVICE_TYPE_CPU => Some(OidnDeviceType::Cpu),
+            oidn::sys::OIDNDeviceType_OIDN_DEVICE_TYPE_SYCL => Some(OidnDeviceType::Sycl),
+            oidn::sys::OIDNDeviceType_OIDN_DEVICE_TYPE_CUDA => Some(OidnDeviceType::Cuda),
+            oidn::sys::OIDNDeviceType_OIDN_DEVICE_TYPE_HIP => Some(OidnDeviceType::Hip),
+            oidn::sys::OIDNDeviceType_OIDN_DEVICE_TYPE_METAL => Some(OidnDeviceType::Metal),
+            _ => None,
+        }
+    }
+
+    /// The GPU device type OIDN uses for adapters of a PCI vendor.
+    pub(crate) fn for_vendor(vendor: u32) -> Option<Self> {
+        match vendor {
+            0x8086 => Some(OidnDeviceType::Sycl),
+            0x10de => Some(OidnDeviceType::Cuda),
+            0x1002 => Some(OidnDeviceType::Hip),
+            0x106b => Some(OidnDeviceType::Metal),
+            _ => None,
+        }
+    }
+}
+
+/// The OIDN physical devices and their types.
+pub(crate) unsafe fn physical_devices() -> impl Iterator<Item = (i32, Option<OidnDeviceType>)> {
+    let count = unsafe { crate::sys::oidnGetNumPhysicalDevices() };
+    (0..count).map(|physical_device| {
+        let ty = unsafe {
+            crate::sys::oidnGetPhysicalDeviceInt(physical_device, b"type\0" as *const _ as _)
+        };
+        (
+            physical_device,
+            OidnDeviceType::from_raw(ty as oidn::sys::OIDNDeviceType),
+        )
+    })
+}
+
+/// The OIDN physical devices whose `id_name` ("uuid\0" or "luid\0") data is `id`.
+pub(crate) unsafe fn matching_physical_devices(
+    id_name: &[u8],
+    id: &[u8],
+) -> impl Iterator<Item = (i32, Option<OidnDeviceType>)> {
+    let supported_name = [&id_name[..id_name.len() - 1], b"Supported\0"].concat();
+    unsafe { physical_devices() }.filter(move |&(physical_device, _)| unsafe {
+        if !crate::sys::oidnGetPhysicalDeviceBool(physical_device, supported_name.as_ptr() as _) {
+            return false;
+        }
+        let mut size = 0;
+        let data = crate::sys::oidnGetPhysicalDeviceData(
+            physical_device,
+            id_name.as_ptr() as _,
+            &mut size,
+        );
+        !data.is_null() && std::slice::from_raw_parts(data as *const u8, size) == id
+    })
+}
+
+/// How the OIDN device is created and which sharing modes it may use.
+#[derive(Debug, Clone)]
+pub(crate) struct DeviceOptions {
+    pub(crate) verbose: Option<u32>,
+    pub(crate) num_threads: Option<u32>,
+    pub(crate) set_affinity: Option<bool>,
+    pub(crate) device_type: Option<OidnDeviceType>,
+    /// In order of preference.
+    pub(crate) sharing_modes: Vec<SharingMode>,
+    pub(crate) allocator: Arc<dyn ExternalMemoryAllocator>,
+}
+
+impl Default for DeviceOptions {
+    fn default() -> Self {
+        Self {
+            verbose: None,
+            num_threads: None,
+            set_affinity: None,
+            device_type: None,
+            sharing_modes: vec![
+                SharingMode::OpaqueWin32,
+                SharingMode::OpaqueFd,
+                SharingMode::DmaBuf,
+            ],
+            allocator: Arc::new(DefaultAllocator),
+        }
+    }
+}
+
+/// Creates an OIDN device for the physical device whose `id_name` ("uuid\0" or "luid\0") data
+/// is `id`, picking `device_type` if several match.
+///
+/// Falls back to `fallback` when no physical device could be matched.
+#[cfg_attr(not(any(dx12, vulkan, gles)), allow(dead_code))]
+pub(crate) unsafe fn new_oidn_device(
+    device_type: Option<OidnDeviceType>,
+    id_name: &[u8],
+    id: &[u8],
+    fallback: impl FnOnce() -> oidn::sys::OIDNDevice,
+) -> oidn::sys::OIDNDevice {
+    let Some(device_type) = device_type else {
+        return fallback();
+    };
+    let matching =
+        unsafe { matching_physical_devices(id_name, id) }.find(|&(_, ty)| ty == Some(device_type));
+    match matching {
+        Some((physical_device, _)) => unsafe { crate::sys::oidnNewDeviceByID(physical_device) },
+        None => fallback(),
+    }
+}
+
+impl DeviceOptions {
+    /// Sets the device parameters, must be called before the device is committed.
+    pub(crate) unsafe fn apply(&self, device: oidn::sys::OIDNDevice) {
+        unsafe {
+            if let Some(verbose) = self.verbose {
+                crate::sys::oidnSetDeviceInt(device, b"verbose\0" as *const _ as _, verbose as _);
+            }
+            if let Some(num_threads) = self.num_threads {
+                crate::sys::oidnSetDeviceInt(
+                    device,
+                    b"numThreads\0" as *const _ as _,
+                    num_threads as _,
+                );
+            }
+            if let Some(set_affinity) = self.set_affinity {
+                crate::sys::oidnSetDeviceBool(
+                    device,
+                    b"setAffinity\0" as *const _ as _,
+                    set_affinity,
+                );
+            }
+        }
+    }
+}
+
+/// Configures and creates a [`Device`].
+///
+/// Unlike [`Device::new`] this also takes OIDN device parameters and by default raises the
+/// buffer size limits of the requested wgpu device to what the adapter supports, as denoised
+/// images are often larger than the default limits allow.
+pub struct DeviceBuilder<'a> {
+    adapter: &'a wgpu::Adapter,
+    desc: wgpu::DeviceDescriptor<'a>,
+    options: DeviceOptions,
+    backend: Option<Box<dyn DynBackend>>,
+    raise_buffer_limits: bool,
+}
+
+impl<'a> DeviceBuilder<'a> {
+    pub fn new(adapter: &'a wgpu::Adapter) -> Self {
+        Self {
+            adapter,
+            desc: Default::default(),
+            options: Default::default(),
+            backend: None,
+            raise_buffer_limits: true,
+        }
+    }
+
+    /// Replaces the descriptor used to request the wgpu device.
+    pub fn descriptor(mut self, desc: wgpu::DeviceDescriptor<'a>) -> Self {
+        self.desc = desc;
+        self
+    }
+
+    pub fn label(mut self, label: &'a str) -> Self {
+        self.desc.label = Some(label);
+        self
+    }
+
+    /// Adds to the features requested from the adapter.
+    pub fn required_features(mut self, features: wgpu::Features) -> Self {
+        self.desc.required_features |= features;
+        self
+    }
+
+    /// Merges `limits` into the limits requested from the adapter, keeping the stricter of
+    /// each: the higher `max_*` limits and the lower `min_*` alignments.
+    pub fn required_limits(mut self, limits: wgpu::Limits) -> Self {
+        merge_limits(&mut self.desc.required_limits, &limits);
+        self
+    }
+
+    /// Whether to raise `max_buffer_size` and `max_storage_buffer_binding_size` to the
+    /// adapter's limits, on by default.
+    pub fn raise_buffer_limits(mut self, raise: bool) -> Self {
+        self.raise_buffer_limits = raise;
+        self
+    }
+
+    /// OIDN's `verbose` device parameter, from 0 (silent) to 4.
+    pub fn oidn_verbose(mut self, verbose: u32) -> Self {
+        self.options.verbose = Some(verbose);
+        self
+    }
+
+    /// OIDN's `numThreads` device parameter, only used by CPU devices.
+    pub fn oidn_num_threads(mut self, num_threads: u32) -> Self {
+        self.options.num_threads = Some(num_threads);
+        self
+    }
+
+    /// OIDN's `setAffinity` device parameter, only used by CPU devices.
+    pub fn oidn_set_affinity(mut self, set_affinity: bool) -> Self {
+        self.options.set_affinity = Some(set_affinity);
+        self
+    }
+
+    /// Prefers OIDN physical devices of `device_type` matching the adapter, falling back to
+    /// OIDN's choice if there are none.
+    pub fn oidn_device_type(mut self, device_type: OidnDeviceType) -> Self {
+        self.options.device_type = Some(device_type);
+        self
+    }
+
+    /// The sharing modes that may be used, in order of preference.
+    ///
+    /// Defaults to [`SharingMode::OpaqueWin32`], [`SharingMode::OpaqueFd`] then
+    /// [`SharingMode::DmaBuf`], add [`SharingMode::HostMemory`] to denoise on the CPU without
+    /// copies when OIDN has no device for the adapter.
+    pub fn sharing_modes(mut self, modes: &[SharingMode]) -> Self {
+        self.options.sharing_modes = modes.to_vec();
+        self
+    }
+
+    /// Allocates the memory of shared buffers through `allocator`, defaults to
+    /// [`DefaultAllocator`].
+    ///
+    /// Only used by the built-in Vulkan and DX12 backends.
+    pub fn allocator(mut self, allocator: impl ExternalMemoryAllocator) -> Self {
+        self.options.allocator = Arc::new(allocator);
+        self
+    }
+
+    /// Shares memory through `backend` instead of the built-in backend for the adapter.
+    ///
+    /// The sharing modes are only used by the built-in backends.
+    pub fn backend(mut self, backend: impl InteropBackend) -> Self {
+        self.backend = Some(Box::new(backend));
+        self
+    }
+
+    pub async fn build_async(mut self) -> Result<(Device, wgpu::Queue), DeviceCreateError> {
+        if self.raise_buffer_limits {
+            let adapter_limits = self.adapter.limits();
+            let limits = &mut self.desc.required_limits;
+            limits.max_buffer_size = limits.max_buffer_size.max(adapter_limits.max_buffer_size);
+            limits.max_storage_buffer_binding_size = limits
+                .max_storage_buffer_binding_size
+                .max(adapter_limits.max_storage_buffer_binding_size);
+        }
+        Device::new_with_options(self.adapter, &self.desc, &self.options, self.backend).await
+    }
+
+    /// Blocks on [`DeviceBuilder::build_async`], for callers without an async executor.
+    #[cfg(feature = "blocking")]
+    pub fn build(self) -> Result<(Device, wgpu::Queue), DeviceCreateError> {
+        futures_lite::future::block_on(self.build_async())
+    }
+}
+
+fn merge_limits(limits: &mut wgpu::Limits, other: &wgpu::Limits) {
+    macro_rules! merge {
+        (max: $($max:ident),*; min: $($min:ident),*;) => {
+            // destructured so new limits fail to compile until they are merged here
+            let wgpu::Limits { $($max,)* $($min,)* min_subgroup_size } = other;
+            $(limits.$max = limits.$max.max(*$max);)*
+            $(limits.$min = limits.$min.min(*$min);)*
+            // 0 requests no subgroup size
+            limits.min_subgroup_size = match (limits.min_subgroup_size, *min_subgroup_size) {
+                (0, size) | (size, 0) => size,
+                (size, other) => size.min(other),
+            };
+        };
+    }
+    merge! {
+        max: max_texture_dimension_1d, max_texture_dimension_2d, max_texture_dimension_3d,
+            max_texture_array_layers, max_bind_groups, max_bindings_per_bind_group,
+            max_dynamic_uniform_buffers_per_pipeline_layout,
+            max_dynamic_storage_buffers_per_pipeline_layout, max_sampled_textures_per_shader_stage,
+            max_samplers_per_shader_stage, max_storage_buffers_per_shader_stage,
+            max_storage_textures_per_shader_stage, max_uniform_buffers_per_shader_stage,
+            max_binding_array_elements_per_shader_stage,
+            max_binding_array_sampler_elements_per_shader_stage, max_uniform_buffer_binding_size,
+            max_storage_buffer_binding_size, max_vertex_buffers, max_buffer_size,
+            max_vertex_attributes, max_vertex_buffer_array_stride,
+            max_inter_stage_shader_components, max_color_attachments,
+            max_color_attachment_bytes_per_sample, max_compute_workgroup_storage_size,
+            max_compute_invocations_per_workgroup, max_compute_workgroup_size_x,
+            max_compute_workgroup_size_y, max_compute_workgroup_size_z,
+            max_compute_workgroups_per_dimension, max_subgroup_size, max_push_constant_size,
+            max_non_sampler_bindings;
+        min: min_uniform_buffer_offset_alignment, min_storage_buffer_offset_alignment;
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn limits_merge() {
+    let mut limits = wgpu::Limits {
+        min_subgroup_size: 16,
+        ..wgpu::Limits::downlevel_defaults()
+    };
+    let requested = wgpu::Limits {
+        max_buffer_size: 1 << 40,
+        min_uniform_buffer_offset_alignment: 64,
+        ..wgpu::Limits::default()
+    };
+    merge_limits(&mut limits, &requested);
+    assert_eq!(limits.max_buffer_size, 1 << 40);
+    assert_eq!(limits.min_uniform_buffer_offset_alignment, 64);
+    // a downlevel limit is raised to the default
+    assert_eq!(
+        limits.max_texture_dimension_2d,
+        wgpu::Limits::default().max_texture_dimension_2d
+    );
+    // an unset subgroup size doesn't drop the requested one
+    assert_eq!(limits.min_subgroup_size, 16);
+}
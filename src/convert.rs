@@ -0,0 +1,681 @@
+use crate::image::texel_format;
+use crate::{ConvertError, Format, SharedImage, SharedImageCreateError};
+use std::borrow::Cow;
+use wgpu::util::DeviceExt;
+
+const WORKGROUP_SIZE: u32 = 64;
+const MAX_WORKGROUPS_PER_DIMENSION: u64 = 65535;
+/// The conversion params are two `u32`s, padded to the 16 bytes some backends require of
+/// uniform buffers.
+const PARAMS_SIZE: u64 = 16;
+/// A slot for each decode or encode mode with and without the signed input flag.
+const PARAMS_SLOTS: u32 = 3 * 2;
+
+/// The storage texture formats [`Converter::image_to_texture`] can write with a compute pass.
+const STORAGE_FORMATS: [(wgpu::TextureFormat, &str); 3] = [
+    (wgpu::TextureFormat::Rgba8Unorm, "rgba8unorm"),
+    (wgpu::TextureFormat::Rgba16Float, "rgba16float"),
+    (wgpu::TextureFormat::Rgba32Float, "rgba32float"),
+];
+
+/// How the channels of a texture are decoded before OIDN reads them.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub enum Decode {
+    #[default]
+    None,
+    /// The color channels are sRGB encoded in a texture format that is not an sRGB format.
+    SrgbToLinear,
+    /// The first two channels hold an octahedral encoded normal, in `[-1, 1]` for snorm formats
+    /// and in `[0, 1]` for all others.
+    OctahedralNormal,
+}
+
+impl Decode {
+    fn as_raw(&self) -> u32 {
+        match self {
+            Decode::None => 0,
+            Decode::SrgbToLinear => 1,
+            Decode::OctahedralNormal => 2,
+        }
+    }
+}
+
+/// How the channels of an OIDN output are encoded when written back to a texture.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub enum Encode {
+    #[default]
+    None,
+    /// Encode the color as sRGB, for writing into a non-sRGB view of an sRGB texture.
+    LinearToSrgb,
+}
+
+impl Encode {
+    fn as_raw(&self) -> u32 {
+        match self {
+            Encode::None => 0,
+            Encode::LinearToSrgb => 1,
+        }
+    }
+}
+
+/// How a texture of a given format reaches a [`SharedImage`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Conversion {
+    /// OIDN reads the texels in place after a texture copy, see [`SharedImage::for_texture`].
+    Copy(Format),
+    /// A compute pass converts the texture into a tightly packed [`Format::Float3`] image.
+    Shader,
+}
+
+impl Conversion {
+    /// Returns the conversion for textures of `format`, or `None` if the texture cannot be read
+    /// as floats.
+    pub fn for_texture_format(format: wgpu::TextureFormat, decode: Decode) -> Option<Self> {
+        if !matches!(
+            format.sample_type(None, None),
+            Some(wgpu::TextureSampleType::Float { .. })
+        ) {
+            return None;
+        }
+        match (decode, texel_format(format)) {
+            (Decode::None, Some((format, _))) => Some(Conversion::Copy(format)),
+            _ => Some(Conversion::Shader),
+        }
+    }
+
+    /// The format of the image OIDN reads.
+    pub fn format(&self) -> Format {
+        match self {
+            Conversion::Copy(format) => *format,
+            Conversion::Shader => Format::Float3,
+        }
+    }
+}
+
+/// Moves textures of arbitrary float formats in and out of shared images.
+///
+/// Formats OIDN can read in place are copied with a texture copy, everything
+/// else (e.g. `Rg11b10Ufloat`, `Rgb10a2Unorm`, `Rgba8UnormSrgb` or octahedral
+/// normals in `Rg16Snorm`) goes through a bundled compute pass.
+pub struct Converter {
+    wgpu_device: wgpu::Device,
+    /// The parameters of every decode and encode, one aligned slot each, see [`Self::params`].
+    params: wgpu::Buffer,
+    params_stride: u64,
+    to_float3: wgpu::ComputePipeline,
+    from_float3: Vec<(wgpu::TextureFormat, wgpu::ComputePipeline)>,
+}
+
+impl Converter {
+    pub fn new(device: &crate::Device) -> Self {
+        let wgpu_device = device.wgpu_device().clone();
+        let to_float3 = create_pipeline(
+            &wgpu_device,
+            wgpu::include_wgsl!("shaders/texture_to_float3.wgsl"),
+        );
+        let from_float3 = STORAGE_FORMATS
+            .iter()
+            .map(|(format, name)| {
+                let source =
+                    include_str!("shaders/float3_to_texture.wgsl").replace("STORAGE_FORMAT", name);
+                let pipeline = create_pipeline(
+                    &wgpu_device,
+                    wgpu::ShaderModuleDescriptor {
+                        label: Some("float3_to_texture.wgsl"),
+                        source: wgpu::ShaderSource::Wgsl(Cow::Owned(source)),
+                    },
+                );
+                (*format, pipeline)
+            })
+            .collect();
+        // the conversions recorded before a submission can't share a slot that is rewritten
+        // for each of them, so every combination of parameters gets one written up front
+        let params_stride = PARAMS_SIZE
+            .next_multiple_of(wgpu_device.limits().min_uniform_buffer_offset_alignment as u64);
+        let mut contents = vec![0u8; (params_stride * PARAMS_SLOTS as u64) as usize];
+        for slot in 0..PARAMS_SLOTS {
+            let offset = slot as usize * params_stride as usize;
+            let values = [slot / 2, slot % 2];
+            for (i, value) in values.iter().enumerate() {
+                contents[offset + i * 4..offset + i * 4 + 4].copy_from_slice(&value.to_ne_bytes());
+            }
+        }
+        let params = wgpu_device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("oidn-wgpu-interop conversion params"),
+            contents: &contents,
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        Self {
+            wgpu_device,
+            params,
+            params_stride,
+            to_float3,
+            from_float3,
+        }
+    }
+
+    /// Allocates an image that [`Self::texture_to_image`] can convert a texture of
+    /// `texture_format` into.
+    pub fn allocate_image(
+        &self,
+        device: &crate::Device,
+        texture_format: wgpu::TextureFormat,
+        decode: Decode,
+        width: u32,
+        height: u32,
+    ) -> Result<SharedImage, SharedImageCreateError> {
+        match Conversion::for_texture_format(texture_format, decode) {
+            Some(Conversion::Copy(_)) => {
+                device.allocate_shared_image_for_texture(texture_format, width, height)
+            }
+            Some(Conversion::Shader) => device.allocate_shared_image(Format::Float3, width, height),
+            None => Err(SharedImageCreateError::UnsupportedTextureFormat(
+                texture_format,
+            )),
+        }
+    }
+
+    /// Records the commands converting `texture` into `image`.
+    ///
+    /// `texture` must have been created with [`wgpu::TextureUsages::COPY_SRC`] if the conversion
+    /// is [`Conversion::Copy`] and with [`wgpu::TextureUsages::TEXTURE_BINDING`] otherwise.
+    pub fn texture_to_image(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        texture: &wgpu::Texture,
+        decode: Decode,
+        image: &SharedImage,
+    ) -> Result<(), ConvertError> {
+        validate_dimensions(texture, image)?;
+        let Some(conversion) = Conversion::for_texture_format(texture.format(), decode) else {
+            return Err(ConvertError::UnsupportedTextureFormat(texture.format()));
+        };
+        if let Conversion::Copy(_) = conversion {
+            if !has_copy_layout(texture.format(), image) {
+                return Err(ConvertError::UnsupportedLayout);
+            }
+            image.copy_from_texture(encoder, texture.as_image_copy());
+            return Ok(());
+        }
+        if !is_tightly_packed(image, Format::Float3) {
+            return Err(ConvertError::UnsupportedLayout);
+        }
+        let signed_input = is_snorm(texture.format()) as u32;
+        let params = self.params(decode.as_raw(), signed_input);
+        let view = texture.create_view(&Default::default());
+        let bind_group = self
+            .wgpu_device
+            .create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("oidn-wgpu-interop texture to float3"),
+                layout: &self.to_float3.get_bind_group_layout(0),
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::Buffer(params),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::TextureView(&view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: image.buffer().wgpu_buffer().as_entire_binding(),
+                    },
+                ],
+            });
+        dispatch(encoder, &self.to_float3, &bind_group, pixels(image));
+        Ok(())
+    }
+
+    /// Records the commands writing `image` back into `texture`.
+    ///
+    /// Without an encoding, images with the layout of a texture copy are copied with
+    /// [`wgpu::TextureUsages::COPY_DST`]. Otherwise `image` must be a tightly packed
+    /// [`Format::Float3`] image and `texture` an `Rgba8Unorm`, `Rgba16Float` or `Rgba32Float`
+    /// texture created with [`wgpu::TextureUsages::STORAGE_BINDING`], alpha is set to one.
+    pub fn image_to_texture(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        image: &SharedImage,
+        texture: &wgpu::Texture,
+        encode: Encode,
+    ) -> Result<(), ConvertError> {
+        validate_dimensions(texture, image)?;
+        if encode == Encode::None && has_copy_layout(texture.format(), image) {
+            image.copy_to_texture(encoder, texture.as_image_copy());
+            return Ok(());
+        }
+        let Some((_, pipeline)) = self
+            .from_float3
+            .iter()
+            .find(|(format, _)| *format == texture.format())
+        else {
+            return Err(ConvertError::UnsupportedTextureFormat(texture.format()));
+        };
+        if !is_tightly_packed(image, Format::Float3) {
+            return Err(ConvertError::UnsupportedLayout);
+        }
+        let params = self.params(encode.as_raw(), 0);
+        let view = texture.create_view(&Default::default());
+        let bind_group = self
+            .wgpu_device
+            .create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("oidn-wgpu-interop float3 to texture"),
+                layout: &pipeline.get_bind_group_layout(0),
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::Buffer(params),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: image.buffer().wgpu_buffer().as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::TextureView(&view),
+                    },
+                ],
+            });
+        dispatch(encoder, pipeline, &bind_group, pixels(image));
+        Ok(())
+    }
+
+    /// The slot of the params buffer holding the mode and flag of a conversion.
+    fn params(&self, mode: u32, flag: u32) -> wgpu::BufferBinding<'_> {
+        wgpu::BufferBinding {
+            buffer: &self.params,
+            offset: (mode * 2 + flag) as u64 * self.params_stride,
+            size: wgpu::BufferSize::new(PARAMS_SIZE),
+        }
+    }
+}
+
+/// Compute passes that move `Rgba16Float` textures in and out of tightly packed
+/// [`Format::Half3`] shared images, halving the memory and copy bandwidth
+/// compared to float images.
+pub struct HalfConverter {
+    wgpu_device: wgpu::Device,
+    pack: wgpu::ComputePipeline,
+    unpack: wgpu::ComputePipeline,
+}
+
+impl HalfConverter {
+    pub fn new(device: &crate::Device) -> Result<Self, ConvertError> {
+        if !device.supports_half_images() {
+            return Err(ConvertError::HalfUnsupported);
+        }
+        let wgpu_device = device.wgpu_device().clone();
+        let pack = create_pipeline(&wgpu_device, wgpu::include_wgsl!("shaders/pack_half3.wgsl"));
+        let unpack = create_pipeline(
+            &wgpu_device,
+            wgpu::include_wgsl!("shaders/unpack_half3.wgsl"),
+        );
+        Ok(Self {
+            wgpu_device,
+            pack,
+            unpack,
+        })
+    }
+
+    /// Records a pass packing the color channels of `texture` into `image`.
+    ///
+    /// `texture` must have been created with [`wgpu::TextureUsages::TEXTURE_BINDING`].
+    pub fn pack(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        texture: &wgpu::Texture,
+        image: &SharedImage,
+    ) -> Result<(), ConvertError> {
+        validate_half(texture, image)?;
+        let view = texture.create_view(&Default::default());
+        let bind_group = self
+            .wgpu_device
+            .create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("oidn-wgpu-interop pack half3"),
+                layout: &self.pack.get_bind_group_layout(0),
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: image.buffer().wgpu_buffer().as_entire_binding(),
+                    },
+                ],
+            });
+        // each invocation handles a pair of pixels
+        dispatch(encoder, &self.pack, &bind_group, pixels(image).div_ceil(2));
+        Ok(())
+    }
+
+    /// Records a pass unpacking `image` into `texture`, setting alpha to one.
+    ///
+    /// `texture` must have been created with [`wgpu::TextureUsages::STORAGE_BINDING`].
+    pub fn unpack(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        image: &SharedImage,
+        texture: &wgpu::Texture,
+    ) -> Result<(), ConvertError> {
+        validate_half(texture, image)?;
+        let view = texture.create_view(&Default::default());
+        let bind_group = self
+            .wgpu_device
+            .create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("oidn-wgpu-interop unpack half3"),
+                layout: &self.unpack.get_bind_group_layout(0),
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: image.buffer().wgpu_buffer().as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::TextureView(&view),
+                    },
+                ],
+            });
+        // each invocation handles a pair of pixels
+        dispatch(
+            encoder,
+            &self.unpack,
+            &bind_group,
+            pixels(image).div_ceil(2),
+        );
+        Ok(())
+    }
+}
+
+fn create_pipeline(
+    device: &wgpu::Device,
+    module: wgpu::ShaderModuleDescriptor<'_>,
+) -> wgpu::ComputePipeline {
+    let module = device.create_shader_module(module);
+    device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+        label: Some("oidn-wgpu-interop conversion"),
+        layout: None,
+        module: &module,
+        entry_point: Some("main"),
+        compilation_options: Default::default(),
+        cache: None,
+    })
+}
+
+fn validate_half(texture: &wgpu::Texture, image: &SharedImage) -> Result<(), ConvertError> {
+    if texture.format() != wgpu::TextureFormat::Rgba16Float {
+        return Err(ConvertError::UnsupportedTextureFormat(texture.format()));
+    }
+    if !is_tightly_packed(image, Format::Half3) {
+        return Err(ConvertError::UnsupportedLayout);
+    }
+    validate_dimensions(texture, image)
+}
+
+pub(crate) fn validate_dimensions(
+    texture: &wgpu::Texture,
+    image: &SharedImage,
+) -> Result<(), ConvertError> {
+    if texture.width() != image.width() || texture.height() != image.height() {
+        return Err(ConvertError::DimensionMismatch {
+            texture: (texture.width(), texture.height()),
+            image: (image.width(), image.height()),
+        });
+    }
+    Ok(())
+}
+
+// the shaders index the buffer as if it were tightly packed and write whole words
+pub(crate) fn is_tightly_packed(image: &SharedImage, format: Format) -> bool {
+    image.format() == format
+        && image.pixel_byte_stride() == format.bytes_per_pixel()
+        && image.row_byte_stride() == format.bytes_per_pixel() * image.width() as u64
+        && image
+            .buffer()
+            .wgpu_buffer()
+            .size()
+            .is_multiple_of(wgpu::COPY_BUFFER_ALIGNMENT)
+}
+
+fn has_copy_layout(texture_format: wgpu::TextureFormat, image: &SharedImage) -> bool {
+    texel_format(texture_format).is_some_and(|(format, texel_size)| {
+        image.format() == format
+            && image.pixel_byte_stride() == texel_size
+            && image
+                .row_byte_stride()
+                .is_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT as u64)
+    })
+}
+
+fn is_snorm(format: wgpu::TextureFormat) -> bool {
+    matches!(
+        format,
+        wgpu::TextureFormat::R8Snorm
+            | wgpu::TextureFormat::Rg8Snorm
+            | wgpu::TextureFormat::Rgba8Snorm
+            | wgpu::TextureFormat::R16Snorm
+            | wgpu::TextureFormat::Rg16Snorm
+            | wgpu::TextureFormat::Rgba16Snorm
+    )
+}
+
+pub(crate) fn pixels(image: &SharedImage) -> u64 {
+    image.width() as u64 * image.height() as u64
+}
+
+pub(crate) fn dispatch(
+    encoder: &mut wgpu::CommandEncoder,
+    pipeline: &wgpu::ComputePipeline,
+    bind_group: &wgpu::BindGroup,
+    invocations: u64,
+) {
+    let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+        label: Some("oidn-wgpu-interop conversion"),
+        timestamp_writes: None,
+    });
+    pass.set_pipeline(pipeline);
+    pass.set_bind_group(0, bind_group, &[]);
+    let groups = invocations.div_ceil(WORKGROUP_SIZE as u64);
+    // stay under the default `max_compute_workgroups_per_dimension`
+    let x = groups.min(MAX_WORKGROUPS_PER_DIMENSION);
+    pass.dispatch_workgroups(x as u32, groups.div_ceil(x) as u32, 1);
+}
+
+#[cfg(all(test, feature = "testing"))]
+#[async_std::test]
+#[ignore = "needs an adapter that runs shaders, run with `--ignored`"]
+async fn converter_round_trip() {
+    // the noop adapter doesn't run shaders
+    let adapter = wgpu::Instance::default()
+        .request_adapter(&Default::default())
+        .await
+        .expect("no adapter to run the conversion shaders on");
+    let (device, queue) = crate::Device::new_testing(&adapter, &Default::default())
+        .await
+        .unwrap();
+    let extent = wgpu::Extent3d {
+        width: 2,
+        height: 1,
+        depth_or_array_layers: 1,
+    };
+    let texture = |usage| {
+        device
+            .wgpu_device()
+            .create_texture(&wgpu::TextureDescriptor {
+                label: None,
+                size: extent,
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Rgba8Unorm,
+                usage,
+                view_formats: &[],
+            })
+    };
+    // 188 is about 0.5 once decoded from sRGB
+    let texels = [255, 0, 0, 255, 188, 188, 188, 255];
+    let input = texture(wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST);
+    queue.write_texture(
+        input.as_image_copy(),
+        &texels,
+        wgpu::TexelCopyBufferLayout {
+            offset: 0,
+            bytes_per_row: Some(8),
+            rows_per_image: None,
+        },
+        extent,
+    );
+    let converter = Converter::new(&device);
+    let image = converter
+        .allocate_image(
+            &device,
+            wgpu::TextureFormat::Rgba8Unorm,
+            Decode::SrgbToLinear,
+            2,
+            1,
+        )
+        .unwrap();
+    let mut encoder = device
+        .wgpu_device()
+        .create_command_encoder(&Default::default());
+    converter
+        .texture_to_image(&mut encoder, &input, Decode::SrgbToLinear, &image)
+        .unwrap();
+    queue.submit([encoder.finish()]);
+    crate::testing::assert_image_approx_eq(&device, &image, &[1.0, 0.0, 0.0, 0.5, 0.5, 0.5], 0.01);
+
+    let output = texture(wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::COPY_SRC);
+    let readback = device.wgpu_device().create_buffer(&wgpu::BufferDescriptor {
+        label: None,
+        size: wgpu::COPY_BYTES_PER_ROW_ALIGNMENT as u64,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+    let mut encoder = device
+        .wgpu_device()
+        .create_command_encoder(&Default::default());
+    converter
+        .image_to_texture(&mut encoder, &image, &output, Encode::LinearToSrgb)
+        .unwrap();
+    encoder.copy_texture_to_buffer(
+        output.as_image_copy(),
+        wgpu::TexelCopyBufferInfo {
+            buffer: &readback,
+            layout: wgpu::TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT),
+                rows_per_image: None,
+            },
+        },
+        extent,
+    );
+    queue.submit([encoder.finish()]);
+    let mut round_trip = crate::backend::read_mapped(device.wgpu_device(), &readback).unwrap();
+    round_trip.truncate(8);
+    for (actual, expected) in round_trip.iter().zip(texels) {
+        assert!(
+            actual.abs_diff(expected) <= 1,
+            "{round_trip:?} != {texels:?}"
+        );
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+#[async_std::test]
+#[ignore = "needs an adapter that runs shaders, run with `--ignored`"]
+async fn half_round_trip() {
+    // half floats of 0, 0.25, 0.5, 1 and 2
+    const H: [u16; 5] = [0x0000, 0x3400, 0x3800, 0x3c00, 0x4000];
+    let adapter = wgpu::Instance::default()
+        .request_adapter(&Default::default())
+        .await
+        .expect("no adapter to run the conversion shaders on");
+    let (device, queue) = crate::Device::new_testing(&adapter, &Default::default())
+        .await
+        .unwrap();
+    // an odd number of pixels leaves the last invocation with a single pixel
+    let extent = wgpu::Extent3d {
+        width: 3,
+        height: 1,
+        depth_or_array_layers: 1,
+    };
+    let texture = |usage| {
+        device
+            .wgpu_device()
+            .create_texture(&wgpu::TextureDescriptor {
+                label: None,
+                size: extent,
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Rgba16Float,
+                usage,
+                view_formats: &[],
+            })
+    };
+    let to_bytes = |halves: &[u16]| {
+        halves
+            .iter()
+            .flat_map(|half| half.to_ne_bytes())
+            .collect::<Vec<u8>>()
+    };
+    let colors = [[H[3], H[2], H[1]], [H[4], H[0], H[3]], [H[2], H[1], H[4]]];
+    // the alpha channel is dropped when packing
+    let texels = to_bytes(&colors.map(|[r, g, b]| [r, g, b, H[0]]).concat());
+    let input = texture(wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST);
+    queue.write_texture(
+        input.as_image_copy(),
+        &texels,
+        wgpu::TexelCopyBufferLayout {
+            offset: 0,
+            bytes_per_row: Some(3 * 8),
+            rows_per_image: None,
+        },
+        extent,
+    );
+    let converter = HalfConverter::new(&device).unwrap();
+    let image = device
+        .allocate_shared_image(crate::Format::Half3, 3, 1)
+        .unwrap();
+    let mut encoder = device
+        .wgpu_device()
+        .create_command_encoder(&Default::default());
+    converter.pack(&mut encoder, &input, &image).unwrap();
+    queue.submit([encoder.finish()]);
+    let packed = crate::testing::read_buffer(&device, image.buffer()).unwrap();
+    // the last word holds the blue channel of the last pixel and zeroed padding
+    let mut expected = to_bytes(&colors.concat());
+    expected.extend([0; 2]);
+    assert_eq!(packed[..expected.len()], expected);
+
+    let output = texture(wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::COPY_SRC);
+    let readback = device.wgpu_device().create_buffer(&wgpu::BufferDescriptor {
+        label: None,
+        size: wgpu::COPY_BYTES_PER_ROW_ALIGNMENT as u64,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+    let mut encoder = device
+        .wgpu_device()
+        .create_command_encoder(&Default::default());
+    converter.unpack(&mut encoder, &image, &output).unwrap();
+    encoder.copy_texture_to_buffer(
+        output.as_image_copy(),
+        wgpu::TexelCopyBufferInfo {
+            buffer: &readback,
+            layout: wgpu::TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT),
+                rows_per_image: None,
+            },
+        },
+        extent,
+    );
+    queue.submit([encoder.finish()]);
+    let mut round_trip = crate::backend::read_mapped(device.wgpu_device(), &readback).unwrap();
+    round_trip.truncate(texels.len());
+    // unpacking sets alpha to one
+    let expected = to_bytes(&colors.map(|[r, g, b]| [r, g, b, H[3]]).concat());
+    assert_eq!(round_trip, expected);
+}
@@ -1,161 +1,409 @@
-use oidn::sys::OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_WIN32;
+use crate::{
+    ExportedMemory, ExternalMemory, ExternalMemoryAllocator, ExternalMemoryBlock,
+    ExternalMemoryRequest, HandleType, InteropBackend, OidnDeviceType, RawMemoryHandle,
+    SharingMode,
+};
+use oidn::sys::{
+    OIDNExternalMemoryTypeFlag,
+    OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_D3D12_RESOURCE,
+    OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_WIN32,
+};
+use std::os::windows::io::{AsRawHandle, FromRawHandle, OwnedHandle};
 use std::ptr;
+use std::sync::Arc;
 use wgpu::hal::api::Dx12;
 use wgpu::hal::{CommandEncoder, dx12};
-use wgpu::{BufferDescriptor, BufferUsages, DeviceDescriptor};
-use windows::Win32::Foundation::GENERIC_ALL;
+use wgpu::{BufferDescriptor, BufferUsages};
+use windows::Win32::Foundation::{GENERIC_ALL, HANDLE, LUID};
 use windows::Win32::Graphics::Direct3D12::{
     D3D12_CPU_PAGE_PROPERTY_NOT_AVAILABLE, D3D12_DEFAULT_RESOURCE_PLACEMENT_ALIGNMENT,
     D3D12_HEAP_DESC, D3D12_HEAP_FLAG_SHARED, D3D12_HEAP_FLAG_SHARED_CROSS_ADAPTER,
     D3D12_HEAP_PROPERTIES, D3D12_HEAP_TYPE_CUSTOM, D3D12_MEMORY_POOL_L0, D3D12_RESOURCE_DESC,
     D3D12_RESOURCE_DIMENSION_BUFFER, D3D12_RESOURCE_FLAG_ALLOW_CROSS_ADAPTER,
-    D3D12_RESOURCE_STATE_COMMON, D3D12_TEXTURE_LAYOUT_ROW_MAJOR, ID3D12Heap,
+    D3D12_RESOURCE_FLAG_ALLOW_UNORDERED_ACCESS, D3D12_RESOURCE_STATE_COMMON,
+    D3D12_TEXTURE_LAYOUT_ROW_MAJOR, ID3D12Device, ID3D12DeviceChild, ID3D12Heap, ID3D12Resource,
 };
 use windows::Win32::Graphics::Dxgi::Common::{DXGI_FORMAT_UNKNOWN, DXGI_SAMPLE_DESC};
+use windows::Win32::Graphics::Dxgi::DXGI_ADAPTER_DESC2;
+use windows::core::Interface;
 
 pub(crate) struct Dx12Allocation {
-    _heap: ID3D12Heap,
+    /// Released before the block is freed.
+    resource: Option<ID3D12Resource>,
+    /// The heap `resource` is placed in, exported instead of it if there is one.
+    heap: Option<ID3D12Heap>,
+    /// `None` if the resource was adopted.
+    block: Option<(Arc<dyn ExternalMemoryAllocator>, ExternalMemoryBlock)>,
+    wgpu_device: wgpu::Device,
+    allocation_size: u64,
 }
 
-impl crate::Device {
-    pub(crate) async fn new_dx12(
-        adapter: &wgpu::Adapter,
-        desc: &DeviceDescriptor<'_>,
-        trace_path: Option<&std::path::Path>,
-    ) -> Result<(Self, wgpu::Queue), crate::DeviceCreateError> {
-        // # SAFETY: the raw handle is not manually destroyed.
-        let adapter_dx12_desc = unsafe {
-            adapter.as_hal::<Dx12, _, _>(|adapter| {
-                adapter.map(|adapter| adapter.raw_adapter().GetDesc2().unwrap())
+// # SAFETY: D3D12 heaps and resources are free-threaded.
+unsafe impl Send for Dx12Allocation {}
+unsafe impl Sync for Dx12Allocation {}
+
+impl Drop for Dx12Allocation {
+    fn drop(&mut self) {
+        self.resource = None;
+        self.heap = None;
+        if let Some((allocator, block)) = self.block.take() {
+            unsafe { allocator.free(&self.wgpu_device, block) }
+        }
+    }
+}
+
+/// A buffer resource the bundled conversion shaders can bind as storage.
+pub(crate) fn buffer_desc(size: u64, cross_adapter: bool) -> D3D12_RESOURCE_DESC {
+    let mut flags = D3D12_RESOURCE_FLAG_ALLOW_UNORDERED_ACCESS;
+    if cross_adapter {
+        flags |= D3D12_RESOURCE_FLAG_ALLOW_CROSS_ADAPTER;
+    }
+    D3D12_RESOURCE_DESC {
+        Dimension: D3D12_RESOURCE_DIMENSION_BUFFER,
+        Alignment: 0,
+        Width: size,
+        Height: 1,
+        DepthOrArraySize: 1,
+        MipLevels: 1,
+        Format: DXGI_FORMAT_UNKNOWN,
+        SampleDesc: DXGI_SAMPLE_DESC {
+            Count: 1,
+            Quality: 0,
+        },
+        Layout: D3D12_TEXTURE_LAYOUT_ROW_MAJOR,
+        Flags: flags,
+    }
+}
+
+/// Creates a heap OIDN can import, returning `None` if `device` is not a DX12 device.
+pub(crate) unsafe fn create_shared_heap(
+    device: &wgpu::Device,
+    request: &ExternalMemoryRequest,
+) -> Option<Result<ExternalMemoryBlock, crate::SharedBufferCreateError>> {
+    // # SAFETY: the raw handle is not manually destroyed.
+    unsafe {
+        device.as_hal::<Dx12, _, _>(|device| {
+            let device = device?;
+            let properties = D3D12_HEAP_PROPERTIES {
+                Type: D3D12_HEAP_TYPE_CUSTOM,
+                CPUPageProperty: D3D12_CPU_PAGE_PROPERTY_NOT_AVAILABLE,
+                MemoryPoolPreference: D3D12_MEMORY_POOL_L0,
+                CreationNodeMask: 0,
+                VisibleNodeMask: 0,
+            };
+            let flags = D3D12_HEAP_FLAG_SHARED_CROSS_ADAPTER | D3D12_HEAP_FLAG_SHARED;
+            let heap_desc = D3D12_HEAP_DESC {
+                SizeInBytes: request.size,
+                Properties: properties,
+                Alignment: request.alignment,
+                Flags: flags,
+            };
+            let mut heap = None;
+            // Note on safety, since we keep the heap separate from the buffer even if
+            // the buffer is destroyed we don't destroy the backing memory, which allows the
+            // oidn buffer to function as usual
+            let result = device
+                .raw_device()
+                .CreateHeap(&heap_desc, &mut heap)
+                .map(|()| ExternalMemoryBlock {
+                    memory: ExternalMemory::Dx12Heap(heap.unwrap()),
+                    size: request.size,
+                    user_data: Box::new(()),
+                })
+                .map_err(|err| {
+                    crate::trace::windows_error("Failed to create heap", &err);
+                    crate::SharedBufferCreateError::OutOfMemory
+                });
+            Some(result)
+        })
+    }
+}
+
+/// Returns `None` if `adapter` is not a DX12 adapter.
+pub(crate) fn adapter_desc(adapter: &wgpu::Adapter) -> Option<DXGI_ADAPTER_DESC2> {
+    // # SAFETY: the raw handle is not manually destroyed.
+    unsafe {
+        adapter.as_hal::<Dx12, _, _>(|adapter| {
+            adapter.map(|adapter| adapter.raw_adapter().GetDesc2().unwrap())
+        })
+    }
+}
+
+/// The adapter LUID as OIDN reports it for physical devices.
+pub(crate) fn luid_bytes(luid: &LUID) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    bytes[..4].copy_from_slice(&luid.LowPart.to_ne_bytes());
+    bytes[4..].copy_from_slice(&luid.HighPart.to_ne_bytes());
+    bytes
+}
+
+/// Shares memory exported from DX12 through shared heap or resource handles.
+#[derive(Debug)]
+pub(crate) struct Dx12Backend {
+    allowed: bool,
+    luid: Option<LUID>,
+    allocator: Arc<dyn ExternalMemoryAllocator>,
+}
+
+impl Dx12Backend {
+    pub(crate) fn new(
+        sharing_modes: &[SharingMode],
+        allocator: Arc<dyn ExternalMemoryAllocator>,
+    ) -> Self {
+        Self {
+            allowed: sharing_modes.contains(&SharingMode::OpaqueWin32),
+            luid: None,
+            allocator,
+        }
+    }
+}
+
+impl InteropBackend for Dx12Backend {
+    type Memory = Dx12Allocation;
+
+    fn probe(&mut self, adapter: &wgpu::Adapter) -> bool {
+        self.luid = adapter_desc(adapter).map(|desc| desc.AdapterLuid);
+        self.luid.is_some()
+    }
+
+    fn create_oidn_device(&self, device_type: Option<OidnDeviceType>) -> oidn::sys::OIDNDevice {
+        let luid = self.luid.as_ref().expect("the adapter was probed");
+        unsafe {
+            crate::builder::new_oidn_device(device_type, b"luid\0", &luid_bytes(luid), || {
+                crate::sys::oidnNewDeviceByLUID(luid as *const _ as _)
             })
+        }
+    }
+
+    fn select_memory_type(&mut self, supported: OIDNExternalMemoryTypeFlag) -> bool {
+        let required = if self.allocator.dx12_committed_resources() {
+            OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_D3D12_RESOURCE
+        } else {
+            OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_WIN32
         };
-        let Some(dx_desc) = adapter_dx12_desc else {
-            return Err(crate::DeviceCreateError::UnsupportedBackend(
-                adapter.get_info().backend,
-            ));
+        self.allowed && supported & required != 0
+    }
+
+    fn allocate(
+        &self,
+        device: &wgpu::Device,
+        size: wgpu::BufferAddress,
+    ) -> Result<Dx12Allocation, crate::SharedBufferCreateError> {
+        let block = self.allocator.allocate(
+            device,
+            &ExternalMemoryRequest {
+                size,
+                alignment: D3D12_DEFAULT_RESOURCE_PLACEMENT_ALIGNMENT as u64,
+                memory_type_bits: u32::MAX,
+                sharing_mode: SharingMode::OpaqueWin32,
+            },
+        )?;
+        let mut allocation = Dx12Allocation {
+            resource: None,
+            heap: None,
+            allocation_size: block.size,
+            block: Some((self.allocator.clone(), block)),
+            wgpu_device: device.clone(),
         };
-        let device =
-            unsafe { oidn::sys::oidnNewDeviceByLUID((&dx_desc.AdapterLuid) as *const _ as _) };
-        Self::new_from_raw_oidn_adapter(device, adapter, desc, trace_path, |flag| {
-            (flag & OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_WIN32 != 0)
-                .then_some(crate::BackendData::Dx12)
-        })
-        .await
+        let resource = match &allocation.block.as_ref().unwrap().1.memory {
+            ExternalMemory::Dx12Heap(heap) if !self.allocator.dx12_committed_resources() => {
+                allocation.heap = Some(heap.clone());
+                // # SAFETY: the raw handle is not manually destroyed.
+                unsafe {
+                    device.as_hal::<Dx12, _, _>(|device| {
+                        let mut resource = None;
+                        device
+                            .unwrap()
+                            .raw_device()
+                            .CreatePlacedResource(
+                                heap,
+                                0,
+                                &buffer_desc(size, true),
+                                D3D12_RESOURCE_STATE_COMMON,
+                                None,
+                                &mut resource,
+                            )
+                            .map_err(|err| {
+                                crate::trace::windows_error("Failed to create resource", &err);
+                                crate::SharedBufferCreateError::OutOfMemory
+                            })?;
+                        Ok::<ID3D12Resource, crate::SharedBufferCreateError>(resource.unwrap())
+                    })?
+                }
+            }
+            ExternalMemory::Dx12Resource(resource) if self.allocator.dx12_committed_resources() => {
+                resource.clone()
+            }
+            _ => {
+                return Err(crate::SharedBufferCreateError::Allocator(
+                    "expected a DX12 heap, or a resource from allocators of committed resources"
+                        .to_string(),
+                ));
+            }
+        };
+        allocation.resource = Some(resource);
+        Ok(allocation)
     }
-    pub(crate) fn allocate_shared_buffers_dx12(
+
+    unsafe fn import_into_oidn(
         &self,
+        device: &oidn::Device,
+        memory: &Dx12Allocation,
+        size: wgpu::BufferAddress,
+    ) -> Result<oidn::sys::OIDNBuffer, crate::SharedBufferCreateError> {
+        unsafe { memory.import_into_oidn(device, size) }
+    }
+
+    unsafe fn wrap_into_wgpu(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        memory: &mut Dx12Allocation,
+        size: wgpu::BufferAddress,
+    ) -> wgpu::Buffer {
+        let resource = memory.resource.clone().unwrap();
+        unsafe { wrap_into_wgpu(device, Some(queue), resource, size) }
+    }
+}
+
+impl crate::Device {
+    /// Wraps a shared DX12 buffer created elsewhere into a shared buffer, keeping a reference
+    /// to it but not freeing its memory.
+    ///
+    /// If `resource` is placed in `heap`, the heap is exported instead of the resource. The
+    /// contents of the buffer are kept.
+    ///
+    /// # Safety
+    ///
+    /// - The device must use the DX12 backend.
+    /// - `resource` must be a buffer of at least `size` bytes on the device's D3D12 device that
+    ///   allows unordered access, and either be a committed resource or placed at the start of
+    ///   `heap`, created with `D3D12_HEAP_FLAG_SHARED`.
+    pub unsafe fn adopt_dx12_resource(
+        &self,
+        resource: &ID3D12Resource,
+        heap: Option<&ID3D12Heap>,
         size: wgpu::BufferAddress,
     ) -> Result<crate::SharedBuffer, crate::SharedBufferCreateError> {
-        debug_assert_eq!(self.backend_data.as_backend(), crate::Backend::Dx12);
+        if size == 0 {
+            return Err(crate::SharedBufferCreateError::InvalidSize(size));
+        }
+        if self.is_lost() {
+            return Err(crate::SharedBufferCreateError::DeviceLost);
+        }
+        let allocation = Dx12Allocation {
+            resource: Some(resource.clone()),
+            heap: heap.cloned(),
+            block: None,
+            wgpu_device: self.inner.wgpu_device.clone(),
+            allocation_size: size,
+        };
+        let _oidn = self.lock_oidn();
+        let oidn_buffer = unsafe { allocation.import_into_oidn(&self.inner.oidn_device, size)? };
+        let wgpu_buffer =
+            unsafe { wrap_into_wgpu(&self.inner.wgpu_device, None, resource.clone(), size) };
+        Ok(crate::SharedBuffer::new(
+            self,
+            oidn_buffer,
+            wgpu_buffer,
+            Box::new(allocation),
+        ))
+    }
+}
 
+impl Dx12Allocation {
+    /// Creates a new shared handle to the heap, or the resource if it isn't placed in one.
+    unsafe fn export_handle(&self) -> windows::core::Result<(HANDLE, HandleType)> {
+        // # SAFETY: the heap or resource is kept alive by the allocation.
+        unsafe {
+            let resource = self.resource.as_ref().unwrap();
+            let mut d3d12_device: Option<ID3D12Device> = None;
+            resource.GetDevice(&mut d3d12_device).inspect_err(|err| {
+                crate::trace::windows_error("Failed to get device", err);
+            })?;
+            let (shared, handle_type): (ID3D12DeviceChild, _) = match &self.heap {
+                Some(heap) => (heap.cast()?, HandleType::D3D12Heap),
+                None => (resource.cast()?, HandleType::D3D12Resource),
+            };
+            let handle = d3d12_device
+                .unwrap()
+                .CreateSharedHandle(&shared, None, GENERIC_ALL.0, None)
+                .inspect_err(|err| {
+                    crate::trace::windows_error("Failed to create shared handle", err);
+                })?;
+            Ok((handle, handle_type))
+        }
+    }
+
+    pub(crate) unsafe fn export(&self) -> Result<ExportedMemory, crate::ExportError> {
+        let (handle, handle_type) = unsafe { self.export_handle() }
+            .map_err(|err| crate::ExportError::Failed(err.to_string()))?;
+        Ok(ExportedMemory {
+            // # SAFETY: the handle was just created and is not used elsewhere.
+            handle: RawMemoryHandle::Win32(unsafe { OwnedHandle::from_raw_handle(handle.0) }),
+            handle_type,
+            allocation_size: self.allocation_size,
+            memory_type_index: None,
+        })
+    }
+
+    unsafe fn import_into_oidn(
+        &self,
+        device: &oidn::Device,
+        size: wgpu::BufferAddress,
+    ) -> Result<oidn::sys::OIDNBuffer, crate::SharedBufferCreateError> {
+        let (handle, handle_type) = unsafe { self.export_handle() }
+            .map_err(|_| crate::SharedBufferCreateError::OutOfMemory)?;
+        // OIDN doesn't take ownership of Win32 handles, so it is closed after importing
+        let handle = unsafe { OwnedHandle::from_raw_handle(handle.0) };
+        let flag = match handle_type {
+            HandleType::D3D12Resource => {
+                OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_D3D12_RESOURCE
+            }
+            _ => OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_WIN32,
+        };
+        unsafe {
+            let oidn_buffer = crate::sys::oidnNewSharedBufferFromWin32Handle(
+                device.raw(),
+                flag,
+                handle.as_raw_handle(),
+                ptr::null(),
+                size as usize,
+            );
+            crate::backend::check_oidn_buffer(device, oidn_buffer)
+        }
+    }
+}
+
+/// Wraps a buffer created with the shared buffer usages into wgpu, zeroing it if `queue` is
+/// given.
+unsafe fn wrap_into_wgpu(
+    device: &wgpu::Device,
+    queue: Option<&wgpu::Queue>,
+    resource: ID3D12Resource,
+    size: wgpu::BufferAddress,
+) -> wgpu::Buffer {
+    let buf = unsafe { dx12::Device::buffer_from_raw(resource, size) };
+    if let Some(queue) = queue {
+        let mut encoder = device.create_command_encoder(&Default::default());
         // # SAFETY: the raw handle is not manually destroyed.
         unsafe {
-            self.wgpu_device.as_hal::<Dx12, _, _>(|device| {
-                let device = device.unwrap();
-                let properties = D3D12_HEAP_PROPERTIES {
-                    Type: D3D12_HEAP_TYPE_CUSTOM,
-                    CPUPageProperty: D3D12_CPU_PAGE_PROPERTY_NOT_AVAILABLE,
-                    MemoryPoolPreference: D3D12_MEMORY_POOL_L0,
-                    CreationNodeMask: 0,
-                    VisibleNodeMask: 0,
-                };
-                let flags = D3D12_HEAP_FLAG_SHARED_CROSS_ADAPTER | D3D12_HEAP_FLAG_SHARED;
-                let heap_desc = D3D12_HEAP_DESC {
-                    SizeInBytes: size,
-                    Properties: properties,
-                    Alignment: D3D12_DEFAULT_RESOURCE_PLACEMENT_ALIGNMENT as u64,
-                    Flags: flags,
-                };
-                let mut heap = None;
-                // Note on safety, since we keep the heap separate from the buffer even if
-                // the buffer is destroyed we don't destroy the backing memory, which allows the
-                // oidn buffer to function as usual
-                device
-                    .raw_device()
-                    .CreateHeap(&heap_desc, &mut heap)
-                    .map_err(|err| {
-                        eprintln!("Failed to create heap: {}", err.message());
-                        crate::SharedBufferCreateError::OutOfMemory
-                    })?;
-                let heap: ID3D12Heap = heap.unwrap();
-                let desc = D3D12_RESOURCE_DESC {
-                    Dimension: D3D12_RESOURCE_DIMENSION_BUFFER,
-                    Alignment: 0,
-                    Width: size,
-                    Height: 1,
-                    DepthOrArraySize: 1,
-                    MipLevels: 1,
-                    Format: DXGI_FORMAT_UNKNOWN,
-                    SampleDesc: DXGI_SAMPLE_DESC {
-                        Count: 1,
-                        Quality: 0,
-                    },
-                    Layout: D3D12_TEXTURE_LAYOUT_ROW_MAJOR,
-                    Flags: D3D12_RESOURCE_FLAG_ALLOW_CROSS_ADAPTER,
-                };
-                let mut resource = None;
-                device
-                    .raw_device()
-                    .CreatePlacedResource(
-                        &heap,
-                        0,
-                        &desc,
-                        D3D12_RESOURCE_STATE_COMMON,
-                        None,
-                        &mut resource,
-                    )
-                    .map_err(|err| {
-                        eprintln!("Failed to create resource: {}", err.message());
-                        crate::SharedBufferCreateError::OutOfMemory
-                    })?;
-                let resource = resource.unwrap();
-                let handle = device
-                    .raw_device()
-                    .CreateSharedHandle(&heap, None, GENERIC_ALL.0, None)
-                    .map_err(|err| {
-                        eprintln!("Failed to create shared handle: {}", err.message());
-                        crate::SharedBufferCreateError::OutOfMemory
-                    })?;
-                let oidn_buffer = oidn::sys::oidnNewSharedBufferFromWin32Handle(
-                    self.oidn_device.raw(),
-                    OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_WIN32,
-                    handle.0,
-                    ptr::null(),
-                    size as usize,
-                );
-                if oidn_buffer.is_null() {
-                    eprintln!("Failed to create oidn buffer");
-                    eprintln!("error: {:?}", self.oidn_device.get_error());
-                    return Err(crate::SharedBufferCreateError::Oidn(
-                        self.oidn_device.get_error().unwrap_err(),
-                    ));
-                }
-                let buf = dx12::Device::buffer_from_raw(resource, size);
-                // # SAFETY: the raw handle is not manually destroyed.
-                let mut encoder = self.wgpu_device.create_command_encoder(&Default::default());
-                encoder.as_hal_mut::<Dx12, _, _>(|encoder| {
-                    encoder.unwrap().clear_buffer(&buf, 0..size);
-                });
-                self.queue.submit([encoder.finish()]);
-                // # SAFETY: Just initialized buffer, created it from the same device and made with
-                // the manually mapped usages.
-                let wgpu_buffer = self.wgpu_device.create_buffer_from_hal::<Dx12>(
-                    buf,
-                    &BufferDescriptor {
-                        label: None,
-                        size,
-                        usage: BufferUsages::COPY_SRC | BufferUsages::COPY_DST,
-                        mapped_at_creation: false,
-                    },
-                );
-                Ok(crate::SharedBuffer {
-                    _allocation: crate::Allocation::Dx12 {
-                        _dx12: Dx12Allocation { _heap: heap },
-                    },
-                    wgpu_buffer,
-                    oidn_buffer: self.oidn_device.create_buffer_from_raw(oidn_buffer),
-                })
-            })
+            encoder.as_hal_mut::<Dx12, _, _>(|encoder| {
+                encoder.unwrap().clear_buffer(&buf, 0..size);
+            });
         }
+        queue.submit([encoder.finish()]);
+    }
+    // # SAFETY: created it from the same device and made with the manually mapped usages.
+    unsafe {
+        device.create_buffer_from_hal::<Dx12>(
+            buf,
+            &BufferDescriptor {
+                label: None,
+                size,
+                usage: BufferUsages::COPY_SRC | BufferUsages::COPY_DST | BufferUsages::STORAGE,
+                mapped_at_creation: false,
+            },
+        )
     }
 }
@@ -0,0 +1,71 @@
+//! Loads the OpenImageDenoise library at runtime instead of linking it.
+//!
+//! The crate's OIDN bindings in [`crate::sys`] are resolved from the loaded library.
+
+use crate::DeviceCreateError;
+use crate::sys::{OIDNDevice, OIDNDeviceType, OIDNDeviceType_OIDN_DEVICE_TYPE_CPU};
+use libloading::Library;
+use std::ffi::{c_char, c_int};
+
+#[cfg(target_os = "windows")]
+const LIBRARY_NAMES: &[&str] = &["OpenImageDenoise.dll"];
+#[cfg(target_vendor = "apple")]
+const LIBRARY_NAMES: &[&str] = &["libOpenImageDenoise.2.dylib", "libOpenImageDenoise.dylib"];
+#[cfg(not(any(target_os = "windows", target_vendor = "apple")))]
+const LIBRARY_NAMES: &[&str] = &["libOpenImageDenoise.so.2", "libOpenImageDenoise.so"];
+
+/// Loads the library if it isn't already.
+pub(crate) fn load() -> Result<(), DeviceCreateError> {
+    if crate::sys::is_loaded() {
+        return Ok(());
+    }
+    let library = LIBRARY_NAMES
+        .iter()
+        .find_map(|name| {
+            // # SAFETY: OIDN's initialisers don't have preconditions.
+            unsafe { Library::new(name) }
+                .inspect_err(|err| crate::trace::library_load_failed(name, err))
+                .ok()
+        })
+        .ok_or(DeviceCreateError::OidnLibraryMissing)?;
+    unsafe {
+        check_version(&library)?;
+        crate::sys::set_library(library);
+    }
+    Ok(())
+}
+
+/// Fails with [`DeviceCreateError::OidnTooOld`] for OIDN 1.x, which lacks the physical device
+/// functions the crate relies on.
+unsafe fn check_version(library: &Library) -> Result<(), DeviceCreateError> {
+    type GetNumPhysicalDevices = unsafe extern "C" fn() -> c_int;
+    type NewDevice = unsafe extern "C" fn(OIDNDeviceType) -> OIDNDevice;
+    type CommitDevice = unsafe extern "C" fn(OIDNDevice);
+    type GetDeviceInt = unsafe extern "C" fn(OIDNDevice, *const c_char) -> c_int;
+    type ReleaseDevice = unsafe extern "C" fn(OIDNDevice);
+    // # SAFETY: the types match OIDN's declarations, which are the same in 1.x.
+    unsafe {
+        if library
+            .get::<GetNumPhysicalDevices>(b"oidnGetNumPhysicalDevices\0")
+            .is_ok()
+        {
+            return Ok(());
+        }
+        // every 1.x library has a CPU device, whose version is the library's
+        let version = (|| {
+            let new_device = library.get::<NewDevice>(b"oidnNewDevice\0").ok()?;
+            let commit_device = library.get::<CommitDevice>(b"oidnCommitDevice\0").ok()?;
+            let get_device_int = library.get::<GetDeviceInt>(b"oidnGetDeviceInt\0").ok()?;
+            let release_device = library.get::<ReleaseDevice>(b"oidnReleaseDevice\0").ok()?;
+            let device = new_device(OIDNDeviceType_OIDN_DEVICE_TYPE_CPU);
+            if device.is_null() {
+                return None;
+            }
+            commit_device(device);
+            let version = get_device_int(device, b"version\0" as *const _ as _);
+            release_device(device);
+            Some(version)
+        })();
+        Err(DeviceCreateError::OidnTooOld(version.unwrap_or(0)))
+    }
+}
@@ -0,0 +1,188 @@
+use crate::builder::{matching_physical_devices, physical_devices};
+use crate::{DeviceCreateError, OidnDeviceType};
+use std::cmp::Reverse;
+
+/// How well an adapter can share work with OIDN, from worst to best.
+///
+/// There is no level for synchronising through shared semaphores, as the crate doesn't share
+/// semaphores between the APIs. All synchronisation waits on the host, with wgpu's `poll` and
+/// OIDN's blocking filter executions.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum InteropSupport {
+    /// The crate can't create a device denoising the adapter's images.
+    None,
+    /// Images are copied through host memory to an OIDN device, used by the `gles` backend.
+    StagingOnly,
+    /// OIDN and wgpu share memory without copies, synchronised by waiting on the host.
+    SharedMemory,
+}
+
+/// An adapter with what it supports for interop, see [`enumerate_interop_adapters`].
+#[derive(Debug)]
+pub struct InteropAdapter {
+    pub adapter: wgpu::Adapter,
+    pub info: wgpu::AdapterInfo,
+    pub support: InteropSupport,
+    /// The type of OIDN device the adapter's images would be denoised on.
+    pub oidn_device_type: Option<OidnDeviceType>,
+    /// The adapter's device local memory in bytes, if the backend reports it.
+    pub memory_size: Option<u64>,
+}
+
+/// What the backend reports about an adapter without creating a device.
+struct AdapterProbe {
+    /// The name of the OIDN physical device parameter identifying the adapter and its value,
+    /// if the backend can tell.
+    id: Option<(&'static [u8], Vec<u8>)>,
+    /// The memory types the backend can share with OIDN, 0 if it copies through host memory.
+    memory_types: oidn::sys::OIDNExternalMemoryTypeFlag,
+    /// Whether a CPU device can share host memory with the adapter.
+    host_memory: bool,
+    memory_size: Option<u64>,
+}
+
+fn probe(adapter: &wgpu::Adapter) -> Option<AdapterProbe> {
+    match adapter.get_info().backend {
+        #[cfg(vulkan)]
+        wgpu::Backend::Vulkan => {
+            crate::vulkan::VulkanAdapterInfo::new(adapter).map(|info| AdapterProbe {
+                id: Some((b"uuid\0", info.device_uuid.to_vec())),
+                memory_types: info.memory_types(),
+                host_memory: info.host_memory_supported,
+                memory_size: Some(info.device_local_memory),
+            })
+        }
+        #[cfg(dx12)]
+        wgpu::Backend::Dx12 => crate::dx12::adapter_desc(adapter).map(|desc| AdapterProbe {
+            id: Some((
+                b"luid\0",
+                crate::dx12::luid_bytes(&desc.AdapterLuid).to_vec(),
+            )),
+            memory_types:
+                oidn::sys::OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_WIN32,
+            host_memory: false,
+            memory_size: Some(desc.DedicatedVideoMemory as u64),
+        }),
+        #[cfg(gles)]
+        wgpu::Backend::Gl => {
+            let mut backend = crate::gles::GlesBackend::new();
+            crate::InteropBackend::probe(&mut backend, adapter).then(|| AdapterProbe {
+                id: backend
+                    .device_uuid
+                    .map(|uuid| (&b"uuid\0"[..], uuid.to_vec())),
+                memory_types: 0,
+                host_memory: false,
+                memory_size: None,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// The external memory types OIDN supports importing on a physical device.
+unsafe fn external_memory_types(physical_device: i32) -> oidn::sys::OIDNExternalMemoryTypeFlag {
+    unsafe {
+        let device = crate::sys::oidnNewDeviceByID(physical_device);
+        if device.is_null() {
+            return 0;
+        }
+        crate::sys::oidnCommitDevice(device);
+        let types = crate::sys::oidnGetDeviceInt(device, b"externalMemoryTypes\0" as *const _ as _);
+        crate::sys::oidnReleaseDevice(device);
+        types as oidn::sys::OIDNExternalMemoryTypeFlag
+    }
+}
+
+/// The support and OIDN device type the built-in backend would create a device with.
+fn support(probe: &AdapterProbe) -> (InteropSupport, Option<OidnDeviceType>) {
+    let matching = probe.id.as_ref().and_then(|(id_name, id)| {
+        unsafe { matching_physical_devices(id_name, id) }
+            .map(|(physical_device, ty)| {
+                let support = if probe.memory_types == 0 {
+                    InteropSupport::StagingOnly
+                } else if unsafe { external_memory_types(physical_device) } & probe.memory_types
+                    != 0
+                {
+                    InteropSupport::SharedMemory
+                } else {
+                    InteropSupport::None
+                };
+                (support, ty)
+            })
+            .max_by_key(|&(support, _)| support)
+    });
+    match matching {
+        Some((InteropSupport::SharedMemory, ty)) => (InteropSupport::SharedMemory, ty),
+        // a CPU device can share host memory with some adapters
+        _ if probe.host_memory
+            && unsafe { physical_devices() }.any(|(_, ty)| ty == Some(OidnDeviceType::Cpu)) =>
+        {
+            (InteropSupport::SharedMemory, Some(OidnDeviceType::Cpu))
+        }
+        Some((InteropSupport::StagingOnly, ty)) => (InteropSupport::StagingOnly, ty),
+        // without an id the staging backend uses OIDN's default device
+        None if probe.id.is_none()
+            && probe.memory_types == 0
+            && unsafe { physical_devices() }.next().is_some() =>
+        {
+            (InteropSupport::StagingOnly, None)
+        }
+        _ => (InteropSupport::None, None),
+    }
+}
+
+impl InteropAdapter {
+    fn new(adapter: wgpu::Adapter) -> Self {
+        let info = adapter.get_info();
+        let probe = probe(&adapter);
+        let (support, oidn_device_type) =
+            probe.as_ref().map_or((InteropSupport::None, None), support);
+        Self {
+            adapter,
+            info,
+            support,
+            oidn_device_type,
+            memory_size: probe.and_then(|probe| probe.memory_size),
+        }
+    }
+}
+
+fn device_type_rank(device_type: wgpu::DeviceType) -> u8 {
+    match device_type {
+        wgpu::DeviceType::DiscreteGpu => 4,
+        wgpu::DeviceType::IntegratedGpu => 3,
+        wgpu::DeviceType::VirtualGpu => 2,
+        wgpu::DeviceType::Cpu => 1,
+        wgpu::DeviceType::Other => 0,
+    }
+}
+
+/// Returns every adapter of `instance` with what it supports for interop, best first.
+///
+/// Adapters are sorted by their [`InteropSupport`], then discrete before integrated, virtual
+/// and CPU adapters, then by memory size. Checking which memory OIDN can import creates and
+/// releases an OIDN device for each matching physical device, so this is best called once.
+///
+/// Adapters sharing host memory with a CPU device report [`InteropSupport::SharedMemory`], which
+/// needs [`crate::SharingMode::HostMemory`] when creating the device. The support is that of the
+/// built-in backends with their default sharing modes.
+///
+/// Fails if the OIDN library can't be loaded, see [`crate::load_oidn`].
+pub fn enumerate_interop_adapters(
+    instance: &wgpu::Instance,
+) -> Result<Vec<InteropAdapter>, DeviceCreateError> {
+    crate::load_oidn()?;
+    let mut adapters: Vec<_> = instance
+        .enumerate_adapters(wgpu::Backends::all())
+        .into_iter()
+        .map(InteropAdapter::new)
+        .collect();
+    adapters.sort_by_key(|adapter| {
+        Reverse((
+            adapter.support,
+            device_type_rank(adapter.info.device_type),
+            adapter.memory_size,
+        ))
+    });
+    Ok(adapters)
+}
@@ -0,0 +1,73 @@
+use crate::{ExportError, SharedBuffer};
+
+/// How exported memory has to be imported.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum HandleType {
+    /// A Win32 handle to Vulkan memory, for `VK_EXTERNAL_MEMORY_HANDLE_TYPE_OPAQUE_WIN32_BIT`.
+    OpaqueWin32,
+    /// A file descriptor to Vulkan memory, for `VK_EXTERNAL_MEMORY_HANDLE_TYPE_OPAQUE_FD_BIT`.
+    OpaqueFd,
+    /// A Linux dma-buf file descriptor.
+    DmaBuf,
+    /// A shared handle to a `ID3D12Heap` the buffer is placed at the start of.
+    D3D12Heap,
+    /// A shared handle to a committed `ID3D12Resource`.
+    D3D12Resource,
+}
+
+/// An OS handle to the memory of a shared buffer, closed when dropped.
+#[derive(Debug)]
+pub enum RawMemoryHandle {
+    /// A file descriptor, for [`HandleType::OpaqueFd`] and [`HandleType::DmaBuf`].
+    #[cfg(unix)]
+    Fd(std::os::fd::OwnedFd),
+    /// A Win32 `HANDLE`, for the other handle types.
+    #[cfg(windows)]
+    Win32(std::os::windows::io::OwnedHandle),
+}
+
+/// What another API needs to import the memory of a shared buffer.
+#[derive(Debug)]
+pub struct ExportedMemory {
+    pub handle: RawMemoryHandle,
+    pub handle_type: HandleType,
+    /// The size of the exported allocation, which may be larger than the buffer. For adopted
+    /// memory this is the size it was adopted with.
+    pub allocation_size: u64,
+    /// The Vulkan memory type the memory was allocated from, `None` on DX12 and for adopted
+    /// memory.
+    pub memory_type_index: Option<u32>,
+}
+
+impl SharedBuffer {
+    /// Exports a new OS handle to the buffer's memory, so other APIs can import it without
+    /// copies.
+    ///
+    /// Fails with [`ExportError::Unsupported`] if the memory isn't shared through OS handles,
+    /// like host memory or buffers kept in sync through host copies.
+    ///
+    /// # Safety
+    ///
+    /// Accesses to the imported memory have to be synchronised with wgpu and OIDN like accesses
+    /// to the buffers, and must end before the buffer is dropped.
+    pub unsafe fn export_raw(&self) -> Result<ExportedMemory, ExportError> {
+        if !self.is_valid() {
+            return Err(ExportError::DeviceLost);
+        }
+        #[cfg(vulkan)]
+        if let Some(allocation) = self
+            .allocation
+            .downcast_ref::<crate::vulkan::VulkanAllocation>()
+        {
+            return unsafe { allocation.export() };
+        }
+        #[cfg(dx12)]
+        if let Some(allocation) = self
+            .allocation
+            .downcast_ref::<crate::dx12::Dx12Allocation>()
+        {
+            return unsafe { allocation.export() };
+        }
+        Err(ExportError::Unsupported)
+    }
+}
@@ -0,0 +1,560 @@
+use crate::{FilterError, FilterImage, SharedImage};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::ffi::c_void;
+use std::hash::{Hash, Hasher};
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The OIDN filter types.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum FilterType {
+    /// The generic ray tracing denoiser (`RT`).
+    RayTracing,
+    /// The lightmap denoiser (`RTLightmap`).
+    RayTracingLightmap,
+}
+
+impl FilterType {
+    fn name(&self) -> &'static [u8] {
+        match self {
+            FilterType::RayTracing => b"RT\0",
+            FilterType::RayTracingLightmap => b"RTLightmap\0",
+        }
+    }
+}
+
+/// The parameters a filter is committed with.
+///
+/// Parameters that do not apply to a filter type (e.g. `directional` for
+/// [`FilterType::RayTracing`]) are ignored.
+#[derive(Debug, Copy, Clone)]
+pub struct FilterConfig {
+    pub quality: oidn::Quality,
+    /// The color image contains high dynamic range values.
+    pub hdr: bool,
+    /// The color image is encoded with the sRGB curve.
+    pub srgb: bool,
+    /// The auxiliary images are noise free (e.g. prefiltered).
+    pub clean_aux: bool,
+    /// Scales the input values, computed automatically for HDR images if `None`.
+    pub input_scale: Option<f32>,
+    /// The approximate maximum memory the filter may use, OIDN picks a default if `None`.
+    pub max_memory_mb: Option<u32>,
+    /// The lightmap contains spherical harmonics coefficients rather than irradiance.
+    pub directional: bool,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        Self {
+            quality: oidn::Quality::Default,
+            hdr: false,
+            srgb: false,
+            clean_aux: false,
+            input_scale: None,
+            max_memory_mb: None,
+            directional: false,
+        }
+    }
+}
+
+impl FilterConfig {
+    pub fn quality(mut self, quality: oidn::Quality) -> Self {
+        self.quality = quality;
+        self
+    }
+    pub fn hdr(mut self, hdr: bool) -> Self {
+        self.hdr = hdr;
+        self
+    }
+    pub fn srgb(mut self, srgb: bool) -> Self {
+        self.srgb = srgb;
+        self
+    }
+    pub fn clean_aux(mut self, clean_aux: bool) -> Self {
+        self.clean_aux = clean_aux;
+        self
+    }
+    pub fn input_scale(mut self, input_scale: Option<f32>) -> Self {
+        self.input_scale = input_scale;
+        self
+    }
+    pub fn max_memory_mb(mut self, max_memory_mb: Option<u32>) -> Self {
+        self.max_memory_mb = max_memory_mb;
+        self
+    }
+    pub fn directional(mut self, directional: bool) -> Self {
+        self.directional = directional;
+        self
+    }
+
+    fn key(&self) -> ConfigKey {
+        (
+            self.quality,
+            self.hdr,
+            self.srgb,
+            self.clean_aux,
+            self.input_scale.map(f32::to_bits),
+            self.max_memory_mb,
+            self.directional,
+        )
+    }
+}
+
+type ConfigKey = (
+    oidn::Quality,
+    bool,
+    bool,
+    bool,
+    Option<u32>,
+    Option<u32>,
+    bool,
+);
+
+impl PartialEq for FilterConfig {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for FilterConfig {}
+
+impl Hash for FilterConfig {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state)
+    }
+}
+
+/// The images a filter reads from and writes to.
+///
+/// `color` and `output` may be the same image to filter in place. Without a
+/// `color` image the filter denoises the single auxiliary image that is set,
+/// which is how auxiliary images are prefiltered.
+#[derive(Copy, Clone)]
+pub struct FilterImages<'a> {
+    pub color: Option<&'a SharedImage>,
+    pub albedo: Option<&'a SharedImage>,
+    /// Only used if `albedo` is also set, or if there is no `color`.
+    pub normal: Option<&'a SharedImage>,
+    pub output: &'a SharedImage,
+}
+
+impl<'a> FilterImages<'a> {
+    pub fn new(color: &'a SharedImage, output: &'a SharedImage) -> Self {
+        Self {
+            color: Some(color),
+            albedo: None,
+            normal: None,
+            output,
+        }
+    }
+    pub fn in_place(color: &'a SharedImage) -> Self {
+        Self::new(color, color)
+    }
+    /// Denoises an albedo image on its own.
+    pub fn albedo_prefilter(albedo: &'a SharedImage, output: &'a SharedImage) -> Self {
+        Self {
+            color: None,
+            albedo: Some(albedo),
+            normal: None,
+            output,
+        }
+    }
+    /// Denoises a normal image on its own.
+    pub fn normal_prefilter(normal: &'a SharedImage, output: &'a SharedImage) -> Self {
+        Self {
+            color: None,
+            albedo: None,
+            normal: Some(normal),
+            output,
+        }
+    }
+    pub fn albedo(mut self, albedo: &'a SharedImage) -> Self {
+        self.albedo = Some(albedo);
+        self
+    }
+    pub fn normal(mut self, normal: &'a SharedImage) -> Self {
+        self.normal = Some(normal);
+        self
+    }
+
+    fn bindings(&self) -> impl Iterator<Item = (FilterImage, &'a SharedImage)> {
+        // no use supplying the normal if the albedo was not also given.
+        let normal = match self.color {
+            Some(_) => self.albedo.and(self.normal),
+            None => self.normal,
+        };
+        [
+            (FilterImage::Color, self.color),
+            (FilterImage::Albedo, self.albedo),
+            (FilterImage::Normal, normal),
+            (FilterImage::Output, Some(self.output)),
+        ]
+        .into_iter()
+        .filter_map(|(slot, image)| image.map(|image| (slot, image)))
+    }
+}
+
+/// Cancels the filter executions it is passed to, from any thread.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+    /// Allows the token to be used for another execution.
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Observes the progress of a filter execution, installed as OIDN's progress
+/// monitor function.
+#[derive(Copy, Clone, Default)]
+pub struct ProgressMonitor<'a> {
+    /// Called with the progress in `[0, 1]`, possibly from OIDN's worker threads.
+    pub callback: Option<&'a (dyn Fn(f64) + Sync)>,
+    /// Cancelling the token makes the execution fail with [`FilterError::Cancelled`].
+    pub cancellation: Option<&'a CancellationToken>,
+}
+
+impl<'a> ProgressMonitor<'a> {
+    pub fn callback(mut self, callback: &'a (dyn Fn(f64) + Sync)) -> Self {
+        self.callback = Some(callback);
+        self
+    }
+    pub fn cancellation(mut self, cancellation: &'a CancellationToken) -> Self {
+        self.cancellation = Some(cancellation);
+        self
+    }
+
+    fn is_empty(&self) -> bool {
+        self.callback.is_none() && self.cancellation.is_none()
+    }
+}
+
+unsafe extern "C" fn progress_monitor(user_ptr: *mut c_void, n: f64) -> bool {
+    // # SAFETY: the monitor outlives the execution it was installed for.
+    let monitor = unsafe { &*(user_ptr as *const ProgressMonitor<'_>) };
+    if let Some(callback) = monitor.callback {
+        // unwinding into OIDN would abort, so a panicking callback cancels instead
+        if catch_unwind(AssertUnwindSafe(|| callback(n))).is_err() {
+            return false;
+        }
+    }
+    !monitor
+        .cancellation
+        .is_some_and(CancellationToken::is_cancelled)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+struct FilterKey {
+    ty: FilterType,
+    width: u32,
+    height: u32,
+    color: bool,
+    albedo: bool,
+    normal: bool,
+    config: FilterConfig,
+}
+
+type BoundImage = (FilterImage, oidn::sys::OIDNBuffer, crate::Format, u64, u64);
+
+/// An OIDN filter committed with a [`FilterKey`].
+struct Filter {
+    raw: oidn::sys::OIDNFilter,
+    // the images bound to each slot when the filter was last committed
+    bound: Vec<BoundImage>,
+}
+
+// The raw filter is only ever used while the cache lock is held.
+unsafe impl Send for Filter {}
+
+impl Filter {
+    fn new(device: &oidn::Device, key: &FilterKey) -> Result<Self, FilterError> {
+        Ok(Self {
+            raw: new_raw_filter(device, key.ty, &key.config)?,
+            bound: Vec::new(),
+        })
+    }
+
+    /// Binds `images`, committing the filter only if they differ from the last execution.
+    fn bind(&mut self, images: &FilterImages<'_>) {
+        let bound = images
+            .bindings()
+            .map(|(slot, image)| {
+                (
+                    slot,
+                    image.buffer().oidn_buffer,
+                    image.format(),
+                    image.pixel_byte_stride(),
+                    image.row_byte_stride(),
+                )
+            })
+            .collect::<Vec<_>>();
+        if bound == self.bound {
+            return;
+        }
+        for (slot, image) in images.bindings() {
+            // # SAFETY: the filter and the images were created on the same device.
+            unsafe { image.bind_to_filter(self.raw, slot) };
+        }
+        unsafe { crate::sys::oidnCommitFilter(self.raw) };
+        self.bound = bound;
+    }
+}
+
+/// Creates a filter of type `ty` with the parameters of `config` set, which the caller has to
+/// release.
+pub(crate) fn new_raw_filter(
+    device: &oidn::Device,
+    ty: FilterType,
+    config: &FilterConfig,
+) -> Result<oidn::sys::OIDNFilter, FilterError> {
+    let raw = unsafe { crate::sys::oidnNewFilter(device.raw(), ty.name().as_ptr() as _) };
+    if raw.is_null() {
+        let err = crate::sys::device_error(device).err().unwrap_or_else(|| {
+            (
+                oidn::Error::Unknown,
+                "OIDN created no filter without reporting an error".to_owned(),
+            )
+        });
+        return Err(FilterError::Oidn(err));
+    }
+    unsafe {
+        crate::sys::oidnSetFilterInt(
+            raw,
+            b"quality\0" as *const _ as _,
+            config.quality.as_raw_oidn_quality() as i32,
+        );
+        if let Some(input_scale) = config.input_scale {
+            crate::sys::oidnSetFilterFloat(raw, b"inputScale\0" as *const _ as _, input_scale);
+        }
+        if let Some(max_memory_mb) = config.max_memory_mb {
+            crate::sys::oidnSetFilterInt(
+                raw,
+                b"maxMemoryMB\0" as *const _ as _,
+                max_memory_mb as i32,
+            );
+        }
+        match ty {
+            FilterType::RayTracing => {
+                crate::sys::oidnSetFilterBool(raw, b"hdr\0" as *const _ as _, config.hdr);
+                crate::sys::oidnSetFilterBool(raw, b"srgb\0" as *const _ as _, config.srgb);
+                crate::sys::oidnSetFilterBool(
+                    raw,
+                    b"cleanAux\0" as *const _ as _,
+                    config.clean_aux,
+                );
+            }
+            FilterType::RayTracingLightmap => {
+                crate::sys::oidnSetFilterBool(
+                    raw,
+                    b"directional\0" as *const _ as _,
+                    config.directional,
+                );
+            }
+        }
+    }
+    Ok(raw)
+}
+
+impl Drop for Filter {
+    fn drop(&mut self) {
+        unsafe { crate::sys::oidnReleaseFilter(self.raw) }
+    }
+}
+
+/// Committed filters, keyed by everything that would otherwise require an
+/// expensive `oidnCommitFilter`.
+#[derive(Default)]
+pub(crate) struct FilterCache {
+    filters: Mutex<HashMap<FilterKey, Filter>>,
+}
+
+impl FilterCache {
+    /// Releases all filters, without locking as nothing else can hold the cache.
+    pub(crate) fn clear(&mut self) {
+        self.filters
+            .get_mut()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .clear();
+    }
+
+    /// Locks the filters, dropping them if a panic (like one caught by a
+    /// [`crate::DenoiseWorker`]) left them half bound.
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<FilterKey, Filter>> {
+        self.filters.lock().unwrap_or_else(|err| {
+            let mut filters = err.into_inner();
+            filters.clear();
+            self.filters.clear_poison();
+            filters
+        })
+    }
+}
+
+impl crate::Device {
+    /// Runs a filter of type `ty` over `images`, reusing a previously committed
+    /// filter if one with the same type, dimensions, auxiliary images and config
+    /// exists.
+    ///
+    /// Like all OIDN work this waits for the filter to finish, any wgpu work
+    /// using the images must have finished before this is called.
+    pub fn execute_filter(
+        &self,
+        ty: FilterType,
+        config: &FilterConfig,
+        images: &FilterImages<'_>,
+    ) -> Result<(), FilterError> {
+        self.execute_filter_with_progress(ty, config, images, &ProgressMonitor::default())
+    }
+
+    /// Like [`Self::execute_filter`], reporting progress to and checking for
+    /// cancellation through `monitor`.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            skip_all,
+            fields(
+                ?ty,
+                width = images.output.width(),
+                height = images.output.height(),
+                backend = ?self.inner.backend,
+            ),
+        )
+    )]
+    pub fn execute_filter_with_progress(
+        &self,
+        ty: FilterType,
+        config: &FilterConfig,
+        images: &FilterImages<'_>,
+        monitor: &ProgressMonitor<'_>,
+    ) -> Result<(), FilterError> {
+        if self.is_lost()
+            || images
+                .bindings()
+                .any(|(_, image)| !image.buffer().is_valid())
+        {
+            return Err(FilterError::DeviceLost);
+        }
+        let (width, height) = (images.output.width(), images.output.height());
+        let mut key = FilterKey {
+            ty,
+            width,
+            height,
+            color: false,
+            albedo: false,
+            normal: false,
+            config: *config,
+        };
+        for (slot, image) in images.bindings() {
+            if (image.width(), image.height()) != (width, height) {
+                return Err(FilterError::DimensionMismatch {
+                    output: (width, height),
+                    image: (image.width(), image.height()),
+                });
+            }
+            match slot {
+                FilterImage::Color => key.color = true,
+                FilterImage::Albedo => key.albedo = true,
+                FilterImage::Normal => key.normal = true,
+                FilterImage::Output => {}
+            }
+        }
+        for (_, image) in images.bindings() {
+            self.sync_to_oidn(image.buffer())
+                .map_err(FilterError::Sync)?;
+        }
+        let mut filters = self.inner.filter_cache.lock();
+        let oidn_lock = self.lock_oidn();
+        let filter = match filters.entry(key) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(Filter::new(&self.inner.oidn_device, &key)?),
+        };
+        filter.bind(images);
+        unsafe {
+            if !monitor.is_empty() {
+                crate::sys::oidnSetFilterProgressMonitorFunction(
+                    filter.raw,
+                    Some(progress_monitor),
+                    monitor as *const ProgressMonitor<'_> as *mut c_void,
+                );
+            }
+            crate::sys::oidnExecuteFilter(filter.raw);
+            if !monitor.is_empty() {
+                // the filter is cached, so it must not keep pointing at the monitor
+                crate::sys::oidnSetFilterProgressMonitorFunction(
+                    filter.raw,
+                    None,
+                    std::ptr::null_mut(),
+                );
+            }
+        }
+        crate::sys::device_error(&self.inner.oidn_device).map_err(|err| match err {
+            (oidn::Error::Canceled, _) => FilterError::Cancelled,
+            err => FilterError::Oidn(err),
+        })?;
+        drop(oidn_lock);
+        drop(filters);
+        self.sync_to_wgpu(images.output.buffer())
+            .map_err(FilterError::Sync)
+    }
+
+    /// Releases all cached filters, freeing the memory OIDN holds for them.
+    pub fn clear_filter_cache(&self) {
+        let mut filters = self.inner.filter_cache.lock();
+        let _oidn = self.lock_oidn();
+        filters.clear();
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+#[async_std::test]
+async fn cancellation() {
+    use crate::testing::{fill_image, noop_adapter};
+    use std::sync::Mutex;
+
+    let (device, queue) = crate::Device::new_testing(&noop_adapter(), &Default::default())
+        .await
+        .unwrap();
+    let image = device
+        .allocate_shared_image(crate::Format::Float3, 16, 16)
+        .unwrap();
+    fill_image(&queue, &image, &[0.5; 3]);
+    let token = CancellationToken::new();
+    token.cancel();
+    let execute = |monitor: &ProgressMonitor<'_>| {
+        device.execute_filter_with_progress(
+            FilterType::RayTracing,
+            &FilterConfig::default(),
+            &FilterImages::in_place(&image),
+            monitor,
+        )
+    };
+    assert!(matches!(
+        execute(&ProgressMonitor::default().cancellation(&token)),
+        Err(FilterError::Cancelled)
+    ));
+
+    token.reset();
+    let progress = Mutex::new(Vec::new());
+    let callback = |n| progress.lock().unwrap().push(n);
+    execute(
+        &ProgressMonitor::default()
+            .cancellation(&token)
+            .callback(&callback),
+    )
+    .unwrap();
+    let progress = progress.into_inner().unwrap();
+    assert!(!progress.is_empty());
+    assert!(progress.iter().all(|n| (0.0..=1.0).contains(n)));
+}
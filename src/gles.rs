@@ -0,0 +1,115 @@
+use crate::{InteropBackend, OidnDeviceType};
+use glow::HasContext;
+use wgpu::hal::api::Gles;
+
+const GL_DEVICE_UUID_EXT: u32 = 0x9597;
+
+type GetUnsignedBytei = unsafe extern "system" fn(target: u32, index: u32, data: *mut u8);
+
+/// Shares buffers with OIDN on the GL backend through host copies.
+///
+/// wgpu-hal has no way of wrapping a GL buffer imported with `GL_EXT_memory_object_fd`, so
+/// the wgpu and OIDN buffers are separate and the zero-copy path is not implemented.
+/// `GL_EXT_memory_object` is still used to create the OIDN device on the GPU the GL context
+/// runs on.
+#[derive(Debug)]
+pub(crate) struct GlesBackend {
+    pub(crate) device_uuid: Option<[u8; 16]>,
+}
+
+impl GlesBackend {
+    pub(crate) fn new() -> Self {
+        Self { device_uuid: None }
+    }
+}
+
+impl InteropBackend for GlesBackend {
+    type Memory = ();
+
+    fn probe(&mut self, adapter: &wgpu::Adapter) -> bool {
+        // # SAFETY: the raw handle is not manually destroyed and the context is current while
+        // it is locked.
+        let is_gl = unsafe {
+            adapter.as_hal::<Gles, _, _>(|adapter| {
+                let Some(adapter) = adapter else {
+                    return false;
+                };
+                let context = adapter.adapter_context();
+                let gl = context.lock();
+                let get_unsigned_bytei = context
+                    .egl_instance()
+                    .filter(|_| gl.supported_extensions().contains("GL_EXT_memory_object"))
+                    .and_then(|egl| egl.get_proc_address("glGetUnsignedBytei_vEXT"));
+                if let Some(get_unsigned_bytei) = get_unsigned_bytei {
+                    let get_unsigned_bytei: GetUnsignedBytei =
+                        std::mem::transmute(get_unsigned_bytei);
+                    let mut uuid = [0; 16];
+                    get_unsigned_bytei(GL_DEVICE_UUID_EXT, 0, uuid.as_mut_ptr());
+                    self.device_uuid = Some(uuid);
+                }
+                true
+            })
+        };
+        // the staging copies work on any OIDN device, but there has to be one for the GPU the
+        // context runs on (or any if it can't be told)
+        is_gl
+            && match &self.device_uuid {
+                Some(uuid) => unsafe { crate::builder::matching_physical_devices(b"uuid\0", uuid) }
+                    .next()
+                    .is_some(),
+                None => unsafe { crate::builder::physical_devices() }
+                    .next()
+                    .is_some(),
+            }
+    }
+
+    fn create_oidn_device(&self, device_type: Option<OidnDeviceType>) -> oidn::sys::OIDNDevice {
+        match &self.device_uuid {
+            Some(uuid) => unsafe {
+                crate::builder::new_oidn_device(device_type, b"uuid\0", uuid, || {
+                    crate::sys::oidnNewDeviceByUUID(uuid as *const _ as *const _)
+                })
+            },
+            None => unsafe {
+                crate::sys::oidnNewDevice(oidn::sys::OIDNDeviceType_OIDN_DEVICE_TYPE_DEFAULT)
+            },
+        }
+    }
+
+    fn select_memory_type(&mut self, _supported: oidn::sys::OIDNExternalMemoryTypeFlag) -> bool {
+        // no memory is imported, the staging buffers are copied with `oidnWriteBuffer` and
+        // `oidnReadBuffer`
+        true
+    }
+
+    fn allocate(
+        &self,
+        _device: &wgpu::Device,
+        _size: wgpu::BufferAddress,
+    ) -> Result<(), crate::SharedBufferCreateError> {
+        Ok(())
+    }
+
+    unsafe fn import_into_oidn(
+        &self,
+        device: &oidn::Device,
+        _memory: &(),
+        size: wgpu::BufferAddress,
+    ) -> Result<oidn::sys::OIDNBuffer, crate::SharedBufferCreateError> {
+        unsafe { crate::backend::new_staging_oidn_buffer(device, size) }
+    }
+
+    unsafe fn wrap_into_wgpu(
+        &self,
+        device: &wgpu::Device,
+        _queue: &wgpu::Queue,
+        _memory: &mut (),
+        size: wgpu::BufferAddress,
+    ) -> wgpu::Buffer {
+        crate::backend::new_staging_wgpu_buffer(device, size)
+    }
+
+    fn shares_memory(&self) -> bool {
+        false
+    }
+}
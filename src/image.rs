@@ -0,0 +1,425 @@
+use crate::{SharedBuffer, SharedImageCreateError};
+use wgpu::util::align_to;
+
+/// The pixel format of an image stored in a [`SharedImage`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Format {
+    Float,
+    Float2,
+    Float3,
+    Float4,
+    Half,
+    Half2,
+    Half3,
+    Half4,
+}
+
+impl Format {
+    pub fn as_raw_oidn_format(&self) -> oidn::sys::OIDNFormat {
+        match self {
+            Format::Float => oidn::sys::OIDNFormat_OIDN_FORMAT_FLOAT,
+            Format::Float2 => oidn::sys::OIDNFormat_OIDN_FORMAT_FLOAT2,
+            Format::Float3 => oidn::sys::OIDNFormat_OIDN_FORMAT_FLOAT3,
+            Format::Float4 => oidn::sys::OIDNFormat_OIDN_FORMAT_FLOAT4,
+            Format::Half => oidn::sys::OIDNFormat_OIDN_FORMAT_HALF,
+            Format::Half2 => oidn::sys::OIDNFormat_OIDN_FORMAT_HALF2,
+            Format::Half3 => oidn::sys::OIDNFormat_OIDN_FORMAT_HALF3,
+            Format::Half4 => oidn::sys::OIDNFormat_OIDN_FORMAT_HALF4,
+        }
+    }
+
+    pub fn channels(&self) -> u64 {
+        match self {
+            Format::Float | Format::Half => 1,
+            Format::Float2 | Format::Half2 => 2,
+            Format::Float3 | Format::Half3 => 3,
+            Format::Float4 | Format::Half4 => 4,
+        }
+    }
+
+    pub fn bytes_per_channel(&self) -> u64 {
+        match self {
+            Format::Float | Format::Float2 | Format::Float3 | Format::Float4 => 4,
+            Format::Half | Format::Half2 | Format::Half3 | Format::Half4 => 2,
+        }
+    }
+
+    /// The size of a tightly packed pixel of this format.
+    pub fn bytes_per_pixel(&self) -> u64 {
+        self.channels() * self.bytes_per_channel()
+    }
+}
+
+/// Returns the format OIDN can read a texel of `format` as in place, along with the size of the
+/// texel. The alpha channel of four channel textures is skipped over with the pixel stride.
+pub(crate) fn texel_format(format: wgpu::TextureFormat) -> Option<(Format, u64)> {
+    match format {
+        wgpu::TextureFormat::R32Float => Some((Format::Float, 4)),
+        wgpu::TextureFormat::Rg32Float => Some((Format::Float2, 8)),
+        wgpu::TextureFormat::Rgba32Float => Some((Format::Float3, 16)),
+        wgpu::TextureFormat::R16Float => Some((Format::Half, 2)),
+        wgpu::TextureFormat::Rg16Float => Some((Format::Half2, 4)),
+        wgpu::TextureFormat::Rgba16Float => Some((Format::Half3, 8)),
+        _ => None,
+    }
+}
+
+/// The number of bytes a buffer copy of a `width` by `height` texture with texels of
+/// `texel_size` bytes writes, which wgpu requires the buffer to hold.
+fn texture_copy_size(texel_size: u64, width: u32, height: u32) -> u64 {
+    SharedImage::copy_row_byte_stride(texel_size, width) * (height as u64).saturating_sub(1)
+        + texel_size * width as u64
+}
+
+/// Checks that an image layout is valid and fits in `available` bytes.
+pub(crate) fn validate_layout(
+    format: Format,
+    width: u32,
+    height: u32,
+    pixel_byte_stride: u64,
+    row_byte_stride: u64,
+    available: u64,
+) -> Result<(), SharedImageCreateError> {
+    if width == 0 || height == 0 {
+        return Err(SharedImageCreateError::InvalidDimensions(width, height));
+    }
+    if pixel_byte_stride < format.bytes_per_pixel() {
+        return Err(SharedImageCreateError::InvalidPixelStride(
+            pixel_byte_stride,
+        ));
+    }
+    let Some(row_bytes) = pixel_byte_stride.checked_mul(width as u64) else {
+        return Err(SharedImageCreateError::InvalidPixelStride(
+            pixel_byte_stride,
+        ));
+    };
+    // wgpu describes row strides of buffer copies as `u32`s
+    if row_byte_stride < row_bytes || u32::try_from(row_byte_stride).is_err() {
+        return Err(SharedImageCreateError::InvalidRowStride(row_byte_stride));
+    }
+    let required =
+        SharedImage::required_size(format, width, height, pixel_byte_stride, row_byte_stride);
+    if required > available {
+        return Err(SharedImageCreateError::BufferTooSmall {
+            required,
+            available,
+        });
+    }
+    Ok(())
+}
+
+/// The filter image slots a [`SharedImage`] may be bound to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum FilterImage {
+    Color,
+    Albedo,
+    Normal,
+    Output,
+}
+
+impl FilterImage {
+    pub(crate) fn name(&self) -> &'static [u8] {
+        match self {
+            FilterImage::Color => b"color\0",
+            FilterImage::Albedo => b"albedo\0",
+            FilterImage::Normal => b"normal\0",
+            FilterImage::Output => b"output\0",
+        }
+    }
+}
+
+/// A [`SharedBuffer`] interpreted as a two dimensional image.
+///
+/// The layout (format, dimensions and strides) is validated against the size
+/// of the buffer when the image is created, so it can be handed to both OIDN
+/// filters and wgpu copy commands without recomputing it.
+pub struct SharedImage {
+    buffer: SharedBuffer,
+    format: Format,
+    width: u32,
+    height: u32,
+    pixel_byte_stride: u64,
+    row_byte_stride: u64,
+}
+
+impl SharedImage {
+    /// Creates a tightly packed image over `buffer`.
+    pub fn new(
+        buffer: SharedBuffer,
+        format: Format,
+        width: u32,
+        height: u32,
+    ) -> Result<Self, SharedImageCreateError> {
+        let pixel_byte_stride = format.bytes_per_pixel();
+        Self::with_strides(
+            buffer,
+            format,
+            width,
+            height,
+            pixel_byte_stride,
+            pixel_byte_stride * width as u64,
+        )
+    }
+
+    /// Creates an image over `buffer` with the layout a `copy_texture_to_buffer` of a texture of
+    /// `texture_format` produces, so the texture can be denoised without being repacked first.
+    ///
+    /// Rows are padded to [`wgpu::COPY_BYTES_PER_ROW_ALIGNMENT`] and the alpha channel of
+    /// `Rgba32Float` and `Rgba16Float` textures is skipped using the pixel stride.
+    pub fn for_texture(
+        buffer: SharedBuffer,
+        texture_format: wgpu::TextureFormat,
+        width: u32,
+        height: u32,
+    ) -> Result<Self, SharedImageCreateError> {
+        let Some((format, texel_size)) = texel_format(texture_format) else {
+            return Err(SharedImageCreateError::UnsupportedTextureFormat(
+                texture_format,
+            ));
+        };
+        // the copy also writes the skipped alpha channel of the last pixel
+        let required = texture_copy_size(texel_size, width, height);
+        let available = buffer.wgpu_buffer().size();
+        if required > available {
+            return Err(SharedImageCreateError::BufferTooSmall {
+                required,
+                available,
+            });
+        }
+        Self::with_strides(
+            buffer,
+            format,
+            width,
+            height,
+            texel_size,
+            Self::copy_row_byte_stride(texel_size, width),
+        )
+    }
+
+    /// The smallest row stride wgpu accepts for texture copies of rows of `width` pixels.
+    pub fn copy_row_byte_stride(pixel_byte_stride: u64, width: u32) -> u64 {
+        align_to(
+            pixel_byte_stride * width as u64,
+            wgpu::COPY_BYTES_PER_ROW_ALIGNMENT as u64,
+        )
+    }
+
+    /// Creates an image over `buffer` where each pixel starts
+    /// `pixel_byte_stride` bytes after the previous one and each row starts
+    /// `row_byte_stride` bytes after the previous one.
+    pub fn with_strides(
+        buffer: SharedBuffer,
+        format: Format,
+        width: u32,
+        height: u32,
+        pixel_byte_stride: u64,
+        row_byte_stride: u64,
+    ) -> Result<Self, SharedImageCreateError> {
+        validate_layout(
+            format,
+            width,
+            height,
+            pixel_byte_stride,
+            row_byte_stride,
+            buffer.wgpu_buffer().size(),
+        )?;
+        Ok(Self {
+            buffer,
+            format,
+            width,
+            height,
+            pixel_byte_stride,
+            row_byte_stride,
+        })
+    }
+
+    /// The number of bytes an image with this layout covers, the last row is
+    /// not padded out to `row_byte_stride`. Images without pixels cover none,
+    /// layouts covering more than `u64::MAX` bytes saturate to it.
+    pub fn required_size(
+        format: Format,
+        width: u32,
+        height: u32,
+        pixel_byte_stride: u64,
+        row_byte_stride: u64,
+    ) -> wgpu::BufferAddress {
+        let (Some(rows), Some(pixels)) = (
+            (height as u64).checked_sub(1),
+            (width as u64).checked_sub(1),
+        ) else {
+            return 0;
+        };
+        row_byte_stride
+            .saturating_mul(rows)
+            .saturating_add(pixel_byte_stride.saturating_mul(pixels))
+            .saturating_add(format.bytes_per_pixel())
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+    pub fn pixel_byte_stride(&self) -> u64 {
+        self.pixel_byte_stride
+    }
+    pub fn row_byte_stride(&self) -> u64 {
+        self.row_byte_stride
+    }
+    pub fn buffer(&self) -> &SharedBuffer {
+        &self.buffer
+    }
+    pub fn buffer_mut(&mut self) -> &mut SharedBuffer {
+        &mut self.buffer
+    }
+    pub fn into_buffer(self) -> SharedBuffer {
+        self.buffer
+    }
+
+    /// Sets this image as the `slot` image of a raw OIDN filter.
+    ///
+    /// # Safety
+    /// `filter` must be a valid filter created on the same OIDN device as this image's buffer.
+    pub unsafe fn bind_to_filter(&self, filter: oidn::sys::OIDNFilter, slot: FilterImage) {
+        unsafe {
+            crate::sys::oidnSetFilterImage(
+                filter,
+                slot.name().as_ptr() as _,
+                self.buffer.oidn_buffer,
+                self.format.as_raw_oidn_format(),
+                self.width as usize,
+                self.height as usize,
+                0,
+                self.pixel_byte_stride as usize,
+                self.row_byte_stride as usize,
+            );
+        }
+    }
+
+    /// The layout of this image for wgpu buffer copies.
+    pub fn texel_copy_buffer_layout(&self) -> wgpu::TexelCopyBufferLayout {
+        wgpu::TexelCopyBufferLayout {
+            offset: 0,
+            bytes_per_row: Some(
+                u32::try_from(self.row_byte_stride).expect("row strides are checked on creation"),
+            ),
+            rows_per_image: Some(self.height),
+        }
+    }
+
+    pub fn texel_copy_buffer_info(&self) -> wgpu::TexelCopyBufferInfo<'_> {
+        wgpu::TexelCopyBufferInfo {
+            buffer: self.buffer.wgpu_buffer(),
+            layout: self.texel_copy_buffer_layout(),
+        }
+    }
+
+    pub fn extent(&self) -> wgpu::Extent3d {
+        wgpu::Extent3d {
+            width: self.width,
+            height: self.height,
+            depth_or_array_layers: 1,
+        }
+    }
+
+    /// Records a copy of `texture` into this image.
+    ///
+    /// wgpu requires the pixel stride to match the texel size of the texture
+    /// and the row stride to be a multiple of [`wgpu::COPY_BYTES_PER_ROW_ALIGNMENT`].
+    pub fn copy_from_texture(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        texture: wgpu::TexelCopyTextureInfo<'_>,
+    ) {
+        encoder.copy_texture_to_buffer(texture, self.texel_copy_buffer_info(), self.extent());
+    }
+
+    /// Records a copy of this image into `texture`, see [`Self::copy_from_texture`] for the layout
+    /// requirements.
+    pub fn copy_to_texture(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        texture: wgpu::TexelCopyTextureInfo<'_>,
+    ) {
+        encoder.copy_buffer_to_texture(self.texel_copy_buffer_info(), texture, self.extent());
+    }
+}
+
+impl crate::Device {
+    /// Allocates a tightly packed shared image.
+    pub fn allocate_shared_image(
+        &self,
+        format: Format,
+        width: u32,
+        height: u32,
+    ) -> Result<SharedImage, SharedImageCreateError> {
+        if width == 0 || height == 0 {
+            return Err(SharedImageCreateError::InvalidDimensions(width, height));
+        }
+        let pixel_byte_stride = format.bytes_per_pixel();
+        let size = SharedImage::required_size(
+            format,
+            width,
+            height,
+            pixel_byte_stride,
+            pixel_byte_stride * width as u64,
+        );
+        // keep the buffer usable with wgpu copies and storage bindings
+        let buffer = self
+            .allocate_shared_buffers(align_to(size, wgpu::COPY_BUFFER_ALIGNMENT))
+            .map_err(SharedImageCreateError::Buffer)?;
+        SharedImage::new(buffer, format, width, height)
+    }
+
+    /// Allocates a shared image that a texture of `texture_format` can be copied into and out of
+    /// directly, see [`SharedImage::for_texture`].
+    pub fn allocate_shared_image_for_texture(
+        &self,
+        texture_format: wgpu::TextureFormat,
+        width: u32,
+        height: u32,
+    ) -> Result<SharedImage, SharedImageCreateError> {
+        if width == 0 || height == 0 {
+            return Err(SharedImageCreateError::InvalidDimensions(width, height));
+        }
+        let Some((_, texel_size)) = texel_format(texture_format) else {
+            return Err(SharedImageCreateError::UnsupportedTextureFormat(
+                texture_format,
+            ));
+        };
+        let size = texture_copy_size(texel_size, width, height);
+        // keep the buffer usable with wgpu copies and storage bindings
+        let buffer = self
+            .allocate_shared_buffers(align_to(size, wgpu::COPY_BUFFER_ALIGNMENT))
+            .map_err(SharedImageCreateError::Buffer)?;
+        SharedImage::for_texture(buffer, texture_format, width, height)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn texture_layout() {
+    // a 100 pixel wide `Rgba32Float` row is 1600 bytes, which wgpu pads to 1792
+    let (format, texel_size) = texel_format(wgpu::TextureFormat::Rgba32Float).unwrap();
+    assert_eq!(format, Format::Float3);
+    assert_eq!(SharedImage::copy_row_byte_stride(texel_size, 100), 1792);
+    // the copy writes whole texels, including the alpha OIDN skips
+    assert_eq!(texture_copy_size(texel_size, 100, 2), 1792 + 100 * 16);
+    assert_eq!(
+        SharedImage::required_size(format, 0, 2, texel_size, 1792),
+        0
+    );
+    // huge strides are rejected instead of overflowing
+    assert!(matches!(
+        validate_layout(format, 2, 1, u64::MAX / 2 + 1, u32::MAX as u64, u64::MAX),
+        Err(SharedImageCreateError::InvalidPixelStride(_))
+    ));
+    assert_eq!(
+        SharedImage::required_size(format, 2, 2, u64::MAX, u64::MAX),
+        u64::MAX
+    );
+}
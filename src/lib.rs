@@ -1,9 +1,58 @@
+//! Shared buffers between OIDN and wgpu.
+//!
+//! The DX12 and Vulkan backends share memory with OIDN without copies. The GL backend (the
+//! `gles` feature) is staging only: its images are copied through host memory around
+//! [`Device::execute_filter`], and [`enumerate_interop_adapters`] reports GL adapters as
+//! [`InteropSupport::StagingOnly`].
+
+use builder::DeviceOptions;
 use std::fmt::Debug;
 
+mod allocator;
+mod backend;
+mod builder;
+mod convert;
 #[cfg(dx12)]
 mod dx12;
+#[cfg(feature = "dynamic-oidn")]
+mod dynamic;
+mod enumerate;
+mod export;
+mod filter;
+#[cfg(gles)]
+mod gles;
+mod image;
+mod lightmap;
+mod prefilter;
+mod recovery;
+mod ring;
+#[cfg(server)]
+pub mod server;
+mod sys;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod trace;
 #[cfg(vulkan)]
 mod vulkan;
+mod worker;
+
+#[cfg(all(dx12, feature = "gpu-allocator"))]
+pub use allocator::GpuAllocator;
+pub use allocator::{
+    DefaultAllocator, ExternalMemory, ExternalMemoryAllocator, ExternalMemoryBlock,
+    ExternalMemoryRequest,
+};
+pub use backend::InteropBackend;
+pub use builder::{DeviceBuilder, OidnDeviceType, SharingMode};
+pub use convert::{Conversion, Converter, Decode, Encode, HalfConverter};
+pub use enumerate::{InteropAdapter, InteropSupport, enumerate_interop_adapters};
+pub use export::{ExportedMemory, HandleType, RawMemoryHandle};
+pub use filter::{CancellationToken, FilterConfig, FilterImages, FilterType, ProgressMonitor};
+pub use image::{FilterImage, Format, SharedImage};
+pub use lightmap::{LightmapDenoiser, LightmapImages};
+pub use prefilter::PrefilteredDenoiser;
+pub use ring::{FrameRing, FrameSet, FrameState};
+pub use worker::{DenoiseJob, DenoiseWorker, JobFuture};
 
 pub enum DeviceCreateError {
     RequestDeviceError(wgpu::RequestDeviceError),
@@ -11,6 +60,17 @@ pub enum DeviceCreateError {
     OidnImportUnsupported,
     MissingFeature,
     UnsupportedBackend(wgpu::Backend),
+    /// A backend failed to open the wgpu device itself.
+    OpenDevice(String),
+    /// The OpenImageDenoise library could not be loaded, see the `dynamic-oidn` feature.
+    OidnLibraryMissing,
+    /// The OIDN library's version (`major * 10000 + minor * 100 + patch`, 0 if it couldn't be
+    /// read) is older than 2.0, which added importing external memory.
+    ///
+    /// Only returned with the `dynamic-oidn` feature, a linked library has to be 2.x to link.
+    OidnTooOld(i32),
+    /// OIDN has no device for the adapter as the module for its vendor's GPUs isn't loaded.
+    OidnModuleMissing(OidnDeviceType),
 }
 
 impl Debug for DeviceCreateError {
@@ -29,6 +89,27 @@ impl Debug for DeviceCreateError {
                 backend.fmt(f)?;
                 f.write_str(" is not supported.")
             }
+            DeviceCreateError::OpenDevice(err) => {
+                f.write_str("Opening the device failed: ")?;
+                f.write_str(err)
+            }
+            DeviceCreateError::OidnLibraryMissing => {
+                f.write_str("The OpenImageDenoise library could not be loaded")
+            }
+            DeviceCreateError::OidnTooOld(version) => {
+                f.write_str("OIDN ")?;
+                (version / 10000).fmt(f)?;
+                f.write_str(".")?;
+                (version / 100 % 100).fmt(f)?;
+                f.write_str(".")?;
+                (version % 100).fmt(f)?;
+                f.write_str(" is too old, external memory requires OIDN 2.0")
+            }
+            DeviceCreateError::OidnModuleMissing(ty) => {
+                f.write_str("OIDN has no ")?;
+                ty.fmt(f)?;
+                f.write_str(" device module loaded for this adapter")
+            }
         }
     }
 }
@@ -37,6 +118,13 @@ pub enum SharedBufferCreateError {
     InvalidSize(wgpu::BufferAddress),
     Oidn((oidn::Error, String)),
     OutOfMemory,
+    /// The [`ExternalMemoryAllocator`] failed.
+    Allocator(String),
+    /// The sharing mode can't be used for this buffer, e.g. adopting
+    /// [`SharingMode::HostMemory`] memory or allocating with a mode the backend can't export.
+    UnsupportedSharingMode(SharingMode),
+    /// The device was lost, see [`Device::recreate`].
+    DeviceLost,
 }
 
 impl Debug for SharedBufferCreateError {
@@ -54,77 +142,403 @@ impl Debug for SharedBufferCreateError {
                 desc.fmt(f)
             }
             SharedBufferCreateError::OutOfMemory => f.write_str("Out of memory"),
+            SharedBufferCreateError::Allocator(err) => {
+                f.write_str("The external memory allocator failed: ")?;
+                f.write_str(err)
+            }
+            SharedBufferCreateError::UnsupportedSharingMode(mode) => {
+                f.write_str("The sharing mode ")?;
+                mode.fmt(f)?;
+                f.write_str(" is not supported here")
+            }
+            SharedBufferCreateError::DeviceLost => f.write_str("The device was lost"),
+        }
+    }
+}
+
+pub enum SharedImageCreateError {
+    InvalidDimensions(u32, u32),
+    InvalidPixelStride(u64),
+    /// The row stride is smaller than a row or doesn't fit the `u32` wgpu copies use.
+    InvalidRowStride(u64),
+    BufferTooSmall {
+        required: wgpu::BufferAddress,
+        available: wgpu::BufferAddress,
+    },
+    UnsupportedTextureFormat(wgpu::TextureFormat),
+    Buffer(SharedBufferCreateError),
+}
+
+impl Debug for SharedImageCreateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SharedImageCreateError::InvalidDimensions(width, height) => {
+                f.write_str("Dimensions ")?;
+                width.fmt(f)?;
+                f.write_str("x")?;
+                height.fmt(f)?;
+                f.write_str(" are not allowed")
+            }
+            SharedImageCreateError::InvalidPixelStride(stride) => {
+                f.write_str("Pixel stride ")?;
+                stride.fmt(f)?;
+                f.write_str(" is smaller than a pixel")
+            }
+            SharedImageCreateError::InvalidRowStride(stride) => {
+                f.write_str("Row stride ")?;
+                stride.fmt(f)?;
+                f.write_str(" is smaller than a row or too large for wgpu copies")
+            }
+            SharedImageCreateError::BufferTooSmall {
+                required,
+                available,
+            } => {
+                f.write_str("Image requires ")?;
+                required.fmt(f)?;
+                f.write_str(" bytes but the buffer only has ")?;
+                available.fmt(f)
+            }
+            SharedImageCreateError::UnsupportedTextureFormat(format) => {
+                f.write_str("Texture format ")?;
+                format.fmt(f)?;
+                f.write_str(" cannot be read by OIDN in place")
+            }
+            SharedImageCreateError::Buffer(err) => err.fmt(f),
+        }
+    }
+}
+
+pub enum ConvertError {
+    HalfUnsupported,
+    UnsupportedLayout,
+    UnsupportedTextureFormat(wgpu::TextureFormat),
+    DimensionMismatch {
+        texture: (u32, u32),
+        image: (u32, u32),
+    },
+}
+
+impl Debug for ConvertError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConvertError::HalfUnsupported => {
+                f.write_str("The OIDN device does not support half precision images")
+            }
+            ConvertError::UnsupportedLayout => {
+                f.write_str("The image layout is not supported by this conversion")
+            }
+            ConvertError::UnsupportedTextureFormat(format) => {
+                f.write_str("Texture format ")?;
+                format.fmt(f)?;
+                f.write_str(" is not supported by this conversion")
+            }
+            ConvertError::DimensionMismatch { texture, image } => {
+                f.write_str("Texture dimensions ")?;
+                texture.fmt(f)?;
+                f.write_str(" do not match image dimensions ")?;
+                image.fmt(f)
+            }
+        }
+    }
+}
+
+pub enum FilterError {
+    DimensionMismatch {
+        output: (u32, u32),
+        image: (u32, u32),
+    },
+    Cancelled,
+    Oidn((oidn::Error, String)),
+    /// The device was lost or an image was allocated before it was recreated.
+    DeviceLost,
+    /// Copying between the wgpu and OIDN sides of an image failed.
+    Sync(SyncError),
+}
+
+impl Debug for FilterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FilterError::DimensionMismatch { output, image } => {
+                f.write_str("Image dimensions ")?;
+                image.fmt(f)?;
+                f.write_str(" do not match output dimensions ")?;
+                output.fmt(f)
+            }
+            FilterError::Cancelled => f.write_str("The filter execution was cancelled"),
+            FilterError::Oidn((error, desc)) => {
+                f.write_str("OIDN filter execution failed with error ")?;
+                error.fmt(f)?;
+                f.write_str(": ")?;
+                desc.fmt(f)
+            }
+            FilterError::DeviceLost => {
+                f.write_str("The device was lost or the images are from before it was recreated")
+            }
+            FilterError::Sync(err) => err.fmt(f),
+        }
+    }
+}
+
+/// Why copying between the wgpu and OIDN sides of a [`SharedBuffer`] failed.
+pub enum SyncError {
+    /// Waiting for the submitted wgpu work failed.
+    Poll(wgpu::PollError),
+    /// Mapping the staging buffer the wgpu side is read through failed.
+    Map(wgpu::BufferAsyncError),
+    /// The buffer is no longer valid, see [`SharedBuffer::is_valid`].
+    DeviceLost,
+}
+
+impl Debug for SyncError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SyncError::Poll(err) => err.fmt(f),
+            SyncError::Map(err) => {
+                f.write_str("Mapping the staging buffer failed: ")?;
+                err.fmt(f)
+            }
+            SyncError::DeviceLost => f.write_str("The buffer's device was lost"),
+        }
+    }
+}
+
+pub enum LightmapError {
+    Convert(ConvertError),
+    Filter(FilterError),
+    Poll(wgpu::PollError),
+    /// The [`Device`] passed to [`LightmapDenoiser::denoise`] isn't the one the denoiser was
+    /// created with.
+    DeviceMismatch,
+}
+
+impl Debug for LightmapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LightmapError::Convert(err) => err.fmt(f),
+            LightmapError::Filter(err) => err.fmt(f),
+            LightmapError::Poll(err) => err.fmt(f),
+            LightmapError::DeviceMismatch => {
+                f.write_str("The lightmap denoiser was created on another device")
+            }
+        }
+    }
+}
+
+/// An error of a job run by a [`DenoiseWorker`].
+pub enum DenoiseJobError {
+    /// Waiting for the job's wgpu submission failed.
+    Poll(wgpu::PollError),
+    Filter(FilterError),
+    /// The filter panicked, the job's images may hold partial results.
+    Panicked,
+    /// The worker thread stopped before the job ran.
+    Stopped,
+}
+
+impl Debug for DenoiseJobError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DenoiseJobError::Poll(err) => err.fmt(f),
+            DenoiseJobError::Filter(err) => err.fmt(f),
+            DenoiseJobError::Panicked => f.write_str("The denoise job panicked"),
+            DenoiseJobError::Stopped => {
+                f.write_str("The denoise worker stopped before running the job")
+            }
         }
     }
 }
 
-#[derive(Eq, PartialEq, Clone, Copy, Debug)]
-enum Backend {
-    #[cfg(dx12)]
-    Dx12,
-    #[cfg(vulkan)]
-    Vulkan,
+/// Why [`SharedBuffer::export_raw`] failed.
+pub enum ExportError {
+    /// The memory is not shared through OS handles.
+    Unsupported,
+    /// The backend failed to create a handle, with its error.
+    Failed(String),
+    /// The buffer is no longer valid, see [`SharedBuffer::is_valid`].
+    DeviceLost,
+}
+
+impl Debug for ExportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ExportError::Unsupported => f.write_str("The memory can't be exported"),
+            ExportError::Failed(err) => {
+                f.write_str("Exporting the memory failed: ")?;
+                f.write_str(err)
+            }
+            ExportError::DeviceLost => f.write_str("The buffer's device was lost"),
+        }
+    }
 }
 
-enum BackendData {
-    #[cfg(dx12)]
-    Dx12,
-    #[cfg(vulkan)]
-    Vulkan(vulkan::VulkanSharingMode),
+pub enum RecreateError {
+    Device(DeviceCreateError),
+    /// Reallocating a buffer failed, the device was recreated.
+    Buffer(SharedBufferCreateError),
+    /// Other clones of the [`Device`] are still alive.
+    Shared,
 }
 
-impl BackendData {
-    fn as_backend(&self) -> Backend {
+impl Debug for RecreateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            #[cfg(dx12)]
-            BackendData::Dx12 => Backend::Dx12,
-            #[cfg(vulkan)]
-            BackendData::Vulkan(_) => Backend::Vulkan,
+            RecreateError::Device(err) => err.fmt(f),
+            RecreateError::Buffer(err) => err.fmt(f),
+            RecreateError::Shared => f.write_str(
+                "The device is still shared, drop its other clones before recreating it",
+            ),
         }
     }
 }
 
+/// An error of a [`server::RemoteDevice`].
+#[cfg(server)]
+pub enum RemoteError {
+    Io(std::io::Error),
+    Allocate(SharedBufferCreateError),
+    Export(ExportError),
+    /// The server failed to carry out the request.
+    Server(String),
+}
+
+#[cfg(server)]
+impl Debug for RemoteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RemoteError::Io(err) => {
+                f.write_str("Communicating with the server failed: ")?;
+                err.fmt(f)
+            }
+            RemoteError::Allocate(err) => err.fmt(f),
+            RemoteError::Export(err) => err.fmt(f),
+            RemoteError::Server(err) => {
+                f.write_str("The server failed: ")?;
+                f.write_str(err)
+            }
+        }
+    }
+}
+
+/// Loads the OpenImageDenoise library with the `dynamic-oidn` feature, failing if it is
+/// missing or older than 2.0. Without the feature the library is linked and this does nothing.
+///
+/// Creating a [`Device`], [`enumerate_interop_adapters`] and the denoise server load it
+/// themselves, this lets callers check whether OIDN is available up front. Only the crate's own
+/// calls go through the loaded library: calling the `oidn` crate directly, e.g. through
+/// [`Device::oidn_device`] or [`SharedBuffer::oidn_buffer`], links it again.
+pub fn load_oidn() -> Result<(), DeviceCreateError> {
+    #[cfg(feature = "dynamic-oidn")]
+    dynamic::load()?;
+    Ok(())
+}
+
+/// An OIDN device and a wgpu device that share memory.
+///
+/// Devices are cheap to clone and can be used from any thread, clones share the devices,
+/// the filter cache and the OIDN lock. OIDN calls made by this crate are serialized through
+/// [`Device::lock_oidn`], which should also be held when using [`Device::oidn_device`]
+/// directly while other threads use the device.
+#[derive(Clone)]
 pub struct Device {
+    inner: std::sync::Arc<DeviceInner>,
+}
+
+struct DeviceInner {
     wgpu_device: wgpu::Device,
-    oidn_device: oidn::Device,
+    /// Shared with the buffers allocated on it, see [`SharedBuffer::oidn_buffer`].
+    oidn_device: std::sync::Arc<sys::OidnDevice>,
     queue: wgpu::Queue,
-    backend_data: BackendData,
+    backend: Box<dyn backend::DynBackend>,
+    filter_cache: filter::FilterCache,
+    oidn_lock: std::sync::Mutex<()>,
+    /// The result of [`Device::supports_half_images`].
+    half_images: std::sync::OnceLock<bool>,
+    /// What [`Device::recreate`] needs to open the devices again.
+    adapter: wgpu::Adapter,
+    options: DeviceOptions,
+    desc: wgpu::wgt::DeviceDescriptor<Option<String>>,
+    /// Set when the wgpu device is lost or the devices are recreated, shared with the buffers
+    /// allocated on them.
+    lost: std::sync::Arc<std::sync::atomic::AtomicBool>,
 }
 
+// # SAFETY: OIDN's API may be called from any thread, the calls that must not run
+// concurrently on one device (committing and executing filters, importing buffers) are
+// serialized through `oidn_lock`.
+unsafe impl Sync for DeviceInner {}
+
 impl Device {
+    /// `trace_path` is ignored, wgpu traces are requested through `desc.trace` instead (which
+    /// needs `wgpu-core`'s `trace` feature). It is kept for compatibility.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            skip_all,
+            fields(backend = ?adapter.get_info().backend, adapter = %adapter.get_info().name),
+        )
+    )]
     pub async fn new(
         adapter: &wgpu::Adapter,
         desc: &wgpu::DeviceDescriptor<'_>,
         trace_path: Option<&std::path::Path>,
     ) -> Result<(Self, wgpu::Queue), DeviceCreateError> {
-        match adapter.get_info().backend {
-            #[cfg(vulkan)]
-            wgpu::Backend::Vulkan => Self::new_vulkan(adapter, desc, trace_path).await,
-            #[cfg(dx12)]
-            wgpu::Backend::Dx12 => Self::new_dx12(adapter, desc, trace_path).await,
-            _ => Err(DeviceCreateError::UnsupportedBackend(
-                adapter.get_info().backend,
-            )),
-        }
+        let _ = trace_path;
+        Self::new_with_options(adapter, desc, &DeviceOptions::default(), None).await
     }
 
+    pub(crate) async fn new_with_options(
+        adapter: &wgpu::Adapter,
+        desc: &wgpu::DeviceDescriptor<'_>,
+        options: &DeviceOptions,
+        backend: Option<Box<dyn backend::DynBackend>>,
+    ) -> Result<(Self, wgpu::Queue), DeviceCreateError> {
+        let backend = match backend {
+            Some(backend) => backend,
+            None => backend::builtin_backend(adapter, options)?,
+        };
+        Self::from_backend(
+            adapter,
+            backend,
+            options,
+            backend::WgpuDevice::Request(desc),
+        )
+        .await
+    }
+
+    /// `trace_path` is ignored, a trace of `dev` has to be requested when it is created. It is
+    /// kept for compatibility.
+    ///
+    /// The device-lost callback of `dev` is not replaced, call [`Device::notify_device_lost`]
+    /// from it so [`Device::is_lost`] and the shared buffers see the loss.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            skip_all,
+            fields(backend = ?adapter.get_info().backend, adapter = %adapter.get_info().name),
+        )
+    )]
     pub async fn new_from_dev(
         adapter: &wgpu::Adapter,
         dev: wgpu::Device,
         queue: wgpu::Queue,
         trace_path: Option<&std::path::Path>,
     ) -> Result<(Self, wgpu::Queue), DeviceCreateError> {
-        match adapter.get_info().backend {
-            #[cfg(vulkan)]
-            wgpu::Backend::Vulkan => Self::from_vulkan_device(adapter, dev, queue, trace_path).await,
-            #[cfg(dx12)]
-            wgpu::Backend::Dx12 => unimplemented!(),
-            _ => Err(DeviceCreateError::UnsupportedBackend(
-                adapter.get_info().backend,
-            )),
-        }
+        let _ = trace_path;
+        let options = DeviceOptions::default();
+        let backend = backend::builtin_backend(adapter, &options)?;
+        Self::from_backend(
+            adapter,
+            backend,
+            &options,
+            backend::WgpuDevice::Existing(dev, queue),
+        )
+        .await
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self), fields(backend = ?self.inner.backend))
+    )]
     pub fn allocate_shared_buffers(
         &self,
         size: wgpu::BufferAddress,
@@ -132,120 +546,165 @@ impl Device {
         if size == 0 {
             return Err(SharedBufferCreateError::InvalidSize(size));
         }
-        match self.backend_data.as_backend() {
-            #[cfg(dx12)]
-            Backend::Dx12 => self.allocate_shared_buffers_dx12(size),
-            #[cfg(vulkan)]
-            Backend::Vulkan => self.allocate_shared_buffers_vulkan(size),
+        if self.is_lost() {
+            return Err(SharedBufferCreateError::DeviceLost);
         }
+        let _oidn = self.lock_oidn();
+        self.inner.backend.allocate_shared(self, size)
     }
     pub fn oidn_device(&self) -> &oidn::Device {
-        &self.oidn_device
+        &self.inner.oidn_device
     }
 
     pub fn wgpu_device(&self) -> &wgpu::Device {
-        &self.wgpu_device
+        &self.inner.wgpu_device
     }
 
-    async fn new_from_raw_oidn_adapter<
-        F: FnOnce(oidn::sys::OIDNExternalMemoryTypeFlag) -> Option<BackendData>,
-    >(
-        device: oidn::sys::OIDNDevice,
-        adapter: &wgpu::Adapter,
-        desc: &wgpu::DeviceDescriptor<'_>,
-        trace_path: Option<&std::path::Path>,
-        backend_data_callback: F,
-    ) -> Result<(Self, wgpu::Queue), DeviceCreateError> {
-        if device.is_null() {
-            return Err(crate::DeviceCreateError::OidnUnsupported);
-        }
+    /// Locks the OIDN device against the OIDN calls of other threads, which is needed when
+    /// committing or executing filters on [`Device::oidn_device`] directly.
+    pub fn lock_oidn(&self) -> std::sync::MutexGuard<'_, ()> {
+        // the lock guards no data, so a panic while holding it leaves nothing broken
+        self.inner
+            .oidn_lock
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
 
-        let supported_memory_types = unsafe {
-            oidn::sys::oidnCommitDevice(device);
-            oidn::sys::oidnGetDeviceInt(device, b"externalMemoryTypes\0" as *const _ as _)
-        } as oidn::sys::OIDNExternalMemoryTypeFlag;
-        let Some(backend_data) = backend_data_callback(supported_memory_types) else {
+    /// Whether the OIDN device accepts [`Format::Half`] to [`Format::Half4`] images, checked by
+    /// committing a 1x1 `Half3` filter the first time it is called.
+    pub fn supports_half_images(&self) -> bool {
+        *self.inner.half_images.get_or_init(|| {
+            let _oidn = self.lock_oidn();
+            let device = &self.inner.oidn_device;
+            let Ok(filter) =
+                filter::new_raw_filter(device, FilterType::RayTracing, &FilterConfig::default())
+            else {
+                return false;
+            };
             unsafe {
-                oidn::sys::oidnReleaseDevice(device);
+                let buffer = crate::sys::oidnNewBuffer(
+                    device.raw(),
+                    Format::Half3.bytes_per_pixel() as usize,
+                );
+                for slot in [FilterImage::Color, FilterImage::Output] {
+                    crate::sys::oidnSetFilterImage(
+                        filter,
+                        slot.name().as_ptr() as _,
+                        buffer,
+                        Format::Half3.as_raw_oidn_format(),
+                        1,
+                        1,
+                        0,
+                        0,
+                        0,
+                    );
+                }
+                crate::sys::oidnCommitFilter(filter);
+                crate::sys::oidnReleaseFilter(filter);
+                if !buffer.is_null() {
+                    crate::sys::oidnReleaseBuffer(buffer);
+                }
             }
-            return Err(DeviceCreateError::OidnImportUnsupported);
-        };
-        let oidn_device = unsafe { oidn::Device::from_raw(device) };
-        let (wgpu_device, queue) = adapter
-            .request_device(desc)
-            .await
-            .map_err(crate::DeviceCreateError::RequestDeviceError)?;
-        Ok((
-            Self {
-                wgpu_device,
-                oidn_device,
-                queue: queue.clone(),
-                backend_data,
-            },
-            queue,
-        ))
+            sys::device_error(device).is_ok()
+        })
     }
 
-    async fn new_from_raw_oidn_device<
-        F: FnOnce(oidn::sys::OIDNExternalMemoryTypeFlag) -> Option<BackendData>,
-    >(
-        device: oidn::sys::OIDNDevice,
-        wgpu_device: wgpu::Device,
-        queue: wgpu::Queue,
-        backend_data_callback: F,
-    ) -> Result<(Self, wgpu::Queue), DeviceCreateError> {
-        if device.is_null() {
-            return Err(crate::DeviceCreateError::OidnUnsupported);
+    /// The version of the OIDN library, as `major * 10000 + minor * 100 + patch`.
+    pub fn oidn_version(&self) -> i32 {
+        unsafe {
+            crate::sys::oidnGetDeviceInt(
+                self.inner.oidn_device.raw(),
+                b"version\0" as *const _ as _,
+            )
         }
+    }
 
-        let supported_memory_types = unsafe {
-            oidn::sys::oidnCommitDevice(device);
-            oidn::sys::oidnGetDeviceInt(device, b"externalMemoryTypes\0" as *const _ as _)
-        } as oidn::sys::OIDNExternalMemoryTypeFlag;
-        let Some(backend_data) = backend_data_callback(supported_memory_types) else {
-            unsafe {
-                oidn::sys::oidnReleaseDevice(device);
-            }
-            return Err(DeviceCreateError::OidnImportUnsupported);
+    /// The type of the OIDN device, which is a CPU device when sharing host memory.
+    ///
+    /// `None` if OIDN reports a type this crate doesn't know.
+    pub fn oidn_device_type(&self) -> Option<OidnDeviceType> {
+        let ty = unsafe {
+            crate::sys::oidnGetDeviceInt(self.inner.oidn_device.raw(), b"type\0" as *const _ as _)
         };
-        let oidn_device = unsafe { oidn::Device::from_raw(device) };
-
-        Ok((
-            Self {
-                wgpu_device,
-                oidn_device,
-                queue: queue.clone(),
-                backend_data,
-            },
-            queue,
-        ))
+        OidnDeviceType::from_raw(ty as oidn::sys::OIDNDeviceType)
     }
 }
 
-enum Allocation {
-    // we keep these around to keep the allocations alive
-    #[cfg(dx12)]
-    Dx12 { _dx12: dx12::Dx12Allocation },
-    #[cfg(vulkan)]
-    Vulkan { _vulkan: vulkan::VulkanAllocation },
-}
-
+/// A buffer visible to both the OIDN device and the wgpu device.
+///
+/// Buffers can be sent to and shared between threads, share one behind an `Arc` and keep
+/// [`SharedBuffer::oidn_buffer_mut`] to the thread owning it.
 pub struct SharedBuffer {
-    _allocation: Allocation,
-    oidn_buffer: oidn::Buffer,
+    oidn_buffer: oidn::sys::OIDNBuffer,
+    /// The `oidn` crate's handle to `oidn_buffer`, created when it is first asked for.
+    oidn_handle: std::sync::OnceLock<std::mem::ManuallyDrop<oidn::Buffer>>,
+    oidn_device: std::sync::Arc<sys::OidnDevice>,
     wgpu_buffer: wgpu::Buffer,
+    // we keep this around to keep the allocation alive, it is dropped after both buffers
+    #[cfg_attr(not(any(dx12, vulkan)), allow(dead_code))]
+    allocation: Box<dyn std::any::Any + Send + Sync>,
+    lost: std::sync::Arc<std::sync::atomic::AtomicBool>,
 }
 
+// # SAFETY: OIDN buffers may be used from any thread, and the OIDN side can only be
+// written through `&mut self` or while holding the OIDN lock of its device.
+unsafe impl Send for SharedBuffer {}
+unsafe impl Sync for SharedBuffer {}
+
+static_assertions::assert_impl_all!(Device: Send, Sync, Clone);
+static_assertions::assert_impl_all!(SharedBuffer: Send, Sync);
+
 impl SharedBuffer {
+    /// Takes ownership of `oidn_buffer`, keeping `allocation` alive until both buffers are
+    /// dropped.
+    pub(crate) fn new(
+        device: &Device,
+        oidn_buffer: oidn::sys::OIDNBuffer,
+        wgpu_buffer: wgpu::Buffer,
+        allocation: Box<dyn std::any::Any + Send + Sync>,
+    ) -> Self {
+        Self {
+            oidn_buffer,
+            oidn_handle: Default::default(),
+            oidn_device: device.inner.oidn_device.clone(),
+            wgpu_buffer,
+            allocation,
+            lost: device.inner.lost.clone(),
+        }
+    }
+
+    /// The buffer as an `oidn` crate buffer.
+    ///
+    /// With the `dynamic-oidn` feature this calls the linked OIDN library, see
+    /// [`load_oidn`].
     pub fn oidn_buffer(&self) -> &oidn::Buffer {
-        &self.oidn_buffer
+        self.oidn_handle.get_or_init(|| {
+            // # SAFETY: the buffer was created on this device and is released on drop, the
+            // handle never releases it.
+            std::mem::ManuallyDrop::new(unsafe {
+                self.oidn_device.create_buffer_from_raw(self.oidn_buffer)
+            })
+        })
     }
     pub fn oidn_buffer_mut(&mut self) -> &mut oidn::Buffer {
-        &mut self.oidn_buffer
+        self.oidn_buffer();
+        self.oidn_handle.get_mut().unwrap()
     }
     pub fn wgpu_buffer(&self) -> &wgpu::Buffer {
         &self.wgpu_buffer
     }
+
+    /// Whether the devices the buffer was allocated on are still usable, buffers become
+    /// invalid when the wgpu device is lost or the [`Device`] is recreated.
+    pub fn is_valid(&self) -> bool {
+        !self.lost.load(std::sync::atomic::Ordering::Acquire)
+    }
+}
+
+impl Drop for SharedBuffer {
+    fn drop(&mut self) {
+        unsafe { sys::oidnReleaseBuffer(self.oidn_buffer) }
+    }
 }
 
 #[cfg(test)]
@@ -285,11 +744,64 @@ async fn test() {
         let mut filter = oidn::RayTracing::new(device.oidn_device());
         filter.image_dimensions(1, 1);
         filter
-            .filter_in_place_buffer(&mut bufs.oidn_buffer_mut())
+            .filter_in_place_buffer(bufs.oidn_buffer_mut())
             .unwrap();
         match device.oidn_device().get_error() {
             Ok(_) | Err((oidn::Error::OutOfMemory, _)) => {}
             Err(err) => panic!("{err:?}"),
         }
+        let image = device.allocate_shared_image(Format::Float3, 1, 1).unwrap();
+        match device.execute_filter(
+            FilterType::RayTracing,
+            &FilterConfig::default(),
+            &FilterImages::in_place(&image),
+        ) {
+            Ok(_) | Err(FilterError::Oidn((oidn::Error::OutOfMemory, _))) => {}
+            Err(err) => panic!("{err:?}"),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+#[async_std::test]
+async fn oidn_lock_serializes_filters() {
+    use crate::testing::{assert_image_approx_eq, fill_image, noop_adapter};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let (device, queue) = Device::new_testing(&noop_adapter(), &Default::default())
+        .await
+        .unwrap();
+    let images = [(); 2].map(|_| device.allocate_shared_image(Format::Float3, 8, 8).unwrap());
+    for image in &images {
+        fill_image(&queue, image, &[0.5; 3]);
+    }
+    let finished = AtomicUsize::new(0);
+    std::thread::scope(|scope| {
+        let guard = device.lock_oidn();
+        let threads = images.each_ref().map(|image| {
+            let device = device.clone();
+            let finished = &finished;
+            scope.spawn(move || {
+                device
+                    .execute_filter(
+                        FilterType::RayTracing,
+                        &FilterConfig::default(),
+                        &FilterImages::in_place(image),
+                    )
+                    .unwrap();
+                finished.fetch_add(1, Ordering::AcqRel);
+            })
+        });
+        // neither filter can run while the lock is held
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert_eq!(finished.load(Ordering::Acquire), 0);
+        drop(guard);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    });
+    assert_eq!(finished.into_inner(), 2);
+    for image in &images {
+        assert_image_approx_eq(&device, image, &[0.5; 8 * 8 * 3], 0.1);
     }
 }
@@ -0,0 +1,345 @@
+use crate::convert::{dispatch, is_tightly_packed, pixels, validate_dimensions};
+use crate::{
+    ConvertError, FilterConfig, FilterImages, FilterType, Format, LightmapError, SharedImage,
+};
+use std::sync::Mutex;
+use wgpu::util::DeviceExt;
+
+/// The lightmap images to denoise in place.
+///
+/// All images must be tightly packed [`Format::Float3`] images of the same size.
+#[derive(Copy, Clone)]
+pub struct LightmapImages<'a> {
+    pub irradiance: &'a SharedImage,
+    /// The normalized spherical harmonics coefficient images of a directional lightmap, only
+    /// denoised if [`FilterConfig::directional`] is set.
+    pub directional: &'a [&'a SharedImage],
+}
+
+/// Denoises lightmaps baked with wgpu using OIDN's `RTLightmap` filter.
+///
+/// If a chart mask is given the charts are first dilated into the surrounding
+/// padding so the filter does not blend chart edges with the empty texels
+/// around them. Afterwards the texels beyond the padding are cleared, the
+/// dilated padding is kept so sampling at chart edges stays seamless.
+pub struct LightmapDenoiser {
+    wgpu_device: wgpu::Device,
+    init_coverage: wgpu::ComputePipeline,
+    dilate: wgpu::ComputePipeline,
+    clear_outside: wgpu::ComputePipeline,
+    /// The index of each dilation pass, in slots `params_stride` bytes apart.
+    params: wgpu::Buffer,
+    params_stride: u64,
+    /// The coverage of each image's texels, kept between calls and grown as needed. Locked
+    /// for the whole of [`Self::denoise`].
+    coverage: Mutex<Vec<wgpu::Buffer>>,
+    config: FilterConfig,
+    padding: u32,
+}
+
+impl LightmapDenoiser {
+    /// Creates a denoiser running `RTLightmap` with `config`, dilating charts by `padding` texels.
+    pub fn new(device: &crate::Device, config: FilterConfig, padding: u32) -> Self {
+        let wgpu_device = device.wgpu_device().clone();
+        let module = wgpu_device.create_shader_module(wgpu::include_wgsl!("shaders/lightmap.wgsl"));
+        let pipeline = |entry_point| {
+            wgpu_device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some("oidn-wgpu-interop lightmap"),
+                layout: None,
+                module: &module,
+                entry_point: Some(entry_point),
+                compilation_options: Default::default(),
+                cache: None,
+            })
+        };
+        let init_coverage = pipeline("init_coverage");
+        let dilate = pipeline("dilate");
+        let clear_outside = pipeline("clear_outside");
+        // padded to the 16 bytes some backends require of uniform buffers
+        let params_stride =
+            16u64.next_multiple_of(wgpu_device.limits().min_uniform_buffer_offset_alignment as u64);
+        let mut contents = vec![0u8; (params_stride * padding.max(1) as u64) as usize];
+        for pass_index in 1..=padding {
+            let offset = ((pass_index - 1) as u64 * params_stride) as usize;
+            contents[offset..offset + 4].copy_from_slice(&pass_index.to_ne_bytes());
+        }
+        let params = wgpu_device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("oidn-wgpu-interop lightmap params"),
+            contents: &contents,
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        Self {
+            wgpu_device,
+            init_coverage,
+            dilate,
+            clear_outside,
+            params,
+            params_stride,
+            coverage: Mutex::new(Vec::new()),
+            config,
+            padding,
+        }
+    }
+
+    pub fn config(&self) -> &FilterConfig {
+        &self.config
+    }
+
+    /// Denoises `images` in place, writing the results back into their shared buffers.
+    ///
+    /// `mask` is a texture created with [`wgpu::TextureUsages::TEXTURE_BINDING`] whose first
+    /// channel is above one half inside charts. When it is given the dilation is submitted to
+    /// the device's queue and waited on before OIDN runs, and the pass clearing the texels
+    /// beyond the padding is submitted afterwards, so later wgpu work sees the final lightmap.
+    /// Without a mask, any wgpu work writing the images must have finished before this is
+    /// called.
+    ///
+    /// `device` has to be the device the denoiser was created with.
+    pub fn denoise(
+        &self,
+        device: &crate::Device,
+        images: &LightmapImages<'_>,
+        mask: Option<&wgpu::Texture>,
+    ) -> Result<(), LightmapError> {
+        if device.wgpu_device() != &self.wgpu_device {
+            return Err(LightmapError::DeviceMismatch);
+        }
+        let directional = if self.config.directional {
+            images.directional
+        } else {
+            &[]
+        };
+        let all = || std::iter::once(images.irradiance).chain(directional.iter().copied());
+        for image in all() {
+            if !is_tightly_packed(image, Format::Float3) {
+                return Err(LightmapError::Convert(ConvertError::UnsupportedLayout));
+            }
+            if let Some(mask) = mask {
+                validate_dimensions(mask, image).map_err(LightmapError::Convert)?;
+            }
+        }
+        let mut coverage = self.coverage.lock().unwrap();
+        if let Some(mask) = mask {
+            let size = pixels(images.irradiance) * size_of::<u32>() as u64;
+            coverage.truncate(all().count());
+            for buffer in coverage.iter_mut() {
+                if buffer.size() < size {
+                    *buffer = self.coverage_buffer(size);
+                }
+            }
+            while coverage.len() < all().count() {
+                coverage.push(self.coverage_buffer(size));
+            }
+            let mut encoder = self.wgpu_device.create_command_encoder(&Default::default());
+            for (image, coverage) in all().zip(coverage.iter()) {
+                self.record_dilation(&mut encoder, mask, image, coverage);
+            }
+            let submission = device.inner.queue.submit([encoder.finish()]);
+            device
+                .wgpu_device()
+                .poll(wgpu::PollType::WaitForSubmissionIndex(submission))
+                .map_err(LightmapError::Poll)?;
+        }
+        // the irradiance (or L0 coefficient) is not normalized
+        let irradiance_config = self.config.directional(false);
+        device
+            .execute_filter(
+                FilterType::RayTracingLightmap,
+                &irradiance_config,
+                &FilterImages::in_place(images.irradiance),
+            )
+            .map_err(LightmapError::Filter)?;
+        for image in directional {
+            device
+                .execute_filter(
+                    FilterType::RayTracingLightmap,
+                    &self.config,
+                    &FilterImages::in_place(image),
+                )
+                .map_err(LightmapError::Filter)?;
+        }
+        if let Some(mask) = mask {
+            let mut encoder = self.wgpu_device.create_command_encoder(&Default::default());
+            for (image, coverage) in all().zip(coverage.iter()) {
+                let bind_group =
+                    self.bind_group(&self.clear_outside, mask, None, Some(image), Some(coverage));
+                dispatch(
+                    &mut encoder,
+                    &self.clear_outside,
+                    &bind_group,
+                    pixels(image),
+                );
+            }
+            device.inner.queue.submit([encoder.finish()]);
+        }
+        Ok(())
+    }
+
+    fn coverage_buffer(&self, size: u64) -> wgpu::Buffer {
+        self.wgpu_device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("oidn-wgpu-interop lightmap coverage"),
+            size,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        })
+    }
+
+    fn record_dilation(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        mask: &wgpu::Texture,
+        image: &SharedImage,
+        coverage: &wgpu::Buffer,
+    ) {
+        let bind_group = self.bind_group(&self.init_coverage, mask, None, None, Some(coverage));
+        dispatch(encoder, &self.init_coverage, &bind_group, pixels(image));
+        for pass_index in 1..=self.padding {
+            let params = wgpu::BufferBinding {
+                buffer: &self.params,
+                offset: (pass_index - 1) as u64 * self.params_stride,
+                size: wgpu::BufferSize::new(16),
+            };
+            let bind_group = self.bind_group(
+                &self.dilate,
+                mask,
+                Some(params),
+                Some(image),
+                Some(coverage),
+            );
+            dispatch(encoder, &self.dilate, &bind_group, pixels(image));
+        }
+    }
+
+    /// Creates a bind group with the bindings `pipeline`'s entry point uses.
+    fn bind_group(
+        &self,
+        pipeline: &wgpu::ComputePipeline,
+        mask: &wgpu::Texture,
+        params: Option<wgpu::BufferBinding<'_>>,
+        image: Option<&SharedImage>,
+        coverage: Option<&wgpu::Buffer>,
+    ) -> wgpu::BindGroup {
+        let view = mask.create_view(&Default::default());
+        let mut entries = vec![wgpu::BindGroupEntry {
+            binding: 1,
+            resource: wgpu::BindingResource::TextureView(&view),
+        }];
+        if let Some(image) = image {
+            entries.push(wgpu::BindGroupEntry {
+                binding: 2,
+                resource: image.buffer().wgpu_buffer().as_entire_binding(),
+            });
+        }
+        if let Some(params) = params {
+            entries.push(wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(params),
+            });
+        }
+        if let Some(coverage) = coverage {
+            entries.push(wgpu::BindGroupEntry {
+                binding: 3,
+                resource: coverage.as_entire_binding(),
+            });
+        }
+        self.wgpu_device
+            .create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("oidn-wgpu-interop lightmap"),
+                layout: &pipeline.get_bind_group_layout(0),
+                entries: &entries,
+            })
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+#[async_std::test]
+#[ignore = "needs an adapter that runs shaders, run with `--ignored`"]
+async fn lightmap_dilation() {
+    use crate::testing::assert_image_approx_eq;
+
+    let adapter = wgpu::Instance::default()
+        .request_adapter(&Default::default())
+        .await
+        .expect("no adapter to run the lightmap shaders on");
+    let (device, queue) = crate::Device::new_testing(&adapter, &Default::default())
+        .await
+        .unwrap();
+    // a 4 by 2 lightmap with a chart in its first column and one texel of padding
+    let (width, height) = (4, 2);
+    let mask = device.wgpu_device().create_texture_with_data(
+        &queue,
+        &wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::R8Unorm,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        },
+        Default::default(),
+        &[255, 0, 0, 0, 255, 0, 0, 0],
+    );
+    let image = device
+        .allocate_shared_image(Format::Float3, width, height)
+        .unwrap();
+    // one value per texel, repeated over the channels
+    let write = |texels: [f32; 8]| {
+        let contents = texels
+            .iter()
+            .flat_map(|texel| [texel.to_ne_bytes(); 3])
+            .flatten()
+            .collect::<Vec<u8>>();
+        queue.write_buffer(image.buffer().wgpu_buffer(), 0, &contents);
+        queue.submit([]);
+    };
+    let expect = |texels: [f32; 8], tolerance| {
+        let expected = texels
+            .iter()
+            .flat_map(|&texel| [texel; 3])
+            .collect::<Vec<_>>();
+        assert_image_approx_eq(&device, &image, &expected, tolerance);
+    };
+    let denoiser = LightmapDenoiser::new(&device, FilterConfig::default(), 1);
+
+    // the padding takes the average of the chart texels around it, the chart is untouched
+    write([0.25, 9.0, 9.0, 9.0, 0.75, 9.0, 9.0, 9.0]);
+    let coverage = denoiser.coverage_buffer(pixels(&image) * size_of::<u32>() as u64);
+    let mut encoder = device
+        .wgpu_device()
+        .create_command_encoder(&Default::default());
+    denoiser.record_dilation(&mut encoder, &mask, &image, &coverage);
+    queue.submit([encoder.finish()]);
+    expect([0.25, 0.5, 9.0, 9.0, 0.75, 0.5, 9.0, 9.0], 0.0);
+
+    // texels beyond the padding are cleared after denoising
+    write([0.5, 9.0, 9.0, 9.0, 0.5, 9.0, 9.0, 9.0]);
+    let images = LightmapImages {
+        irradiance: &image,
+        directional: &[],
+    };
+    denoiser.denoise(&device, &images, Some(&mask)).unwrap();
+    expect([0.5, 0.5, 0.0, 0.0, 0.5, 0.5, 0.0, 0.0], 0.1);
+    let contents = crate::testing::read_buffer(&device, image.buffer()).unwrap();
+    for texel in [2, 3, 6, 7] {
+        let offset = texel * 3 * size_of::<f32>();
+        assert!(
+            contents[offset..offset + 3 * size_of::<f32>()]
+                .iter()
+                .all(|&byte| byte == 0)
+        );
+    }
+
+    let (other, _) = crate::Device::new_testing(&adapter, &Default::default())
+        .await
+        .unwrap();
+    assert!(matches!(
+        denoiser.denoise(&other, &images, Some(&mask)),
+        Err(LightmapError::DeviceMismatch)
+    ));
+}
@@ -0,0 +1,119 @@
+use crate::{
+    FilterConfig, FilterError, FilterImages, FilterType, Format, SharedImage,
+    SharedImageCreateError,
+};
+
+/// Denoises with prefiltered auxiliary images, as OIDN recommends for final quality renders.
+///
+/// The albedo and normal images are first denoised on their own into intermediate shared
+/// images, then the beauty filter is run on them with `clean_aux` set. The intermediates stay
+/// visible to wgpu, e.g. for debug views.
+pub struct PrefilteredDenoiser {
+    albedo: SharedImage,
+    normal: SharedImage,
+    config: FilterConfig,
+}
+
+impl PrefilteredDenoiser {
+    /// Allocates the intermediate images for denoising `width` by `height` images with `config`.
+    pub fn new(
+        device: &crate::Device,
+        width: u32,
+        height: u32,
+        config: FilterConfig,
+    ) -> Result<Self, SharedImageCreateError> {
+        Ok(Self {
+            albedo: device.allocate_shared_image(Format::Float3, width, height)?,
+            normal: device.allocate_shared_image(Format::Float3, width, height)?,
+            config: config.clean_aux(true),
+        })
+    }
+
+    /// Prefilters `albedo` and `normal` and then denoises `color` into `output`.
+    ///
+    /// All filters run on `device`'s OIDN device and use its filter cache.
+    pub fn denoise(
+        &self,
+        device: &crate::Device,
+        color: &SharedImage,
+        albedo: &SharedImage,
+        normal: &SharedImage,
+        output: &SharedImage,
+    ) -> Result<(), FilterError> {
+        // only the settings that apply to auxiliary images
+        let aux_config = FilterConfig::default()
+            .quality(self.config.quality)
+            .max_memory_mb(self.config.max_memory_mb);
+        device.execute_filter(
+            FilterType::RayTracing,
+            &aux_config,
+            &FilterImages::albedo_prefilter(albedo, &self.albedo),
+        )?;
+        device.execute_filter(
+            FilterType::RayTracing,
+            &aux_config,
+            &FilterImages::normal_prefilter(normal, &self.normal),
+        )?;
+        device.execute_filter(
+            FilterType::RayTracing,
+            &self.config,
+            &FilterImages::new(color, output)
+                .albedo(&self.albedo)
+                .normal(&self.normal),
+        )
+    }
+
+    pub fn config(&self) -> &FilterConfig {
+        &self.config
+    }
+    /// The albedo image as of the last [`Self::denoise`].
+    pub fn prefiltered_albedo(&self) -> &SharedImage {
+        &self.albedo
+    }
+    /// The normal image as of the last [`Self::denoise`].
+    pub fn prefiltered_normal(&self) -> &SharedImage {
+        &self.normal
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+#[async_std::test]
+async fn prefiltered_denoise() {
+    use crate::testing::{assert_image_approx_eq, fill_image, noop_adapter};
+
+    let (device, queue) = crate::Device::new_testing(&noop_adapter(), &Default::default())
+        .await
+        .unwrap();
+    let (width, height) = (8, 8);
+    let image = |pixel: [f32; 3]| {
+        let image = device
+            .allocate_shared_image(Format::Float3, width, height)
+            .unwrap();
+        fill_image(&queue, &image, &pixel);
+        image
+    };
+    let expected = |pixel: [f32; 3]| pixel.repeat((width * height) as usize);
+    // flat images stay flat through denoising
+    let color = image([0.5, 0.25, 0.125]);
+    let albedo = image([0.5, 0.5, 0.5]);
+    let normal = image([0.0, 0.0, 1.0]);
+    let output = image([0.0; 3]);
+    let denoiser =
+        PrefilteredDenoiser::new(&device, width, height, FilterConfig::default()).unwrap();
+    denoiser
+        .denoise(&device, &color, &albedo, &normal, &output)
+        .unwrap();
+    assert_image_approx_eq(
+        &device,
+        denoiser.prefiltered_albedo(),
+        &expected([0.5, 0.5, 0.5]),
+        0.05,
+    );
+    assert_image_approx_eq(
+        &device,
+        denoiser.prefiltered_normal(),
+        &expected([0.0, 0.0, 1.0]),
+        0.05,
+    );
+    assert_image_approx_eq(&device, &output, &expected([0.5, 0.25, 0.125]), 0.05);
+}
@@ -0,0 +1,98 @@
+use crate::backend::WgpuDevice;
+use crate::{Device, RecreateError, SharedBuffer};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Returns a flag that is set once `device` is lost.
+pub(crate) fn watch_device_lost(device: &wgpu::Device) -> Arc<AtomicBool> {
+    let lost = Arc::new(AtomicBool::new(false));
+    let flag = lost.clone();
+    device.set_device_lost_callback(move |reason, message| {
+        crate::trace::device_lost(reason, &message);
+        flag.store(true, Ordering::Release);
+    });
+    lost
+}
+
+impl Device {
+    /// Whether the wgpu device was lost (e.g. by a driver reset), after which allocations and
+    /// filter executions fail with `DeviceLost` errors until the device is recreated.
+    pub fn is_lost(&self) -> bool {
+        self.inner.lost.load(Ordering::Acquire)
+    }
+
+    /// Marks the wgpu device as lost. Devices created with [`Device::new_from_dev`] don't
+    /// install a device-lost callback, so the application's callback has to forward the loss.
+    pub fn notify_device_lost(&self) {
+        self.inner.lost.store(true, Ordering::Release);
+    }
+
+    /// Opens a new wgpu device and OIDN device on the same adapter and with the same options,
+    /// to recover from a lost device or an OIDN device left in a broken state.
+    ///
+    /// `buffers` are reallocated with their sizes on the new devices, their contents are lost.
+    /// Any other buffers allocated before become invalid, and everything created from the old
+    /// wgpu device (like a [`crate::Converter`]) has to be created again. Returns the new queue.
+    ///
+    /// Fails with [`RecreateError::Shared`] while other clones of this device are alive, as
+    /// they would keep using the old devices. A [`crate::DenoiseWorker`] holds a clone, so it
+    /// (and the worker a [`crate::FrameRing`] submits to) has to be dropped first. The sets of a
+    /// `FrameRing` don't, they are reallocated when next acquired.
+    pub async fn recreate(
+        &mut self,
+        buffers: &mut [&mut SharedBuffer],
+    ) -> Result<wgpu::Queue, RecreateError> {
+        let inner = Arc::get_mut(&mut self.inner).ok_or(RecreateError::Shared)?;
+        let label = inner.desc.label.clone();
+        let desc = inner.desc.map_label(|_| label.as_deref());
+        let (oidn_device, wgpu_device, queue) = Self::open(
+            &inner.adapter,
+            &mut *inner.backend,
+            &inner.options,
+            WgpuDevice::Request(&desc),
+        )
+        .await
+        .map_err(RecreateError::Device)?;
+        // the cached filters belong to the old OIDN device
+        inner.filter_cache.clear();
+        inner.half_images = Default::default();
+        inner.lost.store(true, Ordering::Release);
+        inner.lost = watch_device_lost(&wgpu_device);
+        inner.oidn_device = Arc::new(oidn_device);
+        inner.wgpu_device = wgpu_device;
+        inner.queue = queue.clone();
+        for buffer in buffers {
+            **buffer = self
+                .allocate_shared_buffers(buffer.wgpu_buffer().size())
+                .map_err(RecreateError::Buffer)?;
+        }
+        Ok(queue)
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+#[async_std::test]
+async fn recreate_after_loss() {
+    let adapter = crate::testing::noop_adapter();
+    let (mut device, _queue) = Device::new_testing(&adapter, &Default::default())
+        .await
+        .unwrap();
+    let mut kept = device.allocate_shared_buffers(64).unwrap();
+    let dropped = device.allocate_shared_buffers(64).unwrap();
+    assert!(!device.is_lost() && kept.is_valid());
+    device.notify_device_lost();
+    assert!(device.is_lost() && !kept.is_valid() && !dropped.is_valid());
+    assert!(matches!(
+        device.allocate_shared_buffers(64),
+        Err(crate::SharedBufferCreateError::DeviceLost)
+    ));
+    let clone = device.clone();
+    assert!(matches!(
+        device.recreate(&mut [&mut kept]).await,
+        Err(RecreateError::Shared)
+    ));
+    drop(clone);
+    device.recreate(&mut [&mut kept]).await.unwrap();
+    assert!(!device.is_lost() && kept.is_valid() && !dropped.is_valid());
+    assert_eq!(kept.wgpu_buffer().size(), 64);
+}
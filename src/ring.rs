@@ -0,0 +1,240 @@
+use crate::{
+    DenoiseJob, DenoiseJobError, DenoiseWorker, Device, FilterConfig, Format, SharedImage,
+    SharedImageCreateError,
+};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Who a set of a [`FrameRing`] belongs to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FrameState {
+    /// Waiting in the ring to be acquired.
+    Free,
+    /// Acquired and being rendered into by wgpu.
+    Rendering,
+    /// Handed to OIDN to be denoised.
+    Denoising,
+}
+
+/// The shared input and output images of one frame of a [`FrameRing`].
+pub struct FrameSet {
+    ring: u64,
+    index: usize,
+    color: SharedImage,
+    output: SharedImage,
+}
+
+impl FrameSet {
+    /// The position of the set in its ring.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+    /// The image wgpu renders the noisy frame into.
+    pub fn color(&self) -> &SharedImage {
+        &self.color
+    }
+    /// The image OIDN writes the denoised frame to.
+    pub fn output(&self) -> &SharedImage {
+        &self.output
+    }
+}
+
+enum Slot {
+    Free(Box<FrameSet>),
+    Rendering,
+    Denoising,
+}
+
+/// A ring of shared image sets, so one frame can be denoised while the next renders.
+///
+/// Each frame [`FrameRing::acquire`] hands out the next free set to render into, which is then
+/// denoised with [`FrameRing::submit`] and given back with [`FrameRing::release`]. After
+/// [`FrameRing::resize`] sets are reallocated when they are next acquired, so sets in flight
+/// keep their images until they are done with.
+pub struct FrameRing {
+    /// Tells the sets of different rings apart.
+    id: u64,
+    format: Format,
+    width: u32,
+    height: u32,
+    slots: Vec<Slot>,
+    next: usize,
+}
+
+impl FrameRing {
+    /// Allocates `frames` sets of `format` images of `width` by `height` pixels.
+    pub fn new(
+        device: &Device,
+        frames: usize,
+        format: Format,
+        width: u32,
+        height: u32,
+    ) -> Result<Self, SharedImageCreateError> {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        let mut ring = Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            format,
+            width,
+            height,
+            slots: Vec::with_capacity(frames),
+            next: 0,
+        };
+        for index in 0..frames {
+            let set = ring.allocate(device, index)?;
+            ring.slots.push(Slot::Free(Box::new(set)));
+        }
+        Ok(ring)
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+    /// The current viewport size, which newly acquired sets have.
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub fn state(&self, index: usize) -> FrameState {
+        match self.slots[index] {
+            Slot::Free(_) => FrameState::Free,
+            Slot::Rendering => FrameState::Rendering,
+            Slot::Denoising => FrameState::Denoising,
+        }
+    }
+
+    /// Changes the viewport size, sets are reallocated with it when they are next acquired.
+    pub fn resize(&mut self, width: u32, height: u32) -> Result<(), SharedImageCreateError> {
+        if width == 0 || height == 0 {
+            return Err(SharedImageCreateError::InvalidDimensions(width, height));
+        }
+        self.width = width;
+        self.height = height;
+        Ok(())
+    }
+
+    /// Hands out the next free set for wgpu to render into, or `None` if all sets are in
+    /// flight.
+    ///
+    /// A set from before the last [`FrameRing::resize`] (or from before the device was
+    /// recreated) is reallocated on `device` first, if that fails the old set stays free.
+    pub fn acquire(&mut self, device: &Device) -> Result<Option<FrameSet>, SharedImageCreateError> {
+        let Some(index) = (0..self.slots.len())
+            .map(|offset| (self.next + offset) % self.slots.len())
+            .find(|&index| matches!(self.slots[index], Slot::Free(_)))
+        else {
+            return Ok(None);
+        };
+        let mut set = match std::mem::replace(&mut self.slots[index], Slot::Rendering) {
+            Slot::Free(set) => set,
+            _ => unreachable!(),
+        };
+        if (set.color.width(), set.color.height()) != (self.width, self.height)
+            || !set.color.buffer().is_valid()
+        {
+            match self.allocate(device, index) {
+                Ok(fresh) => *set = fresh,
+                Err(err) => {
+                    self.slots[index] = Slot::Free(set);
+                    return Err(err);
+                }
+            }
+        }
+        self.next = (index + 1) % self.slots.len();
+        Ok(Some(*set))
+    }
+
+    /// Marks `set` as handed to OIDN, for denoising it without a [`DenoiseWorker`].
+    ///
+    /// Panics if `set` wasn't acquired from this ring.
+    pub fn begin_denoise(&mut self, set: &FrameSet) {
+        assert!(
+            set.ring == self.id && matches!(self.slots[set.index], Slot::Rendering),
+            "the set was not acquired from this ring"
+        );
+        self.slots[set.index] = Slot::Denoising;
+    }
+
+    /// Denoises `set` on `worker` once the wgpu `submission` rendering it has finished, calling
+    /// `on_done` on the worker thread with the set, which then has to be
+    /// [released](FrameRing::release).
+    ///
+    /// If the worker thread has stopped the set is given back still rendering, to be released
+    /// or submitted again.
+    pub fn submit(
+        &mut self,
+        worker: &DenoiseWorker,
+        set: FrameSet,
+        config: FilterConfig,
+        submission: wgpu::SubmissionIndex,
+        on_done: impl FnOnce(FrameSet, Result<(), DenoiseJobError>) + Send + 'static,
+    ) -> Result<(), Box<FrameSet>> {
+        self.begin_denoise(&set);
+        let (ring, index) = (set.ring, set.index);
+        let into_set = move |job: DenoiseJob| FrameSet {
+            ring,
+            index,
+            color: job.color,
+            output: job.output.expect("ring jobs have an output image"),
+        };
+        let job = DenoiseJob::new(set.color, set.output)
+            .config(config)
+            .wait_for(submission);
+        worker
+            .submit(job, move |job, result| on_done(into_set(job), result))
+            .map_err(|job| {
+                self.slots[index] = Slot::Rendering;
+                Box::new(into_set(*job))
+            })
+    }
+
+    /// Gives `set` back to the ring once OIDN (or wgpu, if it was never denoised) is done
+    /// with it.
+    ///
+    /// Panics if `set` wasn't acquired from this ring.
+    pub fn release(&mut self, set: FrameSet) {
+        let index = set.index;
+        assert!(
+            set.ring == self.id && !matches!(self.slots[index], Slot::Free(_)),
+            "the set was not acquired from this ring"
+        );
+        self.slots[index] = Slot::Free(Box::new(set));
+    }
+
+    fn allocate(&self, device: &Device, index: usize) -> Result<FrameSet, SharedImageCreateError> {
+        Ok(FrameSet {
+            ring: self.id,
+            index,
+            color: device.allocate_shared_image(self.format, self.width, self.height)?,
+            output: device.allocate_shared_image(self.format, self.width, self.height)?,
+        })
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+#[async_std::test]
+async fn ring_hands_out_free_sets() {
+    let adapter = crate::testing::noop_adapter();
+    let (device, _queue) = Device::new_testing(&adapter, &Default::default())
+        .await
+        .unwrap();
+    let mut ring = FrameRing::new(&device, 2, Format::Float3, 4, 4).unwrap();
+    let first = ring.acquire(&device).unwrap().unwrap();
+    let second = ring.acquire(&device).unwrap().unwrap();
+    assert!(ring.acquire(&device).unwrap().is_none());
+    ring.begin_denoise(&first);
+    assert_eq!(ring.state(first.index()), FrameState::Denoising);
+    ring.resize(8, 2).unwrap();
+    ring.release(second);
+    let resized = ring.acquire(&device).unwrap().unwrap();
+    assert_eq!((resized.color().width(), resized.color().height()), (8, 2));
+    // the set in flight keeps its images
+    assert_eq!((first.output().width(), first.output().height()), (4, 4));
+
+    // a set can't be given back to another ring, even if its slot there is in flight
+    let mut other = FrameRing::new(&device, 2, Format::Float3, 4, 4).unwrap();
+    let _in_flight = [other.acquire(&device), other.acquire(&device)];
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| other.release(resized)));
+    assert!(result.is_err());
+}
@@ -0,0 +1,961 @@
+//! Denoising in a separate process, so a crash in OIDN or its driver doesn't take the renderer
+//! down with it.
+//!
+//! A [`Server`] owns the OIDN device and serves [`RemoteDevice`]s connected to it over Unix
+//! domain sockets, the `oidn-server` binary runs one listening on a socket path. Buffers are
+//! allocated by the client, either on its wgpu device or as memfd backed host memory, and their
+//! file descriptors are passed to the server with `SCM_RIGHTS` to be imported into OIDN, so
+//! images never go through the socket.
+//!
+//! Requests and replies are frames of a little endian `u32` length followed by a tag byte and
+//! the request's fields. Every request is answered before the next one is read.
+
+use crate::image::validate_layout;
+use crate::sys::OidnDevice;
+use crate::{
+    DefaultAllocator, DeviceCreateError, ExternalMemoryAllocator, FilterConfig, FilterImage,
+    FilterType, Format, RemoteError, SharedBufferCreateError, SharedImageCreateError, SharingMode,
+};
+use oidn::sys::{
+    OIDNDeviceType_OIDN_DEVICE_TYPE_DEFAULT,
+    OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_DMA_BUF,
+    OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_FD,
+};
+use rustix::fd::{AsFd, BorrowedFd, FromRawFd, IntoRawFd, OwnedFd};
+use rustix::io::Errno;
+use rustix::mm::{MapFlags, ProtFlags};
+use rustix::net::{
+    RecvAncillaryBuffer, RecvAncillaryMessage, RecvFlags, SendAncillaryBuffer,
+    SendAncillaryMessage, SendFlags,
+};
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::io::{self, IoSlice, IoSliceMut, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::ptr::{self, NonNull};
+use std::sync::Arc;
+
+/// Requests are much smaller than this, so larger frames are rejected as malformed.
+const MAX_FRAME_SIZE: usize = 1 << 16;
+
+const REQUEST_IMPORT: u8 = 0;
+const REQUEST_RELEASE: u8 = 1;
+const REQUEST_EXECUTE: u8 = 2;
+
+const REPLY_OK: u8 = 0;
+const REPLY_ERROR: u8 = 1;
+
+// the wire values of these are their indices
+const FILTER_TYPES: [FilterType; 2] = [FilterType::RayTracing, FilterType::RayTracingLightmap];
+const QUALITIES: [oidn::Quality; 4] = [
+    oidn::Quality::Default,
+    oidn::Quality::Fast,
+    oidn::Quality::Balanced,
+    oidn::Quality::High,
+];
+const FORMATS: [Format; 8] = [
+    Format::Float,
+    Format::Float2,
+    Format::Float3,
+    Format::Float4,
+    Format::Half,
+    Format::Half2,
+    Format::Half3,
+    Format::Half4,
+];
+const SLOTS: [FilterImage; 4] = [
+    FilterImage::Color,
+    FilterImage::Albedo,
+    FilterImage::Normal,
+    FilterImage::Output,
+];
+
+/// How the memory behind a file descriptor is imported into OIDN.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Transport {
+    OpaqueFd,
+    DmaBuf,
+    /// Host memory mapped from a memfd.
+    Memfd,
+}
+
+const TRANSPORTS: [Transport; 3] = [Transport::OpaqueFd, Transport::DmaBuf, Transport::Memfd];
+
+#[derive(Debug, Copy, Clone)]
+struct ImageDesc {
+    buffer: u32,
+    format: Format,
+    width: u32,
+    height: u32,
+    pixel_byte_stride: u64,
+    row_byte_stride: u64,
+}
+
+#[derive(Debug)]
+enum Request {
+    /// Sent along with the file descriptor to import.
+    Import {
+        id: u32,
+        transport: Transport,
+        size: u64,
+    },
+    Release {
+        id: u32,
+    },
+    /// The images are in the order of [`SLOTS`].
+    Execute {
+        ty: FilterType,
+        config: FilterConfig,
+        images: [Option<ImageDesc>; 4],
+    },
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn index_of<T: PartialEq>(values: &[T], value: &T) -> u8 {
+    values.iter().position(|v| v == value).unwrap() as u8
+}
+
+fn encode_option<T>(out: &mut Vec<u8>, value: Option<T>, encode: impl FnOnce(&mut Vec<u8>, T)) {
+    match value {
+        Some(value) => {
+            out.push(1);
+            encode(out, value);
+        }
+        None => out.push(0),
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn bytes<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        if self.0.len() < N {
+            return Err(invalid_data("truncated frame"));
+        }
+        let (bytes, rest) = self.0.split_at(N);
+        self.0 = rest;
+        Ok(bytes.try_into().unwrap())
+    }
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes::<1>()?[0])
+    }
+    fn u32(&mut self) -> io::Result<u32> {
+        self.bytes().map(u32::from_le_bytes)
+    }
+    fn u64(&mut self) -> io::Result<u64> {
+        self.bytes().map(u64::from_le_bytes)
+    }
+    fn index<T: Copy>(&mut self, values: &[T]) -> io::Result<T> {
+        let index = self.u8()?;
+        values
+            .get(index as usize)
+            .copied()
+            .ok_or_else(|| invalid_data("unknown enum value"))
+    }
+    fn option<T>(
+        &mut self,
+        decode: impl FnOnce(&mut Self) -> io::Result<T>,
+    ) -> io::Result<Option<T>> {
+        match self.u8()? {
+            0 => Ok(None),
+            _ => decode(self).map(Some),
+        }
+    }
+}
+
+impl Request {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        match self {
+            Request::Import {
+                id,
+                transport,
+                size,
+            } => {
+                out.push(REQUEST_IMPORT);
+                out.extend_from_slice(&id.to_le_bytes());
+                out.push(index_of(&TRANSPORTS, transport));
+                out.extend_from_slice(&size.to_le_bytes());
+            }
+            Request::Release { id } => {
+                out.push(REQUEST_RELEASE);
+                out.extend_from_slice(&id.to_le_bytes());
+            }
+            Request::Execute { ty, config, images } => {
+                out.push(REQUEST_EXECUTE);
+                out.push(index_of(&FILTER_TYPES, ty));
+                out.push(index_of(&QUALITIES, &config.quality));
+                out.push(
+                    config.hdr as u8
+                        | (config.srgb as u8) << 1
+                        | (config.clean_aux as u8) << 2
+                        | (config.directional as u8) << 3,
+                );
+                encode_option(&mut out, config.input_scale, |out, scale| {
+                    out.extend_from_slice(&scale.to_bits().to_le_bytes())
+                });
+                encode_option(&mut out, config.max_memory_mb, |out, mb| {
+                    out.extend_from_slice(&mb.to_le_bytes())
+                });
+                for image in images {
+                    encode_option(&mut out, *image, |out, image| {
+                        out.extend_from_slice(&image.buffer.to_le_bytes());
+                        out.push(index_of(&FORMATS, &image.format));
+                        out.extend_from_slice(&image.width.to_le_bytes());
+                        out.extend_from_slice(&image.height.to_le_bytes());
+                        out.extend_from_slice(&image.pixel_byte_stride.to_le_bytes());
+                        out.extend_from_slice(&image.row_byte_stride.to_le_bytes());
+                    });
+                }
+            }
+        }
+        out
+    }
+
+    fn decode(payload: &[u8]) -> io::Result<Self> {
+        let mut reader = Reader(payload);
+        let request = match reader.u8()? {
+            REQUEST_IMPORT => Request::Import {
+                id: reader.u32()?,
+                transport: reader.index(&TRANSPORTS)?,
+                size: reader.u64()?,
+            },
+            REQUEST_RELEASE => Request::Release { id: reader.u32()? },
+            REQUEST_EXECUTE => {
+                let ty = reader.index(&FILTER_TYPES)?;
+                let quality = reader.index(&QUALITIES)?;
+                let flags = reader.u8()?;
+                let config = FilterConfig {
+                    quality,
+                    hdr: flags & 1 != 0,
+                    srgb: flags & 1 << 1 != 0,
+                    clean_aux: flags & 1 << 2 != 0,
+                    directional: flags & 1 << 3 != 0,
+                    input_scale: reader.option(|r| r.u32().map(f32::from_bits))?,
+                    max_memory_mb: reader.option(Reader::u32)?,
+                };
+                let mut images = [None; 4];
+                for image in &mut images {
+                    *image = reader.option(|r| {
+                        Ok(ImageDesc {
+                            buffer: r.u32()?,
+                            format: r.index(&FORMATS)?,
+                            width: r.u32()?,
+                            height: r.u32()?,
+                            pixel_byte_stride: r.u64()?,
+                            row_byte_stride: r.u64()?,
+                        })
+                    })?;
+                }
+                Request::Execute { ty, config, images }
+            }
+            _ => return Err(invalid_data("unknown request")),
+        };
+        if !reader.0.is_empty() {
+            return Err(invalid_data("trailing bytes in request"));
+        }
+        Ok(request)
+    }
+}
+
+/// Sends a frame, with `fd` attached to its first byte.
+fn send_frame(stream: &UnixStream, payload: &[u8], fd: Option<BorrowedFd<'_>>) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(payload);
+    let mut space = [0; rustix::cmsg_space!(ScmRights(1))];
+    let mut control = SendAncillaryBuffer::new(&mut space);
+    let fds = fd.as_slice();
+    if !fds.is_empty() {
+        control.push(SendAncillaryMessage::ScmRights(fds));
+    }
+    let sent = loop {
+        match rustix::net::sendmsg(
+            stream,
+            &[IoSlice::new(&frame)],
+            &mut control,
+            SendFlags::NOSIGNAL,
+        ) {
+            Err(Errno::INTR) => continue,
+            result => break result?,
+        }
+    };
+    let mut stream = stream;
+    stream.write_all(&frame[sent..])
+}
+
+/// Receives a frame and the file descriptor sent with it, returning `None` once the peer
+/// disconnected.
+fn recv_frame(stream: &UnixStream) -> io::Result<Option<(Vec<u8>, Option<OwnedFd>)>> {
+    let mut len = [0; 4];
+    let mut space = [0; rustix::cmsg_space!(ScmRights(1))];
+    let mut control = RecvAncillaryBuffer::new(&mut space);
+    let received = loop {
+        match rustix::net::recvmsg(
+            stream,
+            &mut [IoSliceMut::new(&mut len)],
+            &mut control,
+            RecvFlags::CMSG_CLOEXEC,
+        ) {
+            Err(Errno::INTR) => continue,
+            result => break result?.bytes,
+        }
+    };
+    if received == 0 {
+        return Ok(None);
+    }
+    // any further descriptors are closed
+    let fd = control.drain().find_map(|message| match message {
+        RecvAncillaryMessage::ScmRights(mut fds) => fds.next(),
+        _ => None,
+    });
+    let mut stream = stream;
+    stream.read_exact(&mut len[received..])?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(invalid_data("frame too large"));
+    }
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload)?;
+    Ok(Some((payload, fd)))
+}
+
+/// A shared mapping of a memfd.
+struct Mapping {
+    ptr: NonNull<c_void>,
+    len: usize,
+}
+
+// # SAFETY: the memory is only accessed through copies and by OIDN.
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    fn new(fd: BorrowedFd<'_>, len: usize) -> io::Result<Self> {
+        // # SAFETY: a new mapping doesn't alias any memory.
+        let ptr = unsafe {
+            rustix::mm::mmap(
+                ptr::null_mut(),
+                len,
+                ProtFlags::READ | ProtFlags::WRITE,
+                MapFlags::SHARED,
+                fd,
+                0,
+            )?
+        };
+        let ptr = NonNull::new(ptr).ok_or_else(|| io::Error::other("mmap returned null"))?;
+        Ok(Self { ptr, len })
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = rustix::mm::munmap(self.ptr.as_ptr(), self.len);
+        }
+    }
+}
+
+/// A client's buffer imported into OIDN.
+struct ImportedBuffer {
+    buffer: oidn::sys::OIDNBuffer,
+    size: u64,
+    // unmapped after the OIDN buffer is released
+    _mapping: Option<Mapping>,
+}
+
+impl Drop for ImportedBuffer {
+    fn drop(&mut self) {
+        unsafe { crate::sys::oidnReleaseBuffer(self.buffer) }
+    }
+}
+
+/// Serves [`RemoteDevice`]s, running their filters on an OIDN device.
+pub struct Server {
+    device: OidnDevice,
+}
+
+impl Server {
+    /// Serves clients on `device`, which has to support importing the memory clients share
+    /// with it: host memory for staging buffers, and opaque FDs or dma-bufs of the GPU it runs
+    /// on for shared buffers.
+    ///
+    /// Fails if the OIDN library the server calls can't be loaded, see [`crate::load_oidn`].
+    pub fn new(device: oidn::Device) -> Result<Self, DeviceCreateError> {
+        let device = OidnDevice::new(device);
+        crate::load_oidn()?;
+        Ok(Self { device })
+    }
+
+    /// Serves clients on a new OIDN device of the default type, see [`Server::new`].
+    pub fn with_default_device() -> Result<Self, DeviceCreateError> {
+        crate::load_oidn()?;
+        let device = unsafe { crate::sys::oidnNewDevice(OIDNDeviceType_OIDN_DEVICE_TYPE_DEFAULT) };
+        if device.is_null() {
+            return Err(DeviceCreateError::OidnUnsupported);
+        }
+        let device = unsafe {
+            crate::trace::install_oidn_error_callback(device);
+            crate::sys::oidnCommitDevice(device);
+            OidnDevice::from_raw(device)
+        };
+        if let Err(err) = crate::sys::device_error(&device) {
+            crate::trace::oidn_error("Failed to create the OIDN device", &err);
+            return Err(DeviceCreateError::OidnUnsupported);
+        }
+        Ok(Self { device })
+    }
+
+    pub fn oidn_device(&self) -> &oidn::Device {
+        &self.device
+    }
+
+    /// Serves requests on `stream` until the client disconnects, then releases its buffers.
+    ///
+    /// Failed requests are reported to the client, this only fails if the connection does or
+    /// the client sends a malformed request.
+    pub fn serve(&self, stream: &UnixStream) -> io::Result<()> {
+        let mut buffers = HashMap::new();
+        while let Some((payload, fd)) = recv_frame(stream)? {
+            let result = match Request::decode(&payload)? {
+                Request::Import {
+                    id,
+                    transport,
+                    size,
+                } => {
+                    let fd = fd.ok_or_else(|| invalid_data("import without a file descriptor"))?;
+                    self.import(fd, transport, size).map(|buffer| {
+                        buffers.insert(id, buffer);
+                    })
+                }
+                Request::Release { id } => {
+                    buffers.remove(&id);
+                    Ok(())
+                }
+                Request::Execute { ty, config, images } => {
+                    self.execute(&buffers, ty, &config, &images)
+                }
+            };
+            let reply = match result {
+                Ok(()) => vec![REPLY_OK],
+                Err(err) => [&[REPLY_ERROR], err.as_bytes()].concat(),
+            };
+            send_frame(stream, &reply, None)?;
+        }
+        Ok(())
+    }
+
+    fn import(
+        &self,
+        fd: OwnedFd,
+        transport: Transport,
+        size: u64,
+    ) -> Result<ImportedBuffer, String> {
+        let (raw, mapping) = match transport {
+            Transport::Memfd => {
+                // mapping past the end of the file would crash on access
+                let file_size = rustix::fs::fstat(&fd)
+                    .map_err(|err| err.to_string())?
+                    .st_size;
+                if (file_size as u64) < size {
+                    return Err(format!("the memfd holds {file_size} bytes, not {size}"));
+                }
+                let mapping =
+                    Mapping::new(fd.as_fd(), size as usize).map_err(|err| err.to_string())?;
+                let raw = unsafe {
+                    crate::sys::oidnNewSharedBuffer(
+                        self.device.raw(),
+                        mapping.ptr.as_ptr(),
+                        size as usize,
+                    )
+                };
+                (raw, Some(mapping))
+            }
+            Transport::OpaqueFd | Transport::DmaBuf => {
+                let flag = match transport {
+                    Transport::DmaBuf => {
+                        OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_DMA_BUF
+                    }
+                    _ => OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_FD,
+                };
+                // OIDN owns the descriptor once it is imported
+                let raw_fd = fd.into_raw_fd();
+                let raw = unsafe {
+                    crate::sys::oidnNewSharedBufferFromFD(
+                        self.device.raw(),
+                        flag,
+                        raw_fd,
+                        size as usize,
+                    )
+                };
+                if raw.is_null() {
+                    drop(unsafe { OwnedFd::from_raw_fd(raw_fd) });
+                }
+                (raw, None)
+            }
+        };
+        let buffer = crate::backend::check_oidn_buffer(&self.device, raw)
+            .map_err(|err| format!("{err:?}"))?;
+        Ok(ImportedBuffer {
+            buffer,
+            size,
+            _mapping: mapping,
+        })
+    }
+
+    fn execute(
+        &self,
+        buffers: &HashMap<u32, ImportedBuffer>,
+        ty: FilterType,
+        config: &FilterConfig,
+        images: &[Option<ImageDesc>; 4],
+    ) -> Result<(), String> {
+        let filter = crate::filter::new_raw_filter(&self.device, ty, config)
+            .map_err(|err| format!("{err:?}"))?;
+        let result = (|| {
+            for (slot, image) in SLOTS.iter().zip(images) {
+                let Some(image) = image else {
+                    continue;
+                };
+                let buffer = buffers
+                    .get(&image.buffer)
+                    .ok_or_else(|| format!("unknown buffer {}", image.buffer))?;
+                validate_layout(
+                    image.format,
+                    image.width,
+                    image.height,
+                    image.pixel_byte_stride,
+                    image.row_byte_stride,
+                    buffer.size,
+                )
+                .map_err(|err| format!("{err:?}"))?;
+                unsafe {
+                    crate::sys::oidnSetFilterImage(
+                        filter,
+                        slot.name().as_ptr() as _,
+                        buffer.buffer,
+                        image.format.as_raw_oidn_format(),
+                        image.width as usize,
+                        image.height as usize,
+                        0,
+                        image.pixel_byte_stride as usize,
+                        image.row_byte_stride as usize,
+                    );
+                }
+            }
+            unsafe {
+                crate::sys::oidnCommitFilter(filter);
+                crate::sys::oidnExecuteFilter(filter);
+            }
+            crate::sys::device_error(&self.device)
+                .map_err(|err| format!("{:?}", crate::FilterError::Oidn(err)))
+        })();
+        unsafe { crate::sys::oidnReleaseFilter(filter) };
+        result
+    }
+}
+
+#[cfg_attr(not(vulkan), allow(dead_code))]
+enum RemoteMemory {
+    Shared {
+        wgpu_buffer: wgpu::Buffer,
+        // dropped after the wgpu buffer
+        _allocation: Box<dyn std::any::Any + Send + Sync>,
+    },
+    Staging(Mapping),
+}
+
+/// A buffer shared with a [`Server`].
+pub struct RemoteBuffer {
+    id: u32,
+    size: u64,
+    memory: RemoteMemory,
+}
+
+impl RemoteBuffer {
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The wgpu side of a shared buffer, `None` for staging buffers.
+    pub fn wgpu_buffer(&self) -> Option<&wgpu::Buffer> {
+        match &self.memory {
+            RemoteMemory::Shared { wgpu_buffer, .. } => Some(wgpu_buffer),
+            RemoteMemory::Staging(_) => None,
+        }
+    }
+
+    /// Copies out the contents of a staging buffer, `None` for shared buffers.
+    pub fn read_staging(&self) -> Option<Vec<u8>> {
+        match &self.memory {
+            RemoteMemory::Shared { .. } => None,
+            RemoteMemory::Staging(mapping) => {
+                let mut contents = vec![0; mapping.len];
+                // # SAFETY: the mapping is `len` bytes long.
+                unsafe {
+                    ptr::copy_nonoverlapping(
+                        mapping.ptr.as_ptr() as *const u8,
+                        contents.as_mut_ptr(),
+                        mapping.len,
+                    );
+                }
+                Some(contents)
+            }
+        }
+    }
+
+    /// Copies `data` into a staging buffer at `offset`.
+    ///
+    /// # Panics
+    ///
+    /// If this is a shared buffer or `data` doesn't fit.
+    pub fn write_staging(&mut self, offset: u64, data: &[u8]) {
+        let RemoteMemory::Staging(mapping) = &self.memory else {
+            panic!("only staging buffers can be written to directly");
+        };
+        assert!(
+            offset
+                .checked_add(data.len() as u64)
+                .is_some_and(|end| end <= self.size),
+            "writing {} bytes at {offset} overruns a buffer of {} bytes",
+            data.len(),
+            self.size
+        );
+        // # SAFETY: checked to be in bounds above.
+        unsafe {
+            ptr::copy_nonoverlapping(
+                data.as_ptr(),
+                (mapping.ptr.as_ptr() as *mut u8).add(offset as usize),
+                data.len(),
+            );
+        }
+    }
+}
+
+/// A [`RemoteBuffer`] interpreted as a two dimensional image, like a [`crate::SharedImage`].
+#[derive(Copy, Clone)]
+pub struct RemoteImage<'a> {
+    buffer: &'a RemoteBuffer,
+    format: Format,
+    width: u32,
+    height: u32,
+    pixel_byte_stride: u64,
+    row_byte_stride: u64,
+}
+
+impl<'a> RemoteImage<'a> {
+    /// Creates a tightly packed image over `buffer`.
+    pub fn new(
+        buffer: &'a RemoteBuffer,
+        format: Format,
+        width: u32,
+        height: u32,
+    ) -> Result<Self, SharedImageCreateError> {
+        let pixel_byte_stride = format.bytes_per_pixel();
+        Self::with_strides(
+            buffer,
+            format,
+            width,
+            height,
+            pixel_byte_stride,
+            pixel_byte_stride * width as u64,
+        )
+    }
+
+    /// Like [`crate::SharedImage::with_strides`].
+    pub fn with_strides(
+        buffer: &'a RemoteBuffer,
+        format: Format,
+        width: u32,
+        height: u32,
+        pixel_byte_stride: u64,
+        row_byte_stride: u64,
+    ) -> Result<Self, SharedImageCreateError> {
+        validate_layout(
+            format,
+            width,
+            height,
+            pixel_byte_stride,
+            row_byte_stride,
+            buffer.size,
+        )?;
+        Ok(Self {
+            buffer,
+            format,
+            width,
+            height,
+            pixel_byte_stride,
+            row_byte_stride,
+        })
+    }
+
+    pub fn buffer(&self) -> &'a RemoteBuffer {
+        self.buffer
+    }
+
+    fn desc(&self) -> ImageDesc {
+        ImageDesc {
+            buffer: self.buffer.id,
+            format: self.format,
+            width: self.width,
+            height: self.height,
+            pixel_byte_stride: self.pixel_byte_stride,
+            row_byte_stride: self.row_byte_stride,
+        }
+    }
+}
+
+/// The images a remote filter reads from and writes to, like [`crate::FilterImages`].
+#[derive(Copy, Clone)]
+pub struct RemoteFilterImages<'a> {
+    pub color: Option<RemoteImage<'a>>,
+    pub albedo: Option<RemoteImage<'a>>,
+    /// Only used if `albedo` is also set, or if there is no `color`.
+    pub normal: Option<RemoteImage<'a>>,
+    pub output: RemoteImage<'a>,
+}
+
+impl<'a> RemoteFilterImages<'a> {
+    pub fn new(color: RemoteImage<'a>, output: RemoteImage<'a>) -> Self {
+        Self {
+            color: Some(color),
+            albedo: None,
+            normal: None,
+            output,
+        }
+    }
+    pub fn in_place(color: RemoteImage<'a>) -> Self {
+        Self::new(color, color)
+    }
+    pub fn albedo(mut self, albedo: RemoteImage<'a>) -> Self {
+        self.albedo = Some(albedo);
+        self
+    }
+    pub fn normal(mut self, normal: RemoteImage<'a>) -> Self {
+        self.normal = Some(normal);
+        self
+    }
+}
+
+/// A connection to a [`Server`], sharing buffers allocated in this process with it.
+pub struct RemoteDevice {
+    stream: UnixStream,
+    allocator: Arc<dyn ExternalMemoryAllocator>,
+    next_id: u32,
+}
+
+impl RemoteDevice {
+    pub fn connect(path: impl AsRef<Path>) -> Result<Self, RemoteError> {
+        UnixStream::connect(path)
+            .map(Self::from_stream)
+            .map_err(RemoteError::Io)
+    }
+
+    pub fn from_stream(stream: UnixStream) -> Self {
+        Self {
+            stream,
+            allocator: Arc::new(DefaultAllocator),
+            next_id: 0,
+        }
+    }
+
+    /// Allocates the memory of shared buffers through `allocator` instead of the
+    /// [`DefaultAllocator`].
+    pub fn allocator(mut self, allocator: impl ExternalMemoryAllocator) -> Self {
+        self.allocator = Arc::new(allocator);
+        self
+    }
+
+    /// Allocates a zeroed buffer on `device` and shares it with the server, whose OIDN device
+    /// has to run on the same GPU.
+    ///
+    /// Only Vulkan devices are supported, with [`SharingMode::OpaqueFd`] or
+    /// [`SharingMode::DmaBuf`].
+    pub fn allocate_shared_buffer(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        sharing_mode: SharingMode,
+        size: wgpu::BufferAddress,
+    ) -> Result<RemoteBuffer, RemoteError> {
+        if size == 0 {
+            return Err(RemoteError::Allocate(SharedBufferCreateError::InvalidSize(
+                size,
+            )));
+        }
+        let transport = match sharing_mode {
+            SharingMode::OpaqueFd => Transport::OpaqueFd,
+            SharingMode::DmaBuf => Transport::DmaBuf,
+            _ => {
+                return Err(RemoteError::Allocate(
+                    SharedBufferCreateError::UnsupportedSharingMode(sharing_mode),
+                ));
+            }
+        };
+        #[cfg(vulkan)]
+        if let Some(allocation) = unsafe {
+            crate::vulkan::allocate_unimported(device, queue, sharing_mode, &self.allocator, size)
+        } {
+            let (allocation, wgpu_buffer) = allocation.map_err(RemoteError::Allocate)?;
+            let exported = unsafe { allocation.export() }.map_err(RemoteError::Export)?;
+            let crate::RawMemoryHandle::Fd(fd) = exported.handle;
+            let id = self.import(fd.as_fd(), transport, size)?;
+            return Ok(RemoteBuffer {
+                id,
+                size,
+                memory: RemoteMemory::Shared {
+                    wgpu_buffer,
+                    _allocation: Box::new(allocation),
+                },
+            });
+        }
+        let _ = (device, queue, transport);
+        Err(RemoteError::Allocate(
+            SharedBufferCreateError::UnsupportedSharingMode(sharing_mode),
+        ))
+    }
+
+    /// Allocates a zeroed buffer in host memory and shares it with the server through a memfd.
+    ///
+    /// This needs no GPU in this process, but the server's OIDN device has to be able to access
+    /// host memory, like CPU devices can.
+    pub fn allocate_staging_buffer(
+        &mut self,
+        size: wgpu::BufferAddress,
+    ) -> Result<RemoteBuffer, RemoteError> {
+        if size == 0 {
+            return Err(RemoteError::Allocate(SharedBufferCreateError::InvalidSize(
+                size,
+            )));
+        }
+        let fd = rustix::fs::memfd_create("oidn-wgpu-interop", rustix::fs::MemfdFlags::CLOEXEC)
+            .and_then(|fd| rustix::fs::ftruncate(&fd, size).map(|()| fd))
+            .map_err(|err| RemoteError::Io(err.into()))?;
+        let mapping = Mapping::new(fd.as_fd(), size as usize).map_err(RemoteError::Io)?;
+        let id = self.import(fd.as_fd(), Transport::Memfd, size)?;
+        Ok(RemoteBuffer {
+            id,
+            size,
+            memory: RemoteMemory::Staging(mapping),
+        })
+    }
+
+    /// Releases `buffer` on the server. Buffers that are dropped instead stay imported until
+    /// the connection is closed.
+    pub fn release(&mut self, buffer: RemoteBuffer) -> Result<(), RemoteError> {
+        self.request(&Request::Release { id: buffer.id }, None)
+    }
+
+    /// Runs a filter of type `ty` over `images` on the server, waiting for it to finish.
+    ///
+    /// Like [`crate::Device::execute_filter`], any wgpu work using the images must have
+    /// finished before this is called. The server commits a new filter for every execution.
+    pub fn execute_filter(
+        &mut self,
+        ty: FilterType,
+        config: &FilterConfig,
+        images: &RemoteFilterImages<'_>,
+    ) -> Result<(), RemoteError> {
+        // no use supplying the normal if the albedo was not also given.
+        let normal = match images.color {
+            Some(_) => images.albedo.and(images.normal),
+            None => images.normal,
+        };
+        let images = [images.color, images.albedo, normal, Some(images.output)]
+            .map(|image| image.as_ref().map(RemoteImage::desc));
+        self.request(
+            &Request::Execute {
+                ty,
+                config: *config,
+                images,
+            },
+            None,
+        )
+    }
+
+    fn import(
+        &mut self,
+        fd: BorrowedFd<'_>,
+        transport: Transport,
+        size: u64,
+    ) -> Result<u32, RemoteError> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.request(
+            &Request::Import {
+                id,
+                transport,
+                size,
+            },
+            Some(fd),
+        )?;
+        Ok(id)
+    }
+
+    fn request(
+        &mut self,
+        request: &Request,
+        fd: Option<BorrowedFd<'_>>,
+    ) -> Result<(), RemoteError> {
+        send_frame(&self.stream, &request.encode(), fd).map_err(RemoteError::Io)?;
+        let (reply, _) = recv_frame(&self.stream)
+            .map_err(RemoteError::Io)?
+            .ok_or_else(|| RemoteError::Io(io::ErrorKind::UnexpectedEof.into()))?;
+        match reply.split_first() {
+            Some((&REPLY_OK, [])) => Ok(()),
+            Some((&REPLY_ERROR, message)) => Err(RemoteError::Server(
+                String::from_utf8_lossy(message).into_owned(),
+            )),
+            _ => Err(RemoteError::Io(invalid_data("malformed reply"))),
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn memfd_round_trip() {
+    let (client, server) = UnixStream::pair().unwrap();
+    let server = std::thread::spawn(move || {
+        Server::new(oidn::Device::cpu())
+            .unwrap()
+            .serve(&server)
+            .unwrap();
+    });
+    let mut remote = RemoteDevice::from_stream(client);
+    let mut color = remote.allocate_staging_buffer(12).unwrap();
+    let output = remote.allocate_staging_buffer(12).unwrap();
+    let pixel = [0.5_f32; 3].map(f32::to_ne_bytes).concat();
+    color.write_staging(0, &pixel);
+    remote
+        .execute_filter(
+            FilterType::RayTracing,
+            &FilterConfig::default(),
+            &RemoteFilterImages::new(
+                RemoteImage::new(&color, Format::Float3, 1, 1).unwrap(),
+                RemoteImage::new(&output, Format::Float3, 1, 1).unwrap(),
+            ),
+        )
+        .unwrap();
+    let denoised = output.read_staging().unwrap();
+    assert_eq!(denoised.len(), 12);
+    for channel in denoised.chunks_exact(4) {
+        let channel = f32::from_ne_bytes(channel.try_into().unwrap());
+        assert!((channel - 0.5).abs() < 0.1, "denoised to {channel}");
+    }
+    // the RT filter only takes three channel colors
+    assert!(matches!(
+        remote.execute_filter(
+            FilterType::RayTracing,
+            &FilterConfig::default(),
+            &RemoteFilterImages::in_place(RemoteImage::new(&color, Format::Float, 1, 1).unwrap()),
+        ),
+        Err(RemoteError::Server(_))
+    ));
+    remote.release(color).unwrap();
+    drop(remote);
+    server.join().unwrap();
+}
@@ -0,0 +1,36 @@
+// Writes a tightly packed float3 buffer into a storage texture, the inverse of
+// `texture_to_float3.wgsl`. `STORAGE_FORMAT` is replaced before the module is
+// created.
+
+const ENCODE_NONE: u32 = 0u;
+const ENCODE_SRGB: u32 = 1u;
+
+struct Params {
+    encode: u32,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var<storage, read> input: array<f32>;
+@group(0) @binding(2) var output: texture_storage_2d<STORAGE_FORMAT, write>;
+
+fn linear_to_srgb(c: vec3<f32>) -> vec3<f32> {
+    return select(1.055 * pow(c, vec3(1.0 / 2.4)) - 0.055, c * 12.92, c <= vec3(0.0031308));
+}
+
+@compute @workgroup_size(64)
+fn main(
+    @builtin(global_invocation_id) id: vec3<u32>,
+    @builtin(num_workgroups) groups: vec3<u32>,
+) {
+    // large images are dispatched in rows of workgroups
+    let index = id.x + id.y * groups.x * 64u;
+    let dims = textureDimensions(output);
+    if index >= dims.x * dims.y {
+        return;
+    }
+    var color = vec3(input[index * 3u], input[index * 3u + 1u], input[index * 3u + 2u]);
+    if params.encode == ENCODE_SRGB {
+        color = linear_to_srgb(max(color, vec3(0.0)));
+    }
+    textureStore(output, vec2(index % dims.x, index / dims.x), vec4(color, 1.0));
+}
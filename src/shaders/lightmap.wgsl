@@ -0,0 +1,94 @@
+// Passes keeping the empty texels around lightmap charts from bleeding into
+// the charts while denoising.
+//
+// Before denoising the charts are dilated into the surrounding texels, so the
+// filter sees plausible values instead of black, and afterwards the texels the
+// dilation didn't reach are cleared again. The dilated texels are kept, so
+// bilinear filtering at chart edges doesn't pick up black.
+
+const UNCOVERED: u32 = 0xffffffffu;
+
+struct Params {
+    // the dilation pass, starting at one
+    pass_index: u32,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+// texels with a value above one half are inside a chart
+@group(0) @binding(1) var mask: texture_2d<f32>;
+@group(0) @binding(2) var<storage, read_write> data: array<f32>;
+// the pass a texel was covered in, zero for chart texels
+@group(0) @binding(3) var<storage, read_write> coverage: array<atomic<u32>>;
+
+fn index(id: vec3<u32>, groups: vec3<u32>) -> u32 {
+    // large images are dispatched in rows of workgroups
+    return id.x + id.y * groups.x * 64u;
+}
+
+fn inside(coords: vec2<u32>) -> bool {
+    return textureLoad(mask, coords, 0).r > 0.5;
+}
+
+@compute @workgroup_size(64)
+fn init_coverage(
+    @builtin(global_invocation_id) id: vec3<u32>,
+    @builtin(num_workgroups) groups: vec3<u32>,
+) {
+    let i = index(id, groups);
+    let dims = textureDimensions(mask);
+    if i >= dims.x * dims.y {
+        return;
+    }
+    atomicStore(&coverage[i], select(UNCOVERED, 0u, inside(vec2(i % dims.x, i / dims.x))));
+}
+
+@compute @workgroup_size(64)
+fn dilate(
+    @builtin(global_invocation_id) id: vec3<u32>,
+    @builtin(num_workgroups) groups: vec3<u32>,
+) {
+    let i = index(id, groups);
+    let dims = textureDimensions(mask);
+    if i >= dims.x * dims.y || atomicLoad(&coverage[i]) != UNCOVERED {
+        return;
+    }
+    let coords = vec2<i32>(vec2(i % dims.x, i / dims.x));
+    var sum = vec3(0.0);
+    var count = 0.0;
+    for (var y = -1; y <= 1; y++) {
+        for (var x = -1; x <= 1; x++) {
+            let neighbour = coords + vec2(x, y);
+            if any(neighbour < vec2(0)) || any(neighbour >= vec2<i32>(dims)) {
+                continue;
+            }
+            let n = u32(neighbour.x) + u32(neighbour.y) * dims.x;
+            // only texels covered by earlier passes, those are not written to in this pass
+            if atomicLoad(&coverage[n]) < params.pass_index {
+                sum += vec3(data[n * 3u], data[n * 3u + 1u], data[n * 3u + 2u]);
+                count += 1.0;
+            }
+        }
+    }
+    if count > 0.0 {
+        let average = sum / count;
+        data[i * 3u] = average.r;
+        data[i * 3u + 1u] = average.g;
+        data[i * 3u + 2u] = average.b;
+        atomicStore(&coverage[i], params.pass_index);
+    }
+}
+
+@compute @workgroup_size(64)
+fn clear_outside(
+    @builtin(global_invocation_id) id: vec3<u32>,
+    @builtin(num_workgroups) groups: vec3<u32>,
+) {
+    let i = index(id, groups);
+    let dims = textureDimensions(mask);
+    if i >= dims.x * dims.y || atomicLoad(&coverage[i]) != UNCOVERED {
+        return;
+    }
+    data[i * 3u] = 0.0;
+    data[i * 3u + 1u] = 0.0;
+    data[i * 3u + 2u] = 0.0;
+}
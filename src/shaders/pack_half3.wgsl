@@ -0,0 +1,37 @@
+// Packs an `Rgba16Float` texture into a tightly packed half3 buffer.
+//
+// A half3 pixel is 6 bytes, so each invocation packs two pixels into three
+// words to avoid two invocations writing to the same word.
+
+@group(0) @binding(0) var input: texture_2d<f32>;
+@group(0) @binding(1) var<storage, read_write> output: array<u32>;
+
+fn load(index: u32) -> vec3<f32> {
+    let width = textureDimensions(input).x;
+    return textureLoad(input, vec2(index % width, index / width), 0).rgb;
+}
+
+@compute @workgroup_size(64)
+fn main(
+    @builtin(global_invocation_id) id: vec3<u32>,
+    @builtin(num_workgroups) groups: vec3<u32>,
+) {
+    // large images are dispatched in rows of workgroups
+    let pair = id.x + id.y * groups.x * 64u;
+    let dims = textureDimensions(input);
+    let count = dims.x * dims.y;
+    let first = pair * 2u;
+    if first >= count {
+        return;
+    }
+    let a = load(first);
+    if first + 1u < count {
+        let b = load(first + 1u);
+        output[pair * 3u] = pack2x16float(a.xy);
+        output[pair * 3u + 1u] = pack2x16float(vec2(a.z, b.x));
+        output[pair * 3u + 2u] = pack2x16float(b.yz);
+    } else {
+        output[pair * 3u] = pack2x16float(a.xy);
+        output[pair * 3u + 1u] = pack2x16float(vec2(a.z, 0.0));
+    }
+}
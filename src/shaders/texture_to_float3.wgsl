@@ -0,0 +1,55 @@
+// Converts any float texture into a tightly packed float3 buffer, optionally
+// decoding the channels on the way.
+
+const DECODE_NONE: u32 = 0u;
+const DECODE_SRGB: u32 = 1u;
+const DECODE_OCTAHEDRAL: u32 = 2u;
+
+struct Params {
+    decode: u32,
+    // whether the texture stores values in [-1, 1] rather than [0, 1]
+    signed_input: u32,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var input: texture_2d<f32>;
+@group(0) @binding(2) var<storage, read_write> output: array<f32>;
+
+fn srgb_to_linear(c: vec3<f32>) -> vec3<f32> {
+    return select(pow((c + 0.055) / 1.055, vec3(2.4)), c / 12.92, c <= vec3(0.04045));
+}
+
+fn decode_octahedral(e: vec2<f32>) -> vec3<f32> {
+    var n = vec3(e, 1.0 - abs(e.x) - abs(e.y));
+    let t = max(-n.z, 0.0);
+    n.x += select(t, -t, n.x >= 0.0);
+    n.y += select(t, -t, n.y >= 0.0);
+    return normalize(n);
+}
+
+@compute @workgroup_size(64)
+fn main(
+    @builtin(global_invocation_id) id: vec3<u32>,
+    @builtin(num_workgroups) groups: vec3<u32>,
+) {
+    // large images are dispatched in rows of workgroups
+    let index = id.x + id.y * groups.x * 64u;
+    let dims = textureDimensions(input);
+    if index >= dims.x * dims.y {
+        return;
+    }
+    let texel = textureLoad(input, vec2(index % dims.x, index / dims.x), 0);
+    var color = texel.rgb;
+    if params.decode == DECODE_SRGB {
+        color = srgb_to_linear(color);
+    } else if params.decode == DECODE_OCTAHEDRAL {
+        var encoded = texel.xy;
+        if params.signed_input == 0u {
+            encoded = encoded * 2.0 - 1.0;
+        }
+        color = decode_octahedral(encoded);
+    }
+    output[index * 3u] = color.r;
+    output[index * 3u + 1u] = color.g;
+    output[index * 3u + 2u] = color.b;
+}
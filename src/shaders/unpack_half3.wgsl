@@ -0,0 +1,32 @@
+// Unpacks a tightly packed half3 buffer into an `Rgba16Float` storage texture,
+// the inverse of `pack_half3.wgsl`.
+
+@group(0) @binding(0) var<storage, read> input: array<u32>;
+@group(0) @binding(1) var output: texture_storage_2d<rgba16float, write>;
+
+fn store(index: u32, color: vec3<f32>) {
+    let width = textureDimensions(output).x;
+    textureStore(output, vec2(index % width, index / width), vec4(color, 1.0));
+}
+
+@compute @workgroup_size(64)
+fn main(
+    @builtin(global_invocation_id) id: vec3<u32>,
+    @builtin(num_workgroups) groups: vec3<u32>,
+) {
+    // large images are dispatched in rows of workgroups
+    let pair = id.x + id.y * groups.x * 64u;
+    let dims = textureDimensions(output);
+    let count = dims.x * dims.y;
+    let first = pair * 2u;
+    if first >= count {
+        return;
+    }
+    let ab = unpack2x16float(input[pair * 3u]);
+    let cd = unpack2x16float(input[pair * 3u + 1u]);
+    store(first, vec3(ab, cd.x));
+    if first + 1u < count {
+        let ef = unpack2x16float(input[pair * 3u + 2u]);
+        store(first + 1u, vec3(cd.y, ef));
+    }
+}
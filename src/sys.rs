@@ -0,0 +1,244 @@
+//! The OIDN functions the crate calls.
+//!
+//! Without the `dynamic-oidn` feature these are the `oidn` crate's linked bindings. With it they
+//! are resolved from the library loaded by [`crate::load_oidn`], and until then return null
+//! handles, zeroes or `false`, with devices reporting the missing library as their error.
+//!
+//! The `oidn` crate's own API (dropping its devices and buffers, `get_error`, ...) always
+//! calls the linked functions, so the crate only goes through it where callers ask for it.
+
+#[cfg(feature = "dynamic-oidn")]
+use libloading::Library;
+pub(crate) use oidn::sys::*;
+use std::ffi::CStr;
+#[cfg(feature = "dynamic-oidn")]
+use std::ffi::{c_char, c_int, c_void};
+use std::mem::ManuallyDrop;
+#[cfg(feature = "dynamic-oidn")]
+use std::sync::OnceLock;
+
+/// Declares the OIDN functions the crate calls, which with the `dynamic-oidn` feature are
+/// resolved from the library given to [`set_library`] instead of linked.
+///
+/// While no library is set the functions return zeroes (null handles, `false` and 0), or the
+/// value of their `#[missing(...)]` expression.
+macro_rules! functions {
+    ($($(#[missing($missing:expr)])? fn $name:ident($($arg:ident: $ty:ty),*) $(-> $ret:ty)?;)*) => {
+        #[cfg(feature = "dynamic-oidn")]
+        #[allow(non_snake_case)]
+        struct Functions {
+            $($name: Option<unsafe extern "C" fn($($ty),*) $(-> $ret)?>,)*
+            // the functions are only valid while the library is loaded
+            _library: Library,
+        }
+
+        #[cfg(feature = "dynamic-oidn")]
+        impl Functions {
+            /// # Safety
+            /// `library` must be OIDN 2.x, whose declarations the function types match.
+            unsafe fn resolve(library: Library) -> Self {
+                Self {
+                    $($name: unsafe { library.get(concat!(stringify!($name), "\0").as_bytes()) }
+                        .ok()
+                        .map(|symbol| *symbol),)*
+                    _library: library,
+                }
+            }
+        }
+
+        $(
+            // not every backend calls every function
+            #[cfg(feature = "dynamic-oidn")]
+            #[allow(non_snake_case, dead_code, clippy::too_many_arguments)]
+            pub(crate) unsafe fn $name($($arg: $ty),*) $(-> $ret)? {
+                match FUNCTIONS.get().and_then(|functions| functions.$name) {
+                    Some(function) => unsafe { function($($arg),*) },
+                    None => missing!($($missing)?),
+                }
+            }
+        )*
+    };
+}
+
+/// The result of a function while no library is set.
+#[cfg(feature = "dynamic-oidn")]
+macro_rules! missing {
+    // # SAFETY: all of OIDN's return types are integers, pointers or bools.
+    () => {
+        unsafe { ::std::mem::zeroed() }
+    };
+    ($missing:expr) => {
+        unsafe { $missing }
+    };
+}
+
+functions! {
+    fn oidnGetNumPhysicalDevices() -> c_int;
+    fn oidnGetPhysicalDeviceBool(physical_device: c_int, name: *const c_char) -> bool;
+    fn oidnGetPhysicalDeviceInt(physical_device: c_int, name: *const c_char) -> c_int;
+    fn oidnGetPhysicalDeviceData(
+        physical_device: c_int,
+        name: *const c_char,
+        byte_size: *mut usize
+    ) -> *const c_void;
+    fn oidnNewDevice(ty: OIDNDeviceType) -> OIDNDevice;
+    fn oidnNewDeviceByID(physical_device: c_int) -> OIDNDevice;
+    fn oidnNewDeviceByUUID(uuid: *const c_void) -> OIDNDevice;
+    fn oidnNewDeviceByLUID(luid: *const c_void) -> OIDNDevice;
+    fn oidnReleaseDevice(device: OIDNDevice);
+    fn oidnSetDeviceBool(device: OIDNDevice, name: *const c_char, value: bool);
+    fn oidnSetDeviceInt(device: OIDNDevice, name: *const c_char, value: c_int);
+    fn oidnGetDeviceInt(device: OIDNDevice, name: *const c_char) -> c_int;
+    fn oidnSetDeviceErrorFunction(
+        device: OIDNDevice,
+        func: OIDNErrorFunction,
+        user_ptr: *mut c_void
+    );
+    #[missing(missing_library_error(out_message))]
+    fn oidnGetDeviceError(device: OIDNDevice, out_message: *mut *const c_char) -> OIDNError;
+    fn oidnCommitDevice(device: OIDNDevice);
+    fn oidnNewBuffer(device: OIDNDevice, byte_size: usize) -> OIDNBuffer;
+    fn oidnNewSharedBuffer(
+        device: OIDNDevice,
+        dev_ptr: *mut c_void,
+        byte_size: usize
+    ) -> OIDNBuffer;
+    fn oidnNewSharedBufferFromFD(
+        device: OIDNDevice,
+        fd_type: OIDNExternalMemoryTypeFlag,
+        fd: c_int,
+        byte_size: usize
+    ) -> OIDNBuffer;
+    fn oidnNewSharedBufferFromWin32Handle(
+        device: OIDNDevice,
+        handle_type: OIDNExternalMemoryTypeFlag,
+        handle: *mut c_void,
+        name: *const c_void,
+        byte_size: usize
+    ) -> OIDNBuffer;
+    fn oidnReadBuffer(
+        buffer: OIDNBuffer,
+        byte_offset: usize,
+        byte_size: usize,
+        dst_host_ptr: *mut c_void
+    );
+    fn oidnWriteBuffer(
+        buffer: OIDNBuffer,
+        byte_offset: usize,
+        byte_size: usize,
+        src_host_ptr: *const c_void
+    );
+    fn oidnReleaseBuffer(buffer: OIDNBuffer);
+    fn oidnNewFilter(device: OIDNDevice, ty: *const c_char) -> OIDNFilter;
+    fn oidnReleaseFilter(filter: OIDNFilter);
+    fn oidnSetFilterImage(
+        filter: OIDNFilter,
+        name: *const c_char,
+        buffer: OIDNBuffer,
+        format: OIDNFormat,
+        width: usize,
+        height: usize,
+        byte_offset: usize,
+        pixel_byte_stride: usize,
+        row_byte_stride: usize
+    );
+    fn oidnSetFilterBool(filter: OIDNFilter, name: *const c_char, value: bool);
+    fn oidnSetFilterInt(filter: OIDNFilter, name: *const c_char, value: c_int);
+    fn oidnSetFilterFloat(filter: OIDNFilter, name: *const c_char, value: f32);
+    fn oidnSetFilterProgressMonitorFunction(
+        filter: OIDNFilter,
+        func: OIDNProgressMonitorFunction,
+        user_ptr: *mut c_void
+    );
+    fn oidnCommitFilter(filter: OIDNFilter);
+    fn oidnExecuteFilter(filter: OIDNFilter);
+}
+
+#[cfg(feature = "dynamic-oidn")]
+static FUNCTIONS: OnceLock<Functions> = OnceLock::new();
+
+/// Resolves the functions from `library` from now on, unless a library was already set.
+///
+/// # Safety
+/// `library` must be OIDN 2.x.
+#[cfg(feature = "dynamic-oidn")]
+pub(crate) unsafe fn set_library(library: Library) {
+    // another thread may have set one first
+    let _ = FUNCTIONS.set(unsafe { Functions::resolve(library) });
+}
+
+/// Whether a library was set with [`set_library`].
+#[cfg(feature = "dynamic-oidn")]
+pub(crate) fn is_loaded() -> bool {
+    FUNCTIONS.get().is_some()
+}
+
+/// Reports the missing library as the error of every device.
+#[cfg(feature = "dynamic-oidn")]
+unsafe fn missing_library_error(out_message: *mut *const c_char) -> OIDNError {
+    if !out_message.is_null() {
+        unsafe {
+            *out_message = c"The OpenImageDenoise library is not loaded".as_ptr();
+        }
+    }
+    OIDNError_OIDN_ERROR_UNKNOWN
+}
+
+/// The error of `device`, like [`oidn::Device::get_error`].
+pub(crate) fn device_error(device: &oidn::Device) -> Result<(), (oidn::Error, String)> {
+    let mut message = std::ptr::null();
+    // # SAFETY: `device` is valid, OIDN points `message` at a C string if there is an error.
+    let code = unsafe { oidnGetDeviceError(device.raw(), &mut message) };
+    if code == OIDNError_OIDN_ERROR_NONE {
+        return Ok(());
+    }
+    let message = match message.is_null() {
+        true => String::new(),
+        false => unsafe { CStr::from_ptr(message) }
+            .to_string_lossy()
+            .into_owned(),
+    };
+    Err((
+        oidn::Error::try_from(code).unwrap_or(oidn::Error::Unknown),
+        message,
+    ))
+}
+
+/// An [`oidn::Device`] released through these bindings.
+///
+/// The few bytes `oidn::Device` allocates for itself are leaked, as dropping it would call the
+/// linked `oidnReleaseDevice`.
+pub(crate) struct OidnDevice(ManuallyDrop<oidn::Device>);
+
+// # SAFETY: OIDN's API may be called from any thread, the calls that must not run concurrently
+// are serialized by the owner (`Device::lock_oidn`, or the server serving one client at a time).
+unsafe impl Sync for OidnDevice {}
+
+impl OidnDevice {
+    /// Takes ownership of `device`, which is released on drop.
+    pub(crate) fn new(device: oidn::Device) -> Self {
+        Self(ManuallyDrop::new(device))
+    }
+
+    /// Takes ownership of the committed `device`.
+    ///
+    /// # Safety
+    /// `device` must be a valid committed device.
+    pub(crate) unsafe fn from_raw(device: OIDNDevice) -> Self {
+        Self::new(unsafe { oidn::Device::from_raw(device) })
+    }
+}
+
+impl std::ops::Deref for OidnDevice {
+    type Target = oidn::Device;
+
+    fn deref(&self) -> &oidn::Device {
+        &self.0
+    }
+}
+
+impl Drop for OidnDevice {
+    fn drop(&mut self) {
+        unsafe { oidnReleaseDevice(self.0.raw()) }
+    }
+}
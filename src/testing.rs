@@ -0,0 +1,214 @@
+//! A fake interop backend for testing code using this crate without GPU interop.
+//!
+//! [`Device::new_testing`] works with any adapter, including wgpu's noop adapter from
+//! [`noop_adapter`], and denoises on an OIDN CPU device. Its shared buffers are a separate
+//! wgpu buffer and OIDN buffer that [`Device::execute_filter`] keeps in sync through host
+//! copies. Code running OIDN filters directly has to call [`Device::sync_to_oidn`] and
+//! [`Device::sync_to_wgpu`] itself, both do nothing on backends sharing memory.
+//!
+//! The noop adapter does not run shaders, so [`crate::Converter`] and the other compute based
+//! helpers only work with the fake backend on a real adapter.
+
+use crate::backend::WgpuDevice;
+use crate::builder::DeviceOptions;
+use crate::{
+    Device, DeviceCreateError, InteropBackend, OidnDeviceType, SharedBuffer,
+    SharedBufferCreateError, SharedImage, SyncError,
+};
+
+/// Returns wgpu's noop adapter, which executes buffer copies on the CPU and ignores shaders.
+pub fn noop_adapter() -> wgpu::Adapter {
+    let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor {
+        backends: wgpu::Backends::NOOP,
+        backend_options: wgpu::BackendOptions {
+            noop: wgpu::NoopBackendOptions { enable: true },
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    futures_lite::future::block_on(instance.request_adapter(&Default::default()))
+        .expect("the noop backend always has an adapter")
+}
+
+/// Reads back the wgpu side of `buffer`, waiting for all submitted work to finish.
+pub fn read_buffer(device: &Device, buffer: &SharedBuffer) -> Result<Vec<u8>, SyncError> {
+    crate::backend::read_buffer(device, buffer)
+}
+
+/// Sets every pixel of the wgpu side of `image` to `pixel`, which holds one value per channel.
+///
+/// # Panics
+///
+/// Panics if `image` has a half precision format or `pixel` doesn't match its channels.
+pub fn fill_image(queue: &wgpu::Queue, image: &SharedImage, pixel: &[f32]) {
+    assert_eq!(
+        image.format().bytes_per_channel(),
+        size_of::<f32>() as u64,
+        "only single precision images can be filled, not {:?}",
+        image.format()
+    );
+    assert_eq!(pixel.len() as u64, image.format().channels());
+    let pixel = pixel
+        .iter()
+        .flat_map(|channel| channel.to_ne_bytes())
+        .collect::<Vec<u8>>();
+    let mut contents = vec![0u8; image.buffer().wgpu_buffer().size() as usize];
+    for y in 0..image.height() as u64 {
+        for x in 0..image.width() as u64 {
+            let offset = (y * image.row_byte_stride() + x * image.pixel_byte_stride()) as usize;
+            contents[offset..offset + pixel.len()].copy_from_slice(&pixel);
+        }
+    }
+    queue.write_buffer(image.buffer().wgpu_buffer(), 0, &contents);
+    queue.submit([]);
+}
+
+/// Asserts that every channel of the wgpu side of `image` is within `tolerance` of `expected`,
+/// which holds the channels of each pixel tightly packed in row order.
+///
+/// # Panics
+///
+/// Also panics if `image` has a half precision format or reading it back fails.
+#[track_caller]
+pub fn assert_image_approx_eq(
+    device: &Device,
+    image: &SharedImage,
+    expected: &[f32],
+    tolerance: f32,
+) {
+    let channels = image.format().channels();
+    assert_eq!(
+        image.format().bytes_per_channel(),
+        size_of::<f32>() as u64,
+        "only single precision images can be compared, not {:?}",
+        image.format()
+    );
+    assert_eq!(
+        expected.len() as u64,
+        image.width() as u64 * image.height() as u64 * channels,
+        "expected values do not match the image size"
+    );
+    let contents = read_buffer(device, image.buffer()).expect("reading the image back failed");
+    let mut expected = expected.iter();
+    for y in 0..image.height() as u64 {
+        for x in 0..image.width() as u64 {
+            let pixel = (y * image.row_byte_stride() + x * image.pixel_byte_stride()) as usize;
+            for channel in 0..channels as usize {
+                let offset = pixel + channel * size_of::<f32>();
+                let actual = f32::from_ne_bytes(
+                    contents[offset..offset + size_of::<f32>()]
+                        .try_into()
+                        .unwrap(),
+                );
+                let expected = *expected.next().unwrap();
+                assert!(
+                    (actual - expected).abs() <= tolerance,
+                    "channel {channel} of pixel ({x}, {y}) is {actual}, expected {expected}"
+                );
+            }
+        }
+    }
+}
+
+/// Keeps separate wgpu and OIDN CPU buffers in sync through host copies.
+#[derive(Debug)]
+struct TestingBackend;
+
+impl InteropBackend for TestingBackend {
+    type Memory = ();
+
+    fn probe(&mut self, _adapter: &wgpu::Adapter) -> bool {
+        true
+    }
+
+    fn create_oidn_device(&self, _device_type: Option<OidnDeviceType>) -> oidn::sys::OIDNDevice {
+        unsafe { crate::sys::oidnNewDevice(oidn::sys::OIDNDeviceType_OIDN_DEVICE_TYPE_CPU) }
+    }
+
+    fn select_memory_type(&mut self, _supported: oidn::sys::OIDNExternalMemoryTypeFlag) -> bool {
+        true
+    }
+
+    fn allocate(
+        &self,
+        _device: &wgpu::Device,
+        _size: wgpu::BufferAddress,
+    ) -> Result<(), SharedBufferCreateError> {
+        Ok(())
+    }
+
+    unsafe fn import_into_oidn(
+        &self,
+        device: &oidn::Device,
+        _memory: &(),
+        size: wgpu::BufferAddress,
+    ) -> Result<oidn::sys::OIDNBuffer, SharedBufferCreateError> {
+        unsafe { crate::backend::new_staging_oidn_buffer(device, size) }
+    }
+
+    unsafe fn wrap_into_wgpu(
+        &self,
+        device: &wgpu::Device,
+        _queue: &wgpu::Queue,
+        _memory: &mut (),
+        size: wgpu::BufferAddress,
+    ) -> wgpu::Buffer {
+        crate::backend::new_staging_wgpu_buffer(device, size)
+    }
+
+    fn shares_memory(&self) -> bool {
+        false
+    }
+}
+
+impl Device {
+    /// Creates a device using the fake testing backend on any adapter.
+    pub async fn new_testing(
+        adapter: &wgpu::Adapter,
+        desc: &wgpu::DeviceDescriptor<'_>,
+    ) -> Result<(Self, wgpu::Queue), DeviceCreateError> {
+        Self::from_backend(
+            adapter,
+            Box::new(TestingBackend),
+            &DeviceOptions::default(),
+            WgpuDevice::Request(desc),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+#[async_std::test]
+async fn round_trip() {
+    let adapter = noop_adapter();
+    let (device, queue) = Device::new_testing(&adapter, &Default::default())
+        .await
+        .unwrap();
+    let image = device
+        .allocate_shared_image(crate::Format::Float, 2, 1)
+        .unwrap();
+    queue.write_buffer(
+        image.buffer().wgpu_buffer(),
+        0,
+        &[1.0f32.to_ne_bytes(), 2.0f32.to_ne_bytes()].concat(),
+    );
+    device.sync_to_oidn(image.buffer()).unwrap();
+    let mut contents = [0.0f32; 2];
+    unsafe {
+        crate::sys::oidnReadBuffer(
+            image.buffer().oidn_buffer,
+            0,
+            size_of_val(&contents),
+            contents.as_mut_ptr() as *mut _,
+        );
+        crate::sys::oidnWriteBuffer(
+            image.buffer().oidn_buffer,
+            0,
+            size_of::<f32>(),
+            3.0f32.to_ne_bytes().as_ptr() as *const _,
+        );
+    }
+    assert_eq!(contents, [1.0, 2.0]);
+    device.sync_to_wgpu(image.buffer()).unwrap();
+    assert_image_approx_eq(&device, &image, &[3.0, 2.0], 0.0);
+}
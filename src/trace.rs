@@ -0,0 +1,69 @@
+//! Structured diagnostics, emitted through `tracing` when the `tracing`
+//! feature is enabled and discarded otherwise.
+
+#[cfg(dx12)]
+pub(crate) fn windows_error(context: &str, err: &windows::core::Error) {
+    #[cfg(feature = "tracing")]
+    tracing::error!(code = %err.code(), message = %err.message(), "{context}");
+    #[cfg(not(feature = "tracing"))]
+    let _ = (context, err);
+}
+
+pub(crate) fn oidn_error(context: &str, err: &(oidn::Error, String)) {
+    #[cfg(feature = "tracing")]
+    tracing::error!(code = ?err.0, message = %err.1, "{context}");
+    #[cfg(not(feature = "tracing"))]
+    let _ = (context, err);
+}
+
+pub(crate) fn device_lost(reason: wgpu::DeviceLostReason, message: &str) {
+    #[cfg(feature = "tracing")]
+    tracing::warn!(?reason, message, "wgpu device lost");
+    #[cfg(not(feature = "tracing"))]
+    let _ = (reason, message);
+}
+
+#[cfg(feature = "tracing")]
+unsafe extern "C" fn oidn_error_callback(
+    _user_ptr: *mut std::ffi::c_void,
+    code: oidn::sys::OIDNError,
+    message: *const std::ffi::c_char,
+) {
+    let message = if message.is_null() {
+        Default::default()
+    } else {
+        // # SAFETY: OIDN passes a nul terminated string that lives for the duration of the call.
+        unsafe { std::ffi::CStr::from_ptr(message) }.to_string_lossy()
+    };
+    match oidn::Error::try_from(code).unwrap_or(oidn::Error::Unknown) {
+        // cancelling through a `CancellationToken` is not a failure
+        oidn::Error::Canceled => tracing::debug!(%message, "OIDN execution cancelled"),
+        code => tracing::error!(?code, %message, "OIDN error"),
+    }
+}
+
+/// Routes the errors of `device` into `tracing` as they happen, they can
+/// still be retrieved with `oidnGetDeviceError` afterwards.
+///
+/// # Safety
+/// `device` must be a valid OIDN device.
+pub(crate) unsafe fn install_oidn_error_callback(device: oidn::sys::OIDNDevice) {
+    #[cfg(feature = "tracing")]
+    unsafe {
+        crate::sys::oidnSetDeviceErrorFunction(
+            device,
+            Some(oidn_error_callback),
+            std::ptr::null_mut(),
+        );
+    }
+    #[cfg(not(feature = "tracing"))]
+    let _ = device;
+}
+
+#[cfg(feature = "dynamic-oidn")]
+pub(crate) fn library_load_failed(name: &str, err: &libloading::Error) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(library = name, %err, "Loading OIDN failed");
+    #[cfg(not(feature = "tracing"))]
+    let _ = (name, err);
+}
@@ -1,22 +1,70 @@
+use crate::{
+    ExportedMemory, ExternalMemory, ExternalMemoryAllocator, ExternalMemoryBlock,
+    ExternalMemoryRequest, HandleType, InteropBackend, OidnDeviceType, RawMemoryHandle,
+    SharingMode,
+};
 use ash::{ext, khr, vk};
 use oidn::sys::{
-    OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_DMA_BUF,
+    OIDNExternalMemoryTypeFlag, OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_DMA_BUF,
     OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_FD,
     OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_WIN32,
 };
 
-use std::ptr;
+use std::alloc::Layout;
+use std::ffi::CStr;
+#[cfg(unix)]
+use std::os::fd::{FromRawFd, IntoRawFd};
+#[cfg(windows)]
+use std::os::windows::io::{AsRawHandle, FromRawHandle};
+use std::ptr::NonNull;
+use std::sync::Arc;
 use wgpu::hal::api::Vulkan;
 use wgpu::hal::{CommandEncoder, vulkan};
 use wgpu::util::align_to;
-use wgpu::{BufferDescriptor, BufferUsages, DeviceDescriptor};
+use wgpu::{BufferDescriptor, BufferUsages};
 
 // We can't rely on the windows crate existing here and this may also be either a u32 or u64.
 const ACCESS_GENERIC_ALL: vk::DWORD = 268435456;
 
 pub(crate) struct VulkanAllocation {
     memory: vk::DeviceMemory,
+    /// Moved into the wgpu buffer once it is wrapped.
+    buffer: Option<vk::Buffer>,
+    mode: VulkanSharingMode,
     wgpu_device: wgpu::Device,
+    /// The block `memory` was allocated as, unless it is imported host memory.
+    block: Option<(Arc<dyn ExternalMemoryAllocator>, ExternalMemoryBlock)>,
+    /// Freed after `memory`, which it backs when sharing host memory.
+    host_memory: Option<HostMemory>,
+    /// Whether `memory` was adopted and is freed by its owner.
+    borrowed: bool,
+    allocation_size: u64,
+    /// `None` if the memory was adopted.
+    memory_type_index: Option<u32>,
+}
+
+/// Zeroed host memory aligned for importing into Vulkan.
+struct HostMemory {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+// # SAFETY: the memory is only accessed through Vulkan and OIDN, which synchronise access.
+unsafe impl Send for HostMemory {}
+unsafe impl Sync for HostMemory {}
+
+impl HostMemory {
+    fn new(layout: Layout) -> Option<Self> {
+        // # SAFETY: the layout is never zero sized, as shared buffers can't be empty.
+        let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+        NonNull::new(ptr).map(|ptr| Self { ptr, layout })
+    }
+}
+
+impl Drop for HostMemory {
+    fn drop(&mut self) {
+        unsafe { std::alloc::dealloc(self.ptr.as_ptr(), self.layout) }
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -24,6 +72,154 @@ pub(crate) enum VulkanSharingMode {
     Win32,
     Fd,
     Dma,
+    Host,
+}
+
+/// What a Vulkan adapter supports for sharing memory with OIDN.
+#[derive(Debug)]
+pub(crate) struct VulkanAdapterInfo {
+    pub(crate) win_32_handle_supported: bool,
+    pub(crate) fd_supported: bool,
+    pub(crate) dma_buf_supported: bool,
+    pub(crate) host_memory_supported: bool,
+    /// Host pointers imported into Vulkan must be aligned to this.
+    min_imported_host_pointer_alignment: u64,
+    pub(crate) device_uuid: [u8; vk::UUID_SIZE],
+    pub(crate) device_local_memory: u64,
+}
+
+impl VulkanAdapterInfo {
+    /// Returns `None` if `adapter` is not a Vulkan 1.1 adapter.
+    pub(crate) fn new(adapter: &wgpu::Adapter) -> Option<Self> {
+        // # SAFETY: the raw handle is not manually destroyed.
+        unsafe {
+            adapter.as_hal::<Vulkan, _, _>(|adapter| {
+                let adapter = adapter?;
+                let capabilities = adapter.physical_device_capabilities();
+                let instance = adapter.shared_instance().raw_instance();
+                let physical_device = adapter.raw_physical_device();
+                // `get_physical_device_properties2` requires version >= 1.1
+                if instance
+                    .get_physical_device_properties(physical_device)
+                    .api_version
+                    < vk::API_VERSION_1_1
+                {
+                    return None;
+                }
+                let host_memory_supported =
+                    capabilities.supports_extension(ext::external_memory_host::NAME);
+                let mut id_properties = vk::PhysicalDeviceIDProperties::default();
+                let mut host_properties =
+                    vk::PhysicalDeviceExternalMemoryHostPropertiesEXT::default();
+                let mut properties =
+                    vk::PhysicalDeviceProperties2::default().push_next(&mut id_properties);
+                if host_memory_supported {
+                    properties = properties.push_next(&mut host_properties);
+                }
+                instance.get_physical_device_properties2(physical_device, &mut properties);
+                let device_local_memory = instance
+                    .get_physical_device_memory_properties(physical_device)
+                    .memory_heaps_as_slice()
+                    .iter()
+                    .filter(|heap| heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL))
+                    .map(|heap| heap.size)
+                    .sum();
+                let fd_supported = capabilities.supports_extension(khr::external_memory_fd::NAME);
+                Some(Self {
+                    win_32_handle_supported: capabilities
+                        .supports_extension(khr::external_memory_win32::NAME),
+                    fd_supported,
+                    dma_buf_supported: fd_supported
+                        && capabilities.supports_extension(ext::external_memory_dma_buf::NAME),
+                    host_memory_supported,
+                    min_imported_host_pointer_alignment: host_properties
+                        .min_imported_host_pointer_alignment,
+                    device_uuid: id_properties.device_uuid,
+                    device_local_memory,
+                })
+            })
+        }
+    }
+
+    /// The OIDN external memory types the adapter can export.
+    pub(crate) fn memory_types(&self) -> OIDNExternalMemoryTypeFlag {
+        let mut flags = 0;
+        if self.win_32_handle_supported {
+            flags |= OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_WIN32;
+        }
+        if self.fd_supported {
+            flags |= OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_FD;
+        }
+        if self.dma_buf_supported {
+            flags |= OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_DMA_BUF;
+        }
+        flags
+    }
+
+    /// Whether to share host memory with an OIDN CPU device, which is only done when OIDN has
+    /// no device for the adapter.
+    fn uses_host_memory(&self, sharing_modes: &[SharingMode], has_oidn_device: bool) -> bool {
+        self.host_memory_supported
+            && sharing_modes.contains(&SharingMode::HostMemory)
+            && !has_oidn_device
+    }
+
+    /// Picks the first of the preferred sharing modes both OIDN and the adapter support.
+    fn sharing_mode(
+        &self,
+        flag: OIDNExternalMemoryTypeFlag,
+        sharing_modes: &[SharingMode],
+    ) -> Option<VulkanSharingMode> {
+        let supported = flag & self.memory_types();
+        sharing_modes.iter().find_map(|mode| {
+            let (flag, mode) = match mode {
+                SharingMode::OpaqueWin32 => (
+                    OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_WIN32,
+                    VulkanSharingMode::Win32,
+                ),
+                SharingMode::OpaqueFd => (
+                    OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_FD,
+                    VulkanSharingMode::Fd,
+                ),
+                SharingMode::DmaBuf => (
+                    OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_DMA_BUF,
+                    VulkanSharingMode::Dma,
+                ),
+                // chosen when probing, as OIDN CPU devices don't report it
+                SharingMode::HostMemory => return None,
+            };
+            (supported & flag != 0).then_some(mode)
+        })
+    }
+}
+
+impl VulkanSharingMode {
+    fn from_sharing_mode(mode: SharingMode) -> Self {
+        match mode {
+            SharingMode::OpaqueWin32 => VulkanSharingMode::Win32,
+            SharingMode::OpaqueFd => VulkanSharingMode::Fd,
+            SharingMode::DmaBuf => VulkanSharingMode::Dma,
+            SharingMode::HostMemory => VulkanSharingMode::Host,
+        }
+    }
+
+    fn sharing_mode(self) -> SharingMode {
+        match self {
+            VulkanSharingMode::Win32 => SharingMode::OpaqueWin32,
+            VulkanSharingMode::Fd => SharingMode::OpaqueFd,
+            VulkanSharingMode::Dma => SharingMode::DmaBuf,
+            VulkanSharingMode::Host => SharingMode::HostMemory,
+        }
+    }
+
+    fn handle_type(self) -> vk::ExternalMemoryHandleTypeFlags {
+        match self {
+            VulkanSharingMode::Win32 => vk::ExternalMemoryHandleTypeFlags::OPAQUE_WIN32_KHR,
+            VulkanSharingMode::Fd => vk::ExternalMemoryHandleTypeFlags::OPAQUE_FD_KHR,
+            VulkanSharingMode::Dma => vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT,
+            VulkanSharingMode::Host => vk::ExternalMemoryHandleTypeFlags::HOST_ALLOCATION_EXT,
+        }
+    }
 }
 
 impl Drop for VulkanAllocation {
@@ -31,382 +227,724 @@ impl Drop for VulkanAllocation {
         unsafe {
             self.wgpu_device.as_hal::<Vulkan, _, _>(|device| {
                 let device = device.unwrap();
-                device.raw_device().free_memory(self.memory, None);
-            })
+                if let Some(buffer) = self.buffer {
+                    device.raw_device().destroy_buffer(buffer, None);
+                }
+                if self.block.is_none() && !self.borrowed {
+                    device.raw_device().free_memory(self.memory, None);
+                }
+            });
+            if let Some((allocator, block)) = self.block.take() {
+                allocator.free(&self.wgpu_device, block);
+            }
         }
     }
 }
 
-impl crate::Device {
-    pub(crate) async fn new_vulkan(
-        adapter: &wgpu::Adapter,
-        desc: &DeviceDescriptor<'_>,
-        trace_path: Option<&std::path::Path>,
-    ) -> Result<(Self, wgpu::Queue), crate::DeviceCreateError> {
-        let mut win_32_handle_supported = false;
-        let mut fd_supported = false;
-        let mut dma_buf_supported = false;
-        // # SAFETY: the raw handle is not manually destroyed.
-        let adapter_vulkan_desc = unsafe {
-            adapter.as_hal::<Vulkan, _, _>(|adapter| {
-                adapter
-                    .and_then(|adapter| {
-                        win_32_handle_supported = adapter
-                            .physical_device_capabilities()
-                            .supports_extension(khr::external_memory_win32::NAME);
-                        fd_supported = adapter
-                            .physical_device_capabilities()
-                            .supports_extension(khr::external_memory_fd::NAME);
-                        dma_buf_supported = adapter
-                            .physical_device_capabilities()
-                            .supports_extension(ext::external_memory_dma_buf::NAME)
-                            && adapter
-                                .physical_device_capabilities()
-                                .supports_extension(khr::external_memory_fd::NAME);
-
-                        let any_supported =
-                            win_32_handle_supported || dma_buf_supported || fd_supported;
-
-                        // `get_physical_device_properties2` requires version >= 1.1
-                        (any_supported
-                            && adapter
-                                .shared_instance()
-                                .raw_instance()
-                                .get_physical_device_properties(adapter.raw_physical_device())
-                                .api_version
-                                >= vk::API_VERSION_1_1)
-                            .then_some(adapter)
-                    })
-                    .map(|adapter| {
-                        let mut id_properties = vk::PhysicalDeviceIDProperties::default();
-                        adapter
-                            .shared_instance()
-                            .raw_instance()
-                            .get_physical_device_properties2(
-                                adapter.raw_physical_device(),
-                                &mut vk::PhysicalDeviceProperties2::default()
-                                    .push_next(&mut id_properties),
-                            );
-                        id_properties
-                    })
+/// Shares memory exported from Vulkan through opaque Win32 handles, opaque FDs or dma-bufs,
+/// or host memory imported into Vulkan for OIDN CPU devices.
+#[derive(Debug)]
+pub(crate) struct VulkanBackend {
+    sharing_modes: Vec<SharingMode>,
+    info: Option<VulkanAdapterInfo>,
+    /// Whether the adapter shares host memory with an OIDN CPU device.
+    host_memory: bool,
+    mode: Option<VulkanSharingMode>,
+    allocator: Arc<dyn ExternalMemoryAllocator>,
+}
+
+impl VulkanBackend {
+    pub(crate) fn new(
+        sharing_modes: &[SharingMode],
+        allocator: Arc<dyn ExternalMemoryAllocator>,
+    ) -> Self {
+        Self {
+            sharing_modes: sharing_modes.to_vec(),
+            info: None,
+            host_memory: false,
+            mode: None,
+            allocator,
+        }
+    }
+
+    fn info(&self) -> &VulkanAdapterInfo {
+        self.info.as_ref().expect("the adapter was probed")
+    }
+
+    fn mode(&self) -> VulkanSharingMode {
+        self.mode.expect("a memory type was selected")
+    }
+}
+
+impl InteropBackend for VulkanBackend {
+    type Memory = VulkanAllocation;
+
+    fn probe(&mut self, adapter: &wgpu::Adapter) -> bool {
+        let info = VulkanAdapterInfo::new(adapter);
+        self.host_memory = info.as_ref().is_some_and(|info| {
+            let has_oidn_device =
+                unsafe { crate::builder::matching_physical_devices(b"uuid\0", &info.device_uuid) }
+                    .next()
+                    .is_some();
+            info.uses_host_memory(&self.sharing_modes, has_oidn_device)
+        });
+        self.info = info.filter(|info| self.host_memory || info.memory_types() != 0);
+        self.info.is_some()
+    }
+
+    fn create_oidn_device(&self, device_type: Option<OidnDeviceType>) -> oidn::sys::OIDNDevice {
+        if self.host_memory {
+            return unsafe {
+                crate::sys::oidnNewDevice(oidn::sys::OIDNDeviceType_OIDN_DEVICE_TYPE_CPU)
+            };
+        }
+        let uuid = &self.info().device_uuid;
+        unsafe {
+            crate::builder::new_oidn_device(device_type, b"uuid\0", uuid, || {
+                crate::sys::oidnNewDeviceByUUID(uuid as *const _ as *const _)
             })
+        }
+    }
+
+    fn select_memory_type(&mut self, supported: OIDNExternalMemoryTypeFlag) -> bool {
+        self.mode = if self.host_memory {
+            Some(VulkanSharingMode::Host)
+        } else {
+            self.info().sharing_mode(supported, &self.sharing_modes)
         };
-        let Some(vk_desc) = adapter_vulkan_desc else {
-            return Err(crate::DeviceCreateError::MissingFeature);
-        };
-        let device = unsafe {
-            oidn::sys::oidnNewDeviceByUUID((&vk_desc.device_uuid) as *const _ as *const _)
-        };
-        Self::new_from_raw_oidn_adapter(device, adapter, desc, trace_path, |flag| {
-            let oidn_supports_win32 =
-                flag & OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_WIN32 != 0;
-            let oidn_supports_fd =
-                flag & OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_FD != 0;
-            let oidn_supports_dma =
-                flag & OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_DMA_BUF != 0;
-            if oidn_supports_win32 && win_32_handle_supported {
-                return Some(crate::BackendData::Vulkan(VulkanSharingMode::Win32));
-            }
-            if oidn_supports_fd && fd_supported {
-                return Some(crate::BackendData::Vulkan(VulkanSharingMode::Fd));
-            }
-            if oidn_supports_dma && dma_buf_supported {
-                return Some(crate::BackendData::Vulkan(VulkanSharingMode::Dma));
-            }
-            None
-        })
-        .await
+        self.mode.is_some()
     }
 
-    pub(crate) async fn from_vulkan_device(
+    fn request_device(
+        &self,
         adapter: &wgpu::Adapter,
-        wgpu_device: wgpu::Device,
-        queue: wgpu::Queue,
-        trace_path: Option<&std::path::Path>,
-    ) -> Result<(Self, wgpu::Queue), crate::DeviceCreateError> {
-        let mut win_32_handle_supported = false;
-        let mut fd_supported = false;
-        let mut dma_buf_supported = false;
-        let adapter_vulkan_desc = unsafe {
-            adapter.as_hal::<Vulkan, _, _>(|adapter| {
-                adapter
-                    .and_then(|adapter| {
-                        win_32_handle_supported = adapter
-                            .physical_device_capabilities()
-                            .supports_extension(khr::external_memory_win32::NAME);
-                        fd_supported = adapter
-                            .physical_device_capabilities()
-                            .supports_extension(khr::external_memory_fd::NAME);
-                        dma_buf_supported = adapter
-                            .physical_device_capabilities()
-                            .supports_extension(ext::external_memory_dma_buf::NAME)
-                            && adapter
-                                .physical_device_capabilities()
-                                .supports_extension(khr::external_memory_fd::NAME);
-
-                        let any_supported =
-                            win_32_handle_supported || dma_buf_supported || fd_supported;
-
-                        // `get_physical_device_properties2` requires version >= 1.1
-                        (any_supported
-                            && adapter
-                                .shared_instance()
-                                .raw_instance()
-                                .get_physical_device_properties(adapter.raw_physical_device())
-                                .api_version
-                                >= vk::API_VERSION_1_1)
-                            .then_some(adapter)
-                    })
-                    .map(|adapter| {
-                        let mut id_properties = vk::PhysicalDeviceIDProperties::default();
-                        adapter
-                            .shared_instance()
-                            .raw_instance()
-                            .get_physical_device_properties2(
-                                adapter.raw_physical_device(),
-                                &mut vk::PhysicalDeviceProperties2::default()
-                                    .push_next(&mut id_properties),
-                            );
-                        id_properties
-                    })
+        desc: &wgpu::DeviceDescriptor<'_>,
+    ) -> Option<Result<(wgpu::Device, wgpu::Queue), crate::DeviceCreateError>> {
+        // wgpu doesn't enable `VK_EXT_external_memory_host`
+        (self.mode == Some(VulkanSharingMode::Host))
+            .then(|| unsafe { open_device(adapter, desc, &[ext::external_memory_host::NAME]) })
+    }
+
+    fn allocate(
+        &self,
+        device: &wgpu::Device,
+        size: wgpu::BufferAddress,
+    ) -> Result<VulkanAllocation, crate::SharedBufferCreateError> {
+        let mode = self.mode();
+        // # SAFETY: the raw handle is not manually destroyed.
+        unsafe {
+            device.as_hal::<Vulkan, _, _>(|hal_device| {
+                let hal_device = hal_device.unwrap();
+                let (mut allocation, req) =
+                    VulkanAllocation::unbound(hal_device, device, mode, size)?;
+                if mode == VulkanSharingMode::Host {
+                    self.import_host_memory(hal_device, &mut allocation, req)?;
+                } else {
+                    allocation.allocate_block(&self.allocator, req)?;
+                }
+                allocation.bind(hal_device)?;
+                Ok(allocation)
             })
-        };
-        let Some(vk_desc) = adapter_vulkan_desc else {
-            return Err(crate::DeviceCreateError::MissingFeature);
-        };
-        let device = unsafe {
-            oidn::sys::oidnNewDeviceByUUID((&vk_desc.device_uuid) as *const _ as *const _)
-        };
+        }
+    }
 
-        Self::new_from_raw_oidn_device(device, wgpu_device, queue, |flag| {
-            let oidn_supports_win32 =
-                flag & OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_WIN32 != 0;
-            let oidn_supports_fd =
-                flag & OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_FD != 0;
-            let oidn_supports_dma =
-                flag & OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_DMA_BUF != 0;
-            if oidn_supports_win32 && win_32_handle_supported {
-                return Some(crate::BackendData::Vulkan(VulkanSharingMode::Win32));
-            }
-            if oidn_supports_fd && fd_supported {
-                return Some(crate::BackendData::Vulkan(VulkanSharingMode::Fd));
-            }
-            if oidn_supports_dma && dma_buf_supported {
-                return Some(crate::BackendData::Vulkan(VulkanSharingMode::Dma));
-            }
-            None
-        })
-        .await
+    unsafe fn import_into_oidn(
+        &self,
+        device: &oidn::Device,
+        memory: &VulkanAllocation,
+        size: wgpu::BufferAddress,
+    ) -> Result<oidn::sys::OIDNBuffer, crate::SharedBufferCreateError> {
+        unsafe { memory.import_into_oidn(device, size) }
     }
 
-    pub(crate) fn allocate_shared_buffers_vulkan(
+    unsafe fn wrap_into_wgpu(
         &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        memory: &mut VulkanAllocation,
         size: wgpu::BufferAddress,
-    ) -> Result<crate::SharedBuffer, crate::SharedBufferCreateError> {
-        // can happen if all other backends are switched off
+    ) -> wgpu::Buffer {
+        let buffer = memory.buffer.take().unwrap();
+        unsafe { wrap_into_wgpu(device, Some(queue), buffer, size) }
+    }
+}
+
+impl VulkanAllocation {
+    /// Creates an exportable buffer without memory, returning its memory requirements.
+    unsafe fn unbound(
+        hal_device: &vulkan::Device,
+        device: &wgpu::Device,
+        mode: VulkanSharingMode,
+        size: wgpu::BufferAddress,
+    ) -> Result<(Self, vk::MemoryRequirements), crate::SharedBufferCreateError> {
+        let raw_buffer = unsafe { create_exportable_buffer(hal_device, mode, size)? };
+        // freeing a null memory is a no-op, so this cleans up after any failure
+        let allocation = VulkanAllocation {
+            memory: vk::DeviceMemory::null(),
+            buffer: Some(raw_buffer),
+            mode,
+            wgpu_device: device.clone(),
+            block: None,
+            host_memory: None,
+            borrowed: false,
+            allocation_size: 0,
+            memory_type_index: None,
+        };
+        let req = unsafe {
+            hal_device
+                .raw_device()
+                .get_buffer_memory_requirements(raw_buffer)
+        };
+        Ok((allocation, req))
+    }
+
+    /// Allocates `memory` through `allocator`.
+    fn allocate_block(
+        &mut self,
+        allocator: &Arc<dyn ExternalMemoryAllocator>,
+        req: vk::MemoryRequirements,
+    ) -> Result<(), crate::SharedBufferCreateError> {
+        let block = allocator.allocate(
+            &self.wgpu_device,
+            &ExternalMemoryRequest {
+                size: req.size,
+                alignment: req.alignment,
+                memory_type_bits: req.memory_type_bits,
+                sharing_mode: self.mode.sharing_mode(),
+            },
+        )?;
         #[allow(unreachable_patterns)]
-        let data = match self.backend_data {
-            crate::BackendData::Vulkan(data) => data,
-            _ => unreachable!(),
+        let memory = match block.memory {
+            ExternalMemory::Vulkan {
+                memory,
+                memory_type_index,
+            } => Some((memory, memory_type_index)),
+            _ => None,
         };
+        self.allocation_size = block.size;
+        self.block = Some((allocator.clone(), block));
+        let (memory, memory_type_index) = memory.ok_or_else(|| {
+            crate::SharedBufferCreateError::Allocator("expected Vulkan memory".to_string())
+        })?;
+        self.memory = memory;
+        self.memory_type_index = Some(memory_type_index);
+        Ok(())
+    }
 
-        // # SAFETY: the raw handle is not manually destroyed.
+    unsafe fn bind(
+        &self,
+        hal_device: &vulkan::Device,
+    ) -> Result<(), crate::SharedBufferCreateError> {
         unsafe {
-            self.wgpu_device.as_hal::<Vulkan, _, _>(|device| {
-                let device = device.unwrap();
-
-                let mut win_32_funcs = None;
-
-                let mut fd_funcs = None;
+            hal_device
+                .raw_device()
+                .bind_buffer_memory(self.buffer.unwrap(), self.memory, 0)
+        }
+        .map_err(|_| crate::SharedBufferCreateError::OutOfMemory)
+    }
+}
 
-                let handle_ty = match data {
-                    VulkanSharingMode::Win32 => {
-                        win_32_funcs = Some(khr::external_memory_win32::Device::new(
-                            device.shared_instance().raw_instance(),
-                            device.raw_device(),
-                        ));
-                        vk::ExternalMemoryHandleTypeFlags::OPAQUE_WIN32_KHR
-                    }
-                    VulkanSharingMode::Fd => {
-                        fd_funcs = Some(khr::external_memory_fd::Device::new(
-                            device.shared_instance().raw_instance(),
-                            device.raw_device(),
-                        ));
-                        vk::ExternalMemoryHandleTypeFlags::OPAQUE_FD_KHR
-                    }
-                    VulkanSharingMode::Dma => {
-                        fd_funcs = Some(khr::external_memory_fd::Device::new(
-                            device.shared_instance().raw_instance(),
-                            device.raw_device(),
-                        ));
-                        vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT
-                    }
-                };
+/// Allocates exportable memory through `allocator` and wraps it into a zeroed wgpu buffer,
+/// without importing it into an OIDN device. Returns `None` if `device` is not a Vulkan device.
+#[cfg(server)]
+pub(crate) unsafe fn allocate_unimported(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    sharing_mode: SharingMode,
+    allocator: &Arc<dyn ExternalMemoryAllocator>,
+    size: wgpu::BufferAddress,
+) -> Option<Result<(VulkanAllocation, wgpu::Buffer), crate::SharedBufferCreateError>> {
+    if sharing_mode == SharingMode::HostMemory {
+        return Some(Err(crate::SharedBufferCreateError::UnsupportedSharingMode(
+            sharing_mode,
+        )));
+    }
+    let mode = VulkanSharingMode::from_sharing_mode(sharing_mode);
+    // # SAFETY: the raw handle is not manually destroyed.
+    let allocation = unsafe {
+        device.as_hal::<Vulkan, _, _>(|hal_device| {
+            let hal_device = hal_device?;
+            Some((|| {
+                let (mut allocation, req) =
+                    VulkanAllocation::unbound(hal_device, device, mode, size)?;
+                allocation.allocate_block(allocator, req)?;
+                allocation.bind(hal_device)?;
+                Ok(allocation)
+            })())
+        })?
+    };
+    Some(allocation.map(|mut allocation| {
+        let buffer = allocation.buffer.take().unwrap();
+        let buffer = unsafe { wrap_into_wgpu(device, Some(queue), buffer, size) };
+        (allocation, buffer)
+    }))
+}
 
-                let mut vk_external_memory_info =
-                    vk::ExternalMemoryBufferCreateInfo::default().handle_types(handle_ty);
+impl VulkanBackend {
+    /// Allocates host memory for the buffer's requirements and imports it as `allocation.memory`.
+    unsafe fn import_host_memory(
+        &self,
+        hal_device: &vulkan::Device,
+        allocation: &mut VulkanAllocation,
+        req: vk::MemoryRequirements,
+    ) -> Result<(), crate::SharedBufferCreateError> {
+        let alignment = self
+            .info()
+            .min_imported_host_pointer_alignment
+            .max(req.alignment);
+        let layout =
+            Layout::from_size_align(align_to(req.size, alignment) as usize, alignment as usize)
+                .map_err(|_| crate::SharedBufferCreateError::OutOfMemory)?;
+        let host_memory =
+            HostMemory::new(layout).ok_or(crate::SharedBufferCreateError::OutOfMemory)?;
+        let host_ptr = host_memory.ptr.as_ptr() as *mut std::ffi::c_void;
+        allocation.host_memory = Some(host_memory);
+
+        let raw_device = hal_device.raw_device();
+        let instance = hal_device.shared_instance().raw_instance();
+        unsafe {
+            let fns = ext::external_memory_host::DeviceFn::load(|name| {
+                std::mem::transmute(
+                    instance.get_device_proc_addr(raw_device.handle(), name.as_ptr()),
+                )
+            });
+            let mut host_properties = vk::MemoryHostPointerPropertiesEXT::default();
+            (fns.get_memory_host_pointer_properties_ext)(
+                raw_device.handle(),
+                VulkanSharingMode::Host.handle_type(),
+                host_ptr,
+                &mut host_properties,
+            )
+            .result()
+            .map_err(|_| crate::SharedBufferCreateError::OutOfMemory)?;
+
+            let memory_type_bits = req.memory_type_bits & host_properties.memory_type_bits;
+            if memory_type_bits == 0 {
+                return Err(crate::SharedBufferCreateError::OutOfMemory);
+            }
+            let memory_type_index = memory_type_bits.trailing_zeros();
+            let mut import_info = vk::ImportMemoryHostPointerInfoEXT::default()
+                .handle_type(VulkanSharingMode::Host.handle_type())
+                .host_pointer(host_ptr);
+            let info = vk::MemoryAllocateInfo::default()
+                .allocation_size(layout.size() as u64)
+                .memory_type_index(memory_type_index)
+                .push_next(&mut import_info);
+            allocation.memory = raw_device
+                .allocate_memory(&info, None)
+                .map_err(|_| crate::SharedBufferCreateError::OutOfMemory)?;
+            allocation.allocation_size = layout.size() as u64;
+            allocation.memory_type_index = Some(memory_type_index);
+        }
+        Ok(())
+    }
+}
 
-                let vk_info = vk::BufferCreateInfo::default()
-                    .size(size)
-                    .usage(vk::BufferUsageFlags::TRANSFER_SRC | vk::BufferUsageFlags::TRANSFER_DST | vk::BufferUsageFlags::STORAGE_BUFFER)
-                    // technically exclusive because cross adapter doesn't matter here
-                    .sharing_mode(vk::SharingMode::EXCLUSIVE)
-                    .push_next(&mut vk_external_memory_info);
+/// Opens a device on `adapter` like wgpu does, additionally enabling `extensions`.
+unsafe fn open_device(
+    adapter: &wgpu::Adapter,
+    desc: &wgpu::DeviceDescriptor<'_>,
+    extensions: &[&'static CStr],
+) -> Result<(wgpu::Device, wgpu::Queue), crate::DeviceCreateError> {
+    let open_error =
+        |err: &dyn std::fmt::Display| crate::DeviceCreateError::OpenDevice(err.to_string());
+    // # SAFETY: the raw handles are not manually destroyed and the device is created from this
+    // adapter with the requested features.
+    unsafe {
+        let open_device = adapter.as_hal::<Vulkan, _, _>(|hal_adapter| {
+            let hal_adapter = hal_adapter.unwrap();
+            let mut enabled_extensions =
+                hal_adapter.required_device_extensions(desc.required_features);
+            enabled_extensions.extend_from_slice(extensions);
+            let mut features =
+                hal_adapter.physical_device_features(&enabled_extensions, desc.required_features);
+            let queue_infos = [vk::DeviceQueueCreateInfo::default()
+                .queue_family_index(0)
+                .queue_priorities(&[1.0])];
+            let extension_names: Vec<_> = enabled_extensions
+                .iter()
+                .map(|name| name.as_ptr())
+                .collect();
+            let info = features.add_to_device_create(
+                vk::DeviceCreateInfo::default()
+                    .queue_create_infos(&queue_infos)
+                    .enabled_extension_names(&extension_names),
+            );
+            let raw_device = hal_adapter
+                .shared_instance()
+                .raw_instance()
+                .create_device(hal_adapter.raw_physical_device(), &info, None)
+                .map_err(|err| open_error(&err))?;
+            hal_adapter
+                .device_from_raw(
+                    raw_device,
+                    None,
+                    &enabled_extensions,
+                    desc.required_features,
+                    &desc.memory_hints,
+                    0,
+                    0,
+                )
+                .map_err(|err| open_error(&err))
+        })?;
+        adapter
+            .create_device_from_hal(open_device, desc)
+            .map_err(crate::DeviceCreateError::RequestDeviceError)
+    }
+}
 
-                let raw_buffer = device
-                    .raw_device()
-                    .create_buffer(&vk_info, None)
-                    .map_err(|_| crate::SharedBufferCreateError::OutOfMemory)?;
+/// Creates a buffer with the shared buffer usages that can be bound to memory exported as
+/// `mode`.
+unsafe fn create_exportable_buffer(
+    hal_device: &vulkan::Device,
+    mode: VulkanSharingMode,
+    size: wgpu::BufferAddress,
+) -> Result<vk::Buffer, crate::SharedBufferCreateError> {
+    let mut vk_external_memory_info =
+        vk::ExternalMemoryBufferCreateInfo::default().handle_types(mode.handle_type());
+
+    let vk_info = vk::BufferCreateInfo::default()
+        .size(size)
+        .usage(
+            vk::BufferUsageFlags::TRANSFER_SRC
+                | vk::BufferUsageFlags::TRANSFER_DST
+                | vk::BufferUsageFlags::STORAGE_BUFFER,
+        )
+        // technically exclusive because cross adapter doesn't matter here
+        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .push_next(&mut vk_external_memory_info);
+
+    unsafe { hal_device.raw_device().create_buffer(&vk_info, None) }
+        .map_err(|_| crate::SharedBufferCreateError::OutOfMemory)
+}
 
-                let req = device
-                    .raw_device()
-                    .get_buffer_memory_requirements(raw_buffer);
+impl crate::Device {
+    /// Wraps exportable Vulkan memory allocated elsewhere into a shared buffer, without taking
+    /// ownership of it.
+    ///
+    /// This takes memory rather than a buffer as wgpu destroys the buffers it wraps, the wgpu
+    /// buffer is a new buffer aliasing `memory` from its start. Its contents are kept.
+    ///
+    /// # Safety
+    ///
+    /// - The device must use the Vulkan backend.
+    /// - `memory` must have been allocated on the device's Vulkan device exportable as
+    ///   `sharing_mode`, hold at least `size` bytes and outlive the returned buffer.
+    pub unsafe fn adopt_vulkan_memory(
+        &self,
+        memory: vk::DeviceMemory,
+        sharing_mode: SharingMode,
+        size: wgpu::BufferAddress,
+    ) -> Result<crate::SharedBuffer, crate::SharedBufferCreateError> {
+        if size == 0 {
+            return Err(crate::SharedBufferCreateError::InvalidSize(size));
+        }
+        if self.is_lost() {
+            return Err(crate::SharedBufferCreateError::DeviceLost);
+        }
+        if sharing_mode == SharingMode::HostMemory {
+            return Err(crate::SharedBufferCreateError::UnsupportedSharingMode(
+                sharing_mode,
+            ));
+        }
+        let mode = VulkanSharingMode::from_sharing_mode(sharing_mode);
+        // # SAFETY: the raw handle is not manually destroyed.
+        let buffer = unsafe {
+            self.inner
+                .wgpu_device
+                .as_hal::<Vulkan, _, _>(|hal_device| {
+                    let hal_device = hal_device.unwrap();
+                    let buffer = create_exportable_buffer(hal_device, mode, size)?;
+                    if hal_device
+                        .raw_device()
+                        .bind_buffer_memory(buffer, memory, 0)
+                        .is_err()
+                    {
+                        hal_device.raw_device().destroy_buffer(buffer, None);
+                        return Err(crate::SharedBufferCreateError::OutOfMemory);
+                    }
+                    Ok(buffer)
+                })?
+        };
+        let allocation = VulkanAllocation {
+            memory,
+            buffer: None,
+            mode,
+            wgpu_device: self.inner.wgpu_device.clone(),
+            block: None,
+            host_memory: None,
+            borrowed: true,
+            allocation_size: size,
+            memory_type_index: None,
+        };
+        let wgpu_buffer = unsafe { wrap_into_wgpu(&self.inner.wgpu_device, None, buffer, size) };
+        let _oidn = self.lock_oidn();
+        let oidn_buffer = unsafe { allocation.import_into_oidn(&self.inner.oidn_device, size)? };
+        Ok(crate::SharedBuffer::new(
+            self,
+            oidn_buffer,
+            wgpu_buffer,
+            Box::new(allocation),
+        ))
+    }
+}
 
-                let aligned_size = align_to(size, req.alignment);
+impl VulkanAllocation {
+    /// Exports a new handle to the memory, which must not be host memory.
+    unsafe fn export_handle(&self) -> Result<RawMemoryHandle, vk::Result> {
+        // # SAFETY: the raw handle is not manually destroyed.
+        unsafe {
+            self.wgpu_device.as_hal::<Vulkan, _, _>(|hal_device| {
+                let hal_device = hal_device.unwrap();
+                match self.mode {
+                    #[cfg(windows)]
+                    VulkanSharingMode::Win32 => khr::external_memory_win32::Device::new(
+                        hal_device.shared_instance().raw_instance(),
+                        hal_device.raw_device(),
+                    )
+                    .get_memory_win32_handle(
+                        &vk::MemoryGetWin32HandleInfoKHR::default()
+                            .memory(self.memory)
+                            .handle_type(self.mode.handle_type()),
+                    )
+                    .map(|handle| {
+                        RawMemoryHandle::Win32(std::os::windows::io::OwnedHandle::from_raw_handle(
+                            handle as *mut _,
+                        ))
+                    }),
+                    #[cfg(unix)]
+                    VulkanSharingMode::Fd | VulkanSharingMode::Dma => {
+                        khr::external_memory_fd::Device::new(
+                            hal_device.shared_instance().raw_instance(),
+                            hal_device.raw_device(),
+                        )
+                        .get_memory_fd(
+                            &vk::MemoryGetFdInfoKHR::default()
+                                .memory(self.memory)
+                                .handle_type(self.mode.handle_type()),
+                        )
+                        .map(|fd| RawMemoryHandle::Fd(std::os::fd::OwnedFd::from_raw_fd(fd)))
+                    }
+                    VulkanSharingMode::Host => unreachable!("host memory has no handle"),
+                    // the handles of other platforms can't be exported
+                    #[allow(unreachable_patterns)]
+                    _ => Err(vk::Result::ERROR_EXTENSION_NOT_PRESENT),
+                }
+            })
+        }
+    }
 
-                let mem_properties = device
-                    .shared_instance()
-                    .raw_instance()
-                    .get_physical_device_memory_properties(device.raw_physical_device());
+    pub(crate) unsafe fn export(&self) -> Result<ExportedMemory, crate::ExportError> {
+        let handle_type = match self.mode {
+            VulkanSharingMode::Win32 => HandleType::OpaqueWin32,
+            VulkanSharingMode::Fd => HandleType::OpaqueFd,
+            VulkanSharingMode::Dma => HandleType::DmaBuf,
+            VulkanSharingMode::Host => return Err(crate::ExportError::Unsupported),
+        };
+        let handle = unsafe { self.export_handle() }
+            .map_err(|err| crate::ExportError::Failed(err.to_string()))?;
+        Ok(ExportedMemory {
+            handle,
+            handle_type,
+            allocation_size: self.allocation_size,
+            memory_type_index: self.memory_type_index,
+        })
+    }
 
-                let mut idx = None;
+    unsafe fn import_into_oidn(
+        &self,
+        device: &oidn::Device,
+        size: wgpu::BufferAddress,
+    ) -> Result<oidn::sys::OIDNBuffer, crate::SharedBufferCreateError> {
+        let flag = match self.mode {
+            VulkanSharingMode::Host => {
+                let oidn_buffer = unsafe {
+                    crate::sys::oidnNewSharedBuffer(
+                        device.raw(),
+                        self.host_memory.as_ref().unwrap().ptr.as_ptr() as *mut _,
+                        size as usize,
+                    )
+                };
+                return crate::backend::check_oidn_buffer(device, oidn_buffer);
+            }
+            VulkanSharingMode::Win32 => {
+                OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_WIN32
+            }
+            VulkanSharingMode::Fd => {
+                OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_FD
+            }
+            VulkanSharingMode::Dma => {
+                OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_DMA_BUF
+            }
+        };
+        let handle = unsafe { self.export_handle() }
+            .map_err(|_| crate::SharedBufferCreateError::OutOfMemory)?;
+        unsafe {
+            let oidn_buffer = match handle {
+                // OIDN doesn't take ownership of Win32 handles, so it is closed after importing
+                #[cfg(windows)]
+                RawMemoryHandle::Win32(handle) => crate::sys::oidnNewSharedBufferFromWin32Handle(
+                    device.raw(),
+                    flag,
+                    handle.as_raw_handle(),
+                    std::ptr::null(),
+                    size as usize,
+                ),
+                // but it does take ownership of file descriptors
+                #[cfg(unix)]
+                RawMemoryHandle::Fd(fd) => crate::sys::oidnNewSharedBufferFromFD(
+                    device.raw(),
+                    flag,
+                    fd.into_raw_fd(),
+                    size as usize,
+                ),
+            };
+            crate::backend::check_oidn_buffer(device, oidn_buffer)
+        }
+    }
+}
 
-                let flags = vk::MemoryPropertyFlags::DEVICE_LOCAL;
+/// Wraps a buffer created with the shared buffer usages into wgpu, zeroing it if `queue` is
+/// given.
+unsafe fn wrap_into_wgpu(
+    device: &wgpu::Device,
+    queue: Option<&wgpu::Queue>,
+    buffer: vk::Buffer,
+    size: wgpu::BufferAddress,
+) -> wgpu::Buffer {
+    let buf = unsafe { vulkan::Device::buffer_from_raw(buffer) };
+    if let Some(queue) = queue {
+        let mut encoder = device.create_command_encoder(&Default::default());
+        // # SAFETY: the raw handle is not manually destroyed.
+        unsafe {
+            encoder.as_hal_mut::<Vulkan, _, _>(|encoder| {
+                encoder.unwrap().clear_buffer(&buf, 0..size);
+            });
+        }
+        queue.submit([encoder.finish()]);
+    }
+    // # SAFETY: created it from the same device and made with the manually mapped usages.
+    unsafe {
+        device.create_buffer_from_hal::<Vulkan>(
+            buf,
+            &BufferDescriptor {
+                label: None,
+                size,
+                usage: BufferUsages::COPY_SRC | BufferUsages::COPY_DST | BufferUsages::STORAGE,
+                mapped_at_creation: false,
+            },
+        )
+    }
+}
 
-                for (i, mem_ty) in mem_properties.memory_types_as_slice().iter().enumerate() {
-                    let types_bits = 1 << i;
-                    let is_required_memory_type = req.memory_type_bits & types_bits != 0;
+/// Allocates memory exportable for the request's sharing mode, returning `None` if `device` is
+/// not a Vulkan device.
+pub(crate) unsafe fn allocate_exportable(
+    device: &wgpu::Device,
+    request: &ExternalMemoryRequest,
+) -> Option<Result<ExternalMemoryBlock, crate::SharedBufferCreateError>> {
+    let mode = VulkanSharingMode::from_sharing_mode(request.sharing_mode);
+    let handle_ty = mode.handle_type();
+    // # SAFETY: the raw handle is not manually destroyed.
+    unsafe {
+        device.as_hal::<Vulkan, _, _>(|hal_device| {
+            let hal_device = hal_device?;
+            let mem_properties = hal_device
+                .shared_instance()
+                .raw_instance()
+                .get_physical_device_memory_properties(hal_device.raw_physical_device());
+
+            let flags = vk::MemoryPropertyFlags::DEVICE_LOCAL;
+
+            let idx = mem_properties
+                .memory_types_as_slice()
+                .iter()
+                .enumerate()
+                .position(|(i, mem_ty)| {
+                    let is_required_memory_type = request.memory_type_bits & (1 << i) != 0;
                     let has_required_properties = mem_ty.property_flags & flags == flags;
-                    if is_required_memory_type && has_required_properties {
-                        idx = Some(i);
-                        break;
-                    }
-                }
+                    is_required_memory_type && has_required_properties
+                });
 
-                let Some(idx) = idx else {
-                    return Err(crate::SharedBufferCreateError::OutOfMemory);
-                };
+            let Some(idx) = idx else {
+                return Some(Err(crate::SharedBufferCreateError::OutOfMemory));
+            };
 
-                let mut info = vk::MemoryAllocateInfo::default()
-                    .allocation_size(aligned_size)
-                    .memory_type_index(idx as u32);
+            let size = align_to(request.size, request.alignment);
+            let mut info = vk::MemoryAllocateInfo::default()
+                .allocation_size(size)
+                .memory_type_index(idx as u32);
 
-                let mut export_alloc_info =
-                    vk::ExportMemoryAllocateInfo::default().handle_types(handle_ty);
+            let mut export_alloc_info =
+                vk::ExportMemoryAllocateInfo::default().handle_types(handle_ty);
 
-                let mut win32_info;
+            let mut win32_info;
 
-                match data {
-                    VulkanSharingMode::Win32 => {
-                        win32_info = vk::ExportMemoryWin32HandleInfoKHR::default()
-                            .dw_access(ACCESS_GENERIC_ALL);
-                        info = info.push_next(&mut win32_info);
-                    }
-                    VulkanSharingMode::Dma | VulkanSharingMode::Fd => {}
+            match mode {
+                VulkanSharingMode::Win32 => {
+                    win32_info =
+                        vk::ExportMemoryWin32HandleInfoKHR::default().dw_access(ACCESS_GENERIC_ALL);
+                    info = info.push_next(&mut win32_info);
                 }
+                VulkanSharingMode::Dma | VulkanSharingMode::Fd | VulkanSharingMode::Host => {}
+            }
 
-                info = info.push_next(&mut export_alloc_info);
-
-                let memory = match device.raw_device().allocate_memory(&info, None) {
-                    Ok(memory) => memory,
-                    Err(_) => return Err(crate::SharedBufferCreateError::OutOfMemory),
-                };
+            info = info.push_next(&mut export_alloc_info);
 
-                device
+            Some(
+                hal_device
                     .raw_device()
-                    .bind_buffer_memory(raw_buffer, memory, 0)
-                    .map_err(|_| crate::SharedBufferCreateError::OutOfMemory)?;
-
-                let oidn_buffer = match data {
-                    VulkanSharingMode::Win32 => {
-                        let handle = win_32_funcs
-                            .as_ref()
-                            .unwrap()
-                            .get_memory_win32_handle(
-                                &vk::MemoryGetWin32HandleInfoKHR::default()
-                                    .memory(memory)
-                                    .handle_type(
-                                        vk::ExternalMemoryHandleTypeFlags::OPAQUE_WIN32_KHR,
-                                    ),
-                            )
-                            .map_err(|_| crate::SharedBufferCreateError::OutOfMemory)?;
-                        oidn::sys::oidnNewSharedBufferFromWin32Handle(
-                            self.oidn_device.raw(),
-                            OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_WIN32,
-                            handle as *mut _,
-                            ptr::null(),
-                            size as usize,
-                        )
-                    }
-                    VulkanSharingMode::Fd => {
-                        let bit = fd_funcs
-                            .as_ref()
-                            .unwrap()
-                            .get_memory_fd(
-                                &vk::MemoryGetFdInfoKHR::default()
-                                    .memory(memory)
-                                    .handle_type(vk::ExternalMemoryHandleTypeFlags::OPAQUE_FD_KHR),
-                            )
-                            .map_err(|_| crate::SharedBufferCreateError::OutOfMemory)?;
-                        oidn::sys::oidnNewSharedBufferFromFD(
-                            self.oidn_device.raw(),
-                            OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_FD,
-                            bit as _,
-                            size as usize,
-                        )
-                    }
-                    VulkanSharingMode::Dma => {
-                        let bit = fd_funcs
-                            .as_ref()
-                            .unwrap()
-                            .get_memory_fd(
-                                &vk::MemoryGetFdInfoKHR::default()
-                                    .memory(memory)
-                                    .handle_type(vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT),
-                            )
-                            .map_err(|_| crate::SharedBufferCreateError::OutOfMemory)?;
-                        oidn::sys::oidnNewSharedBufferFromFD(
-                            self.oidn_device.raw(),
-                            OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_DMA_BUF,
-                            bit as _,
-                            size as usize,
-                        )
-                    }
-                };
-                if oidn_buffer.is_null() {
-                    return Err(crate::SharedBufferCreateError::Oidn(
-                        self.oidn_device.get_error().unwrap_err(),
-                    ));
-                }
-                let buf = vulkan::Device::buffer_from_raw(raw_buffer);
-                let mut encoder = self.wgpu_device.create_command_encoder(&Default::default());
-                // # SAFETY: the raw handle is not manually destroyed.
-                encoder.as_hal_mut::<Vulkan, _, _>(|encoder| {
-                    encoder.unwrap().clear_buffer(&buf, 0..size);
-                });
-                self.queue.submit([encoder.finish()]);
-                // # SAFETY: Just initialized buffer, created it from the same device and made with
-                // the manually mapped usages.
-                let wgpu_buffer = self.wgpu_device.create_buffer_from_hal::<Vulkan>(
-                    buf,
-                    &BufferDescriptor {
-                        label: None,
-                        size,
-                        usage: BufferUsages::COPY_SRC | BufferUsages::COPY_DST | BufferUsages::STORAGE,
-                        mapped_at_creation: false,
-                    },
-                );
-                Ok(crate::SharedBuffer {
-                    _allocation: crate::Allocation::Vulkan {
-                        _vulkan: VulkanAllocation {
+                    .allocate_memory(&info, None)
+                    .map(|memory| ExternalMemoryBlock {
+                        memory: ExternalMemory::Vulkan {
                             memory,
-                            wgpu_device: self.wgpu_device.clone(),
+                            memory_type_index: idx as u32,
                         },
-                    },
-                    wgpu_buffer,
-                    oidn_buffer: self.oidn_device.create_buffer_from_raw(oidn_buffer),
-                })
-            })
-        }
+                        size,
+                        user_data: Box::new(()),
+                    })
+                    .map_err(|_| crate::SharedBufferCreateError::OutOfMemory),
+            )
+        })
     }
 }
+
+/// Frees memory from [`allocate_exportable`].
+pub(crate) unsafe fn free_memory(device: &wgpu::Device, memory: vk::DeviceMemory) {
+    // # SAFETY: the raw handle is not manually destroyed.
+    unsafe {
+        device.as_hal::<Vulkan, _, _>(|device| {
+            device.unwrap().raw_device().free_memory(memory, None);
+        })
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn host_memory_selection() {
+    let info = VulkanAdapterInfo {
+        win_32_handle_supported: false,
+        fd_supported: true,
+        dma_buf_supported: false,
+        host_memory_supported: true,
+        min_imported_host_pointer_alignment: 4096,
+        device_uuid: [0; vk::UUID_SIZE],
+        device_local_memory: 0,
+    };
+    let modes = [SharingMode::OpaqueFd, SharingMode::HostMemory];
+    assert!(info.uses_host_memory(&modes, false));
+    // an OIDN device for the adapter is preferred
+    assert!(!info.uses_host_memory(&modes, true));
+    // host memory has to be opted into
+    assert!(!info.uses_host_memory(&[SharingMode::OpaqueFd], false));
+    let unsupported = VulkanAdapterInfo {
+        host_memory_supported: false,
+        ..info
+    };
+    assert!(!unsupported.uses_host_memory(&modes, false));
+    // it is never picked from the memory types OIDN reports
+    assert_eq!(
+        info.sharing_mode(!0, &[SharingMode::HostMemory, SharingMode::OpaqueFd]),
+        Some(VulkanSharingMode::Fd)
+    );
+}
@@ -0,0 +1,265 @@
+use crate::{
+    CancellationToken, DenoiseJobError, Device, FilterConfig, FilterImages, FilterType,
+    ProgressMonitor, SharedImage,
+};
+use std::future::Future;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::pin::Pin;
+use std::sync::mpsc::{Sender, channel};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread::JoinHandle;
+
+/// A filter execution for a [`DenoiseWorker`], owning its images while it is queued or running.
+///
+/// The images are handed back when the job completes, so they can be reused for the next one.
+pub struct DenoiseJob {
+    pub ty: FilterType,
+    pub config: FilterConfig,
+    pub color: SharedImage,
+    pub albedo: Option<SharedImage>,
+    /// Only used if `albedo` is also set.
+    pub normal: Option<SharedImage>,
+    /// Where the denoised image is written, `color` is filtered in place if this is `None`.
+    pub output: Option<SharedImage>,
+    /// The wgpu submission writing the inputs, which the worker waits for before filtering.
+    pub wait_for: Option<wgpu::SubmissionIndex>,
+    pub cancellation: Option<CancellationToken>,
+}
+
+impl DenoiseJob {
+    pub fn new(color: SharedImage, output: SharedImage) -> Self {
+        Self {
+            output: Some(output),
+            ..Self::in_place(color)
+        }
+    }
+    pub fn in_place(color: SharedImage) -> Self {
+        Self {
+            ty: FilterType::RayTracing,
+            config: FilterConfig::default(),
+            color,
+            albedo: None,
+            normal: None,
+            output: None,
+            wait_for: None,
+            cancellation: None,
+        }
+    }
+    pub fn filter_type(mut self, ty: FilterType) -> Self {
+        self.ty = ty;
+        self
+    }
+    pub fn config(mut self, config: FilterConfig) -> Self {
+        self.config = config;
+        self
+    }
+    pub fn albedo(mut self, albedo: SharedImage) -> Self {
+        self.albedo = Some(albedo);
+        self
+    }
+    pub fn normal(mut self, normal: SharedImage) -> Self {
+        self.normal = Some(normal);
+        self
+    }
+    pub fn wait_for(mut self, submission: wgpu::SubmissionIndex) -> Self {
+        self.wait_for = Some(submission);
+        self
+    }
+    pub fn cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.cancellation = Some(cancellation);
+        self
+    }
+
+    /// The image the denoised result is written to.
+    pub fn output_image(&self) -> &SharedImage {
+        self.output.as_ref().unwrap_or(&self.color)
+    }
+
+    fn images(&self) -> FilterImages<'_> {
+        FilterImages {
+            color: Some(&self.color),
+            albedo: self.albedo.as_ref(),
+            normal: self.normal.as_ref(),
+            output: self.output_image(),
+        }
+    }
+
+    fn run(&mut self, device: &Device) -> Result<(), DenoiseJobError> {
+        if let Some(submission) = self.wait_for.take() {
+            device
+                .wgpu_device()
+                .poll(wgpu::PollType::WaitForSubmissionIndex(submission))
+                .map_err(DenoiseJobError::Poll)?;
+        }
+        let mut monitor = ProgressMonitor::default();
+        if let Some(cancellation) = &self.cancellation {
+            monitor = monitor.cancellation(cancellation);
+        }
+        device
+            .execute_filter_with_progress(self.ty, &self.config, &self.images(), &monitor)
+            .map_err(DenoiseJobError::Filter)
+    }
+}
+
+type Completion = Box<dyn FnOnce(DenoiseJob, Result<(), DenoiseJobError>) + Send>;
+
+/// A job waiting for the worker thread, completed with [`DenoiseJobError::Stopped`] if it is
+/// dropped without running.
+struct Queued {
+    job: Option<DenoiseJob>,
+    on_done: Option<Completion>,
+}
+
+impl Queued {
+    fn complete(&mut self, result: Result<(), DenoiseJobError>) {
+        if let (Some(job), Some(on_done)) = (self.job.take(), self.on_done.take()) {
+            // a panicking callback must not take the queued jobs down with it
+            let _ = catch_unwind(AssertUnwindSafe(|| on_done(job, result)));
+        }
+    }
+}
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        self.complete(Err(DenoiseJobError::Stopped));
+    }
+}
+
+/// Runs filters on a thread of its own, so submitting threads never block on OIDN.
+///
+/// Jobs run one after another in the order they were submitted. Dropping the worker finishes
+/// the queued jobs and joins its thread. The worker holds a clone of its device, so it has to
+/// be dropped before [`Device::recreate`].
+pub struct DenoiseWorker {
+    device: Device,
+    jobs: Option<Sender<Queued>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl DenoiseWorker {
+    /// Starts a worker running filters on a clone of `device`.
+    pub fn new(device: &Device) -> std::io::Result<Self> {
+        let (jobs, queue) = channel::<Queued>();
+        let worker_device = device.clone();
+        let thread = std::thread::Builder::new()
+            .name("oidn-denoise-worker".into())
+            .spawn(move || {
+                for mut queued in queue {
+                    let Some(job) = &mut queued.job else {
+                        continue;
+                    };
+                    let result = catch_unwind(AssertUnwindSafe(|| job.run(&worker_device)))
+                        .unwrap_or(Err(DenoiseJobError::Panicked));
+                    queued.complete(result);
+                }
+            })?;
+        Ok(Self {
+            device: device.clone(),
+            jobs: Some(jobs),
+            thread: Some(thread),
+        })
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    /// Queues `job`, calling `on_done` on the worker thread with the job and its result once
+    /// it completes.
+    ///
+    /// Gives `job` back without calling `on_done` if the worker thread has stopped.
+    pub fn submit(
+        &self,
+        job: DenoiseJob,
+        on_done: impl FnOnce(DenoiseJob, Result<(), DenoiseJobError>) + Send + 'static,
+    ) -> Result<(), Box<DenoiseJob>> {
+        let queued = Queued {
+            job: Some(job),
+            on_done: Some(Box::new(on_done)),
+        };
+        self.jobs
+            .as_ref()
+            .expect("the sender lives until the worker is dropped")
+            .send(queued)
+            .map_err(|err| {
+                let mut queued = err.0;
+                queued.on_done = None;
+                Box::new(queued.job.take().expect("unsent jobs are not completed"))
+            })
+    }
+
+    /// Queues `job`, returning a future resolving to the job and its result once it completes.
+    ///
+    /// Gives `job` back if the worker thread has stopped.
+    pub fn submit_async(&self, job: DenoiseJob) -> Result<JobFuture, Box<DenoiseJob>> {
+        let state = Arc::new(Mutex::new(JobState::default()));
+        let done = state.clone();
+        self.submit(job, move |job, result| {
+            let mut state = done.lock().unwrap();
+            state.result = Some((job, result));
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        })?;
+        Ok(JobFuture(state))
+    }
+}
+
+impl Drop for DenoiseWorker {
+    fn drop(&mut self) {
+        // closing the channel lets the thread exit after the queued jobs
+        self.jobs = None;
+        if let Some(thread) = self.thread.take() {
+            // a completion callback dropping the worker can't wait for its own thread
+            if thread.thread().id() != std::thread::current().id() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct JobState {
+    result: Option<(DenoiseJob, Result<(), DenoiseJobError>)>,
+    waker: Option<Waker>,
+}
+
+/// Resolves to a job submitted with [`DenoiseWorker::submit_async`] and its result.
+pub struct JobFuture(Arc<Mutex<JobState>>);
+
+impl Future for JobFuture {
+    type Output = (DenoiseJob, Result<(), DenoiseJobError>);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.0.lock().unwrap();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+static_assertions::assert_impl_all!(DenoiseWorker: Send, Sync);
+static_assertions::assert_impl_all!(JobFuture: Send);
+
+#[cfg(all(test, feature = "testing"))]
+#[async_std::test]
+async fn worker_round_trip() {
+    let adapter = crate::testing::noop_adapter();
+    let (device, queue) = Device::new_testing(&adapter, &Default::default())
+        .await
+        .unwrap();
+    let image = device
+        .allocate_shared_image(crate::Format::Float3, 1, 1)
+        .unwrap();
+    crate::testing::fill_image(&queue, &image, &[0.5; 3]);
+    let worker = DenoiseWorker::new(&device).unwrap();
+    let job = DenoiseJob::in_place(image).wait_for(queue.submit([]));
+    let (job, result) = worker.submit_async(job).ok().unwrap().await;
+    result.unwrap();
+    crate::testing::assert_image_approx_eq(&device, job.output_image(), &[0.5; 3], 0.1);
+}